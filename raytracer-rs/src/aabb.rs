@@ -5,6 +5,7 @@ use glam::*;
 use crate::ray::Ray;
 
 #[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct AABB {
     pub min: Vec3A,
     pub max: Vec3A
@@ -18,18 +19,22 @@ impl AABB {
         }
     }
 
-    pub fn hit(&self, ray: &Ray, _t_min: f32, _t_max: f32) -> bool {
-        let recip = ray.direction.recip();
-        let min = (self.min - ray.origin) * recip;
-        let max = (self.max - ray.origin) * recip;
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.hit_inverse(ray.origin, ray.direction.recip(), t_min, t_max)
+    }
 
-        let t_min = f32::max(f32::max(f32::min(min.x, max.x), f32::min(min.y, max.y)), f32::min(min.z, max.z));
-        let t_max = f32::min(f32::min(f32::max(min.x, max.x), f32::max(min.y, max.y)), f32::max(min.z, max.z));
+    /// Same as `hit`, for callers testing many boxes against one ray that
+    /// compute the reciprocal direction once.
+    pub fn hit_inverse(&self, origin: Vec3A, inv_direction: Vec3A, t_min: f32, t_max: f32) -> bool {
+        let min = (self.min - origin) * inv_direction;
+        let max = (self.max - origin) * inv_direction;
 
-        if t_max < 0.0 || t_min >= t_max {
-            return false;
-        }
-        return true;
+        let t_enter = f32::max(f32::max(f32::min(min.x, max.x), f32::min(min.y, max.y)), f32::min(min.z, max.z));
+        let t_exit = f32::min(f32::min(f32::max(min.x, max.x), f32::max(min.y, max.y)), f32::max(min.z, max.z));
+
+        // Only the part of the slab overlap inside [t_min, t_max] counts, so
+        // boxes beyond the closest hit found so far are culled
+        f32::max(t_enter, t_min) <= f32::min(t_exit, t_max)
     }
 
     pub fn surrounding_box(box1: &AABB, box2: &AABB) -> AABB {
@@ -0,0 +1,172 @@
+//! Arbitrary output variables: per pixel information about the first
+//! surface the camera rays hit, written next to the beauty image for
+//! compositing and denoising.
+
+extern crate glam;
+
+use glam::*;
+
+use crate::framebuffer::Framebuffer;
+use crate::rng;
+
+use std::path::{Path, PathBuf};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AovType {
+    /// Reflectance of the surface, the background where nothing was hit
+    Albedo,
+    /// Light emitted by the surface towards the camera, the background
+    /// where nothing was hit
+    Emission,
+    /// World space shading normal, facing the camera
+    Normal,
+    /// Distance from the camera along its viewing direction
+    Depth,
+    /// World space position
+    Position,
+    /// `HitRecord::object_id`
+    ObjectId,
+    /// `Material::id`
+    MaterialId,
+    /// Variance of the mean luminance of the beauty pixel, from
+    /// `Accumulator::variance`
+    Variance
+}
+
+impl AovType {
+    pub const NAMES: [(&'static str, AovType); 8] = [
+        ("albedo", AovType::Albedo),
+        ("emission", AovType::Emission),
+        ("normal", AovType::Normal),
+        ("depth", AovType::Depth),
+        ("position", AovType::Position),
+        ("object-id", AovType::ObjectId),
+        ("material-id", AovType::MaterialId),
+        ("variance", AovType::Variance),
+    ];
+
+    pub fn all() -> Vec<AovType> {
+        AovType::NAMES.iter().map(|(_, aov)| *aov).collect()
+    }
+
+    pub fn from_name(name: &str) -> Option<AovType> {
+        AovType::NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, a)| *a)
+    }
+
+    pub fn name(&self) -> &'static str {
+        AovType::NAMES.iter().find(|(_, a)| a == self).map(|(n, _)| *n).unwrap()
+    }
+
+    /// Whether 8-bit images of this AOV are colours that want the sRGB curve.
+    pub fn is_color(&self) -> bool {
+        matches!(self, AovType::Albedo | AovType::Emission)
+    }
+}
+
+/// Where AOV `aov` of the image `output` is written: `render.png` becomes
+/// `render.albedo.png`.
+pub fn aov_path<P: AsRef<Path>>(output: P, aov: AovType) -> PathBuf {
+    let output = output.as_ref();
+    let stem = output.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match output.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, aov.name(), extension.to_string_lossy()),
+        None => format!("{}.{}", stem, aov.name())
+    };
+    output.with_file_name(name)
+}
+
+/// The AOVs of every pixel, top row first. Albedo and emission are averaged
+/// over all samples of the pixel, normal, depth and position over the
+/// samples that hit something and are zero where nothing was. The ids can't
+/// be averaged and come from the first sample of the pixel, zero means
+/// nothing was hit. The variance describes the beauty image instead and is
+/// filled in from its accumulator.
+#[derive(Clone)]
+pub struct Aovs {
+    pub width: u32,
+    pub height: u32,
+    pub albedo: Vec<Vec3A>,
+    pub emission: Vec<Vec3A>,
+    pub normal: Vec<Vec3A>,
+    pub depth: Vec<f32>,
+    pub position: Vec<Vec3A>,
+    pub object_id: Vec<u32>,
+    pub material_id: Vec<u32>,
+    pub variance: Vec<f32>
+}
+
+fn scale_to_largest(values: &[f32]) -> Vec<Vec3A> {
+    let largest = values.iter().copied().filter(|v| v.is_finite()).fold(0.0, f32::max).max(f32::MIN_POSITIVE);
+    values.iter().map(|v| Vec3A::splat((*v / largest).min(1.0))).collect()
+}
+
+/// A colour that tells neighbouring ids apart.
+fn id_color(id: u32) -> Vec3A {
+    if id == 0 {
+        return Vec3A::ZERO;
+    }
+    let hash = rng::mix(id as u64);
+    let channel = |shift: u32| 0.2 + 0.8 * ((hash >> shift) & 0xff) as f32 / 255.0;
+    Vec3A::new(channel(0), channel(8), channel(16))
+}
+
+impl Aovs {
+    pub fn new(width: u32, height: u32) -> Self {
+        let count = (width * height) as usize;
+        Aovs {
+            width,
+            height,
+            albedo: vec![Vec3A::ZERO; count],
+            emission: vec![Vec3A::ZERO; count],
+            normal: vec![Vec3A::ZERO; count],
+            depth: vec![0.0; count],
+            position: vec![Vec3A::ZERO; count],
+            object_id: vec![0; count],
+            material_id: vec![0; count],
+            variance: vec![0.0; count]
+        }
+    }
+
+    /// The image of `aov` with the raw values, ids are stored as floats.
+    pub fn to_framebuffer(&self, aov: AovType) -> Framebuffer {
+        let pixels = match aov {
+            AovType::Albedo => self.albedo.clone(),
+            AovType::Emission => self.emission.clone(),
+            AovType::Normal => self.normal.clone(),
+            AovType::Depth => self.depth.iter().map(|d| Vec3A::splat(*d)).collect(),
+            AovType::Position => self.position.clone(),
+            AovType::ObjectId => self.object_id.iter().map(|id| Vec3A::splat(*id as f32)).collect(),
+            AovType::MaterialId => self.material_id.iter().map(|id| Vec3A::splat(*id as f32)).collect(),
+            AovType::Variance => self.variance.iter().map(|v| Vec3A::splat(*v)).collect()
+        };
+        Framebuffer::from_pixels(self.width, self.height, pixels)
+    }
+
+    /// The image of `aov` brought into [0, 1] for 8-bit formats: normals are
+    /// mapped from [-1, 1], depth and variance are divided by their largest
+    /// value, positions are scaled to the bounds of everything hit and ids
+    /// get a colour each.
+    pub fn to_display_framebuffer(&self, aov: AovType) -> Framebuffer {
+        // Everything hit is in front of the camera
+        let hit = |i: usize| self.depth[i] > 0.0;
+        let pixels = match aov {
+            AovType::Albedo => self.albedo.clone(),
+            AovType::Emission => self.emission.clone(),
+            AovType::Normal => self.normal.iter().map(|n| *n * 0.5 + Vec3A::splat(0.5)).collect(),
+            AovType::Depth => scale_to_largest(&self.depth),
+            AovType::Variance => scale_to_largest(&self.variance),
+            AovType::Position => {
+                let (min, max) = (0..self.position.len()).filter(|i| hit(*i))
+                    .fold((Vec3A::splat(f32::INFINITY), Vec3A::splat(f32::NEG_INFINITY)),
+                        |(min, max), i| (min.min(self.position[i]), max.max(self.position[i])));
+                let extent = (max - min).max(Vec3A::splat(f32::MIN_POSITIVE));
+                (0..self.position.len())
+                    .map(|i| if hit(i) { (self.position[i] - min) / extent } else { Vec3A::ZERO })
+                    .collect()
+            },
+            AovType::ObjectId => self.object_id.iter().map(|id| id_color(*id)).collect(),
+            AovType::MaterialId => self.material_id.iter().map(|id| id_color(*id)).collect()
+        };
+        Framebuffer::from_pixels(self.width, self.height, pixels)
+    }
+}
@@ -0,0 +1,109 @@
+use rayon::prelude::*;
+
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::camera::Camera;
+use crate::shapes::Hittable;
+use crate::bvh_node::{BVHNode, BVHBuildOptions};
+use crate::flat_bvh::FlatBVH;
+use crate::vec3_helpers;
+use crate::sampler::{Sampler, IndependentSampler};
+
+use std::time::{Duration, Instant};
+
+/// Each structure traces the ray set this many times and keeps its best time
+const REPETITIONS: usize = 5;
+
+struct TraceResult {
+    best: Duration,
+    hits: usize
+}
+
+impl TraceResult {
+    fn new() -> Self {
+        TraceResult { best: Duration::MAX, hits: 0 }
+    }
+}
+
+fn trace_all<H: Hittable>(bvh: &H, rays: &[Ray], result: &mut TraceResult) {
+    let start = Instant::now();
+    result.hits = rays.par_iter()
+        .map_init(|| IndependentSampler::new(0), |sampler, r| bvh.intersect(r, 0.001, f32::INFINITY, sampler).is_some())
+        .filter(|hit| *hit)
+        .count();
+    result.best = result.best.min(start.elapsed());
+}
+
+/// One camera ray through the centre of every pixel, followed by one diffuse
+/// bounce from every point the camera rays hit, so both coherent and
+/// incoherent rays are measured. The rays are generated once and shared by
+/// both structures.
+fn generate_rays(bvh: &BVHNode, camera: &Camera, image_width: u32, image_height: u32) -> Vec<Ray> {
+    let mut sampler = IndependentSampler::new(0);
+    let primary: Vec<Ray> = (0..image_width * image_height)
+        .map(|i| {
+            let u = ((i % image_width) as f32 + 0.5) / (image_width - 1) as f32;
+            let v = ((image_height - 1 - i / image_width) as f32 + 0.5) / (image_height - 1) as f32;
+            camera.get_ray(u, v, &mut sampler)
+        })
+        .collect();
+
+    let secondary: Vec<Ray> = primary.iter()
+        .filter_map(|r| {
+            bvh.intersect(r, 0.001, f32::INFINITY, &mut sampler).map(|record| Ray {
+                origin: record.point,
+                direction: record.normal + vec3_helpers::sample_sphere(sampler.get_2d()),
+                time: r.time
+            })
+        })
+        .collect();
+
+    let mut rays = primary;
+    rays.extend(secondary);
+    rays
+}
+
+fn rays_per_second(ray_count: usize, time: Duration) -> f64 {
+    ray_count as f64 / time.as_secs_f64().max(f64::MIN_POSITIVE) / 1.0e6
+}
+
+/// Builds both BVH layouts over `world` and prints their build and
+/// traversal times for the same set of rays.
+pub fn compare_bvhs(name: &str, world: &Scene, camera: &Camera, image_width: u32, image_height: u32, options: BVHBuildOptions) {
+    let start = Instant::now();
+    let tree = BVHNode::from_scene_with_options(world, 0.0, 1.0, options);
+    let tree_build = start.elapsed();
+
+    let start = Instant::now();
+    let flat = FlatBVH::from_scene_with_options(world, 0.0, 1.0, options);
+    let flat_build = start.elapsed();
+
+    let rays = generate_rays(&tree, camera, image_width, image_height);
+
+    // Warm up caches and the thread pool before timing
+    trace_all(&flat, &rays[..rays.len().min(1024)], &mut TraceResult::new());
+
+    // Alternate which structure goes first so neither always runs on a
+    // colder cache
+    let mut tree_result = TraceResult::new();
+    let mut flat_result = TraceResult::new();
+    for i in 0..REPETITIONS {
+        if i % 2 == 0 {
+            trace_all(&tree, &rays, &mut tree_result);
+            trace_all(&flat, &rays, &mut flat_result);
+        } else {
+            trace_all(&flat, &rays, &mut flat_result);
+            trace_all(&tree, &rays, &mut tree_result);
+        }
+    }
+
+    println!("{}: {}", name, tree.stats());
+    println!("  {} rays ({}x{} camera rays + 1 bounce)", rays.len(), image_width, image_height);
+    println!("  BVHNode  build {:>8.2} ms  trace {:>8.2} ms  {:>7.2} Mrays/s  {} hits",
+        tree_build.as_secs_f64() * 1000.0, tree_result.best.as_secs_f64() * 1000.0,
+        rays_per_second(rays.len(), tree_result.best), tree_result.hits);
+    println!("  FlatBVH  build {:>8.2} ms  trace {:>8.2} ms  {:>7.2} Mrays/s  {} hits",
+        flat_build.as_secs_f64() * 1000.0, flat_result.best.as_secs_f64() * 1000.0,
+        rays_per_second(rays.len(), flat_result.best), flat_result.hits);
+    println!("  speedup {:.2}x", tree_result.best.as_secs_f64() / flat_result.best.as_secs_f64().max(f64::MIN_POSITIVE));
+}
@@ -3,170 +3,324 @@ use crate::aabb::AABB;
 use crate::shapes::Hittable;
 use crate::scene::Scene;
 use crate::hit_record::HitRecord;
+use crate::sampler::Sampler;
+use crate::materials::Material;
 
 use glam::*;
 
-use rand::prelude::*;
+use std::fmt;
 use std::sync::Arc;
-use std::cmp::Ordering;
-
-struct EmptyHittable {
 
+/// Controls how `BVHNode` splits its objects.
+#[derive(Copy, Clone, Debug)]
+pub struct BVHBuildOptions {
+    /// Nodes with at most this many objects may become leaves
+    pub max_leaf_size: usize,
+    /// Number of buckets the centroids are sorted into per axis
+    pub bin_count: usize
 }
 
-impl Hittable for EmptyHittable {
-    fn intersect(&self, _ray: &Ray, _t_min: f32, _t_max: f32) -> Option<HitRecord> {
-        return Option::None;
+impl Default for BVHBuildOptions {
+    fn default() -> Self {
+        BVHBuildOptions {
+            max_leaf_size: 4,
+            bin_count: 16
+        }
     }
-    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
-        return None;
+}
+
+// Relative costs of visiting a node and intersecting an object, used by the
+// surface area heuristic.
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+pub enum BVHContents {
+    Node {
+        left: Box<BVHNode>,
+        right: Box<BVHNode>,
+        /// Axis the objects were split along
+        axis: usize
+    },
+    Leaf {
+        objects: Vec<Arc<dyn Hittable>>
     }
 }
 
 pub struct BVHNode {
-    left: Arc<dyn Hittable>,
-    right: Arc<dyn Hittable>,
-    bounding_box: AABB
+    pub contents: BVHContents,
+    pub bounding_box: AABB
 }
 
-fn axis_max(first: (f32, usize), second: (f32, usize)) -> (f32, usize) {
-    if first.0 >= second.0 {
-        return first;
-    } else {
-        return second;
-    }
+/// An object with its bounds cached for the build.
+struct BuildEntry {
+    object: Arc<dyn Hittable>,
+    bounds: AABB,
+    centroid: Vec3A
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bounds: AABB,
+    count: usize
+}
+
+fn surface_area(aabb: &AABB) -> f32 {
+    let extent = (aabb.max - aabb.min).max(Vec3A::ZERO);
+    2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
 }
 
 impl BVHNode {
-    pub fn new() -> BVHNode {
-        BVHNode {
-            left: Arc::new(EmptyHittable{}),
-            right: Arc::new(EmptyHittable{}),
-            bounding_box: AABB::new()
-        }
-    }
     pub fn from_scene(scene: &Scene, t0: f32, t1: f32) -> BVHNode {
-        return BVHNode::from_vector(scene.shapes[0..].to_vec(), t0, t1);
+        BVHNode::from_vector(scene.shapes[0..].to_vec(), t0, t1)
+    }
+
+    pub fn from_scene_with_options(scene: &Scene, t0: f32, t1: f32, options: BVHBuildOptions) -> BVHNode {
+        BVHNode::from_vector_with_options(scene.shapes[0..].to_vec(), t0, t1, options)
     }
 
     pub fn from_vector(objects: Vec<Arc<dyn Hittable>>, t0: f32, t1: f32) -> BVHNode {
-        let mut objects = objects.clone();
+        BVHNode::from_vector_with_options(objects, t0, t1, BVHBuildOptions::default())
+    }
 
-        // let (min, max)  = objects.iter().fold((Vec3A::ONE * f32::INFINITY, Vec3A::ONE * -f32::INFINITY), |acc, object| {
-        //     let obj_aabb = object.bounding_box(0.0,0.0).unwrap();
+    /// Builds the tree top-down with a binned surface area heuristic. The
+    /// result only depends on the objects and their order, so the same scene
+    /// always produces the same tree.
+    pub fn from_vector_with_options(objects: Vec<Arc<dyn Hittable>>, t0: f32, t1: f32, options: BVHBuildOptions) -> BVHNode {
+        let options = BVHBuildOptions {
+            max_leaf_size: options.max_leaf_size.max(1),
+            bin_count: options.bin_count.max(2)
+        };
+
+        let entries = objects.into_iter()
+            .map(|object| {
+                // Unbounded objects (empty scenes) get a degenerate box at the origin
+                let bounds = object.bounding_box(t0, t1).unwrap_or(AABB{ min: Vec3A::ZERO, max: Vec3A::ZERO });
+                BuildEntry {
+                    object,
+                    centroid: 0.5 * (bounds.min + bounds.max),
+                    bounds
+                }
+            })
+            .collect();
 
-        //     (acc.0.min(obj_aabb.min), acc.1.max(obj_aabb.max))
-        // });
+        BVHNode::build(entries, &options)
+    }
 
-        // let range = max - min;
+    fn build(mut entries: Vec<BuildEntry>, options: &BVHBuildOptions) -> BVHNode {
+        let bounding_box = entries.iter().fold(AABB::new(), |acc, e| AABB::surrounding_box(&acc, &e.bounds));
+        let count = entries.len();
 
-        // let (extent, axis) = axis_max(axis_max((range.x, 0), (range.y, 1)), (range.z, 2));
-        let mut rng = rand::thread_rng();
+        if count <= 1 {
+            return BVHNode::leaf(entries, bounding_box);
+        }
 
-        let axis = rng.gen_range(0..3);
+        let centroid_bounds = entries.iter().fold(AABB::new(), |acc, e| AABB{ min: acc.min.min(e.centroid), max: acc.max.max(e.centroid) });
+        let centroid_extent = centroid_bounds.max - centroid_bounds.min;
+        let parent_area = surface_area(&bounding_box);
 
-        let mut root = BVHNode::new();
+        // Find the cheapest bin boundary over all three axes
+        let mut best: Option<(f32, usize, usize)> = None;
 
-        let span = objects.len();
+        for axis in 0..3 {
+            if centroid_extent[axis] <= 0.0 {
+                continue;
+            }
 
-        if span == 1 {
-            root.left = objects[0].clone();
-        } else if span == 2 {
-            let result = BVHNode::box_compare(&objects[0], &objects[1], axis);
+            let bin_count = options.bin_count;
+            let mut bins = vec![Bin{ bounds: AABB::new(), count: 0 }; bin_count];
 
-            match result {
-                Ordering::Less => {
-                    root.left = objects[0].clone();
-                    root.right = objects[1].clone();
-                },
-                _ => {
-                    root.right = objects[0].clone();
-                    root.left = objects[1].clone();
-                }
+            for entry in entries.iter() {
+                let bin = BVHNode::bin_index(entry.centroid, &centroid_bounds, axis, bin_count);
+                bins[bin].bounds = AABB::surrounding_box(&bins[bin].bounds, &entry.bounds);
+                bins[bin].count += 1;
             }
-        } else {            
-            
-            objects.sort_by(|a,b| BVHNode::box_compare(a, b, axis));
-
-            let mid = span / 2;
-            let left = objects[..mid].to_vec();
-            let right = objects[mid..].to_vec();
-
-            if left.len() == 1 {
-                root.left = left[0].clone();
-            } else {
-                root.left = Arc::new(BVHNode::from_vector(left, t0, t1));
+
+            // Sweep from the right to get the cost of everything right of
+            // each boundary, then from the left to evaluate the splits.
+            let mut right_area = vec![0.0; bin_count];
+            let mut right_count = vec![0; bin_count];
+            let mut acc = Bin{ bounds: AABB::new(), count: 0 };
+            for i in (1..bin_count).rev() {
+                acc.bounds = AABB::surrounding_box(&acc.bounds, &bins[i].bounds);
+                acc.count += bins[i].count;
+                right_area[i] = surface_area(&acc.bounds);
+                right_count[i] = acc.count;
             }
-            if right.len() == 1 {
-                root.right = right[0].clone()
-            } else {
-                root.right = Arc::new(BVHNode::from_vector(objects[mid..].to_vec(), t0, t1));
+
+            let mut acc = Bin{ bounds: AABB::new(), count: 0 };
+            for split in 1..bin_count {
+                acc.bounds = AABB::surrounding_box(&acc.bounds, &bins[split - 1].bounds);
+                acc.count += bins[split - 1].count;
+
+                if acc.count == 0 || right_count[split] == 0 {
+                    continue;
+                }
+
+                let cost = TRAVERSAL_COST + INTERSECTION_COST *
+                    (surface_area(&acc.bounds) * acc.count as f32 + right_area[split] * right_count[split] as f32) / parent_area.max(f32::MIN_POSITIVE);
+
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, split));
+                }
             }
         }
 
-        let mut left_aabb = AABB::new();
-        let mut right_aabb = AABB::new();
+        let leaf_cost = INTERSECTION_COST * count as f32;
+
+        let (right_entries, split_axis) = match best {
+            Some((cost, axis, split)) if cost < leaf_cost || count > options.max_leaf_size => {
+                // Stable partition keeps the build deterministic
+                let (left, right): (Vec<BuildEntry>, Vec<BuildEntry>) = entries.into_iter()
+                    .partition(|e| BVHNode::bin_index(e.centroid, &centroid_bounds, axis, options.bin_count) < split);
+                entries = left;
+                (right, axis)
+            },
+            Some(_) => return BVHNode::leaf(entries, bounding_box),
+            None => {
+                // All centroids coincide, binning can't separate them
+                if count <= options.max_leaf_size {
+                    return BVHNode::leaf(entries, bounding_box);
+                }
+                (entries.split_off(count / 2), 0)
+            }
+        };
 
-        if let Some(aabb) = root.left.bounding_box(t0, t1) {
-            left_aabb = aabb;
-        }
-        if let Some(aabb) = root.right.bounding_box(t0, t1) {
-            right_aabb = aabb;
-        }
+        let left = BVHNode::build(entries, options);
+        let right = BVHNode::build(right_entries, options);
 
-        root.bounding_box = AABB::surrounding_box(&left_aabb, &right_aabb);
+        BVHNode {
+            contents: BVHContents::Node {
+                left: Box::new(left),
+                right: Box::new(right),
+                axis: split_axis
+            },
+            bounding_box
+        }
+    }
 
-        return root;
+    fn bin_index(centroid: Vec3A, centroid_bounds: &AABB, axis: usize, bin_count: usize) -> usize {
+        let offset = (centroid[axis] - centroid_bounds.min[axis]) / (centroid_bounds.max[axis] - centroid_bounds.min[axis]);
+        ((offset * bin_count as f32) as usize).min(bin_count - 1)
     }
 
-    fn box_compare(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>, axis: usize) -> std::cmp::Ordering {
-        
-        let mut box_a = AABB::new();
-        if let Some(aabb) = a.bounding_box(0.0, 0.0) {
-            box_a = aabb;
+    fn leaf(entries: Vec<BuildEntry>, bounding_box: AABB) -> BVHNode {
+        BVHNode {
+            contents: BVHContents::Leaf {
+                objects: entries.into_iter().map(|e| e.object).collect()
+            },
+            bounding_box
         }
+    }
 
-        let mut box_b = AABB::new();
-        if let Some(aabb) = b.bounding_box(0.0, 0.0) {
-            box_b = aabb;
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats {
+            node_count: 0,
+            leaf_count: 0,
+            depth: 0,
+            object_count: 0,
+            min_leaf_size: usize::MAX,
+            max_leaf_size: 0
+        };
+        self.collect_stats(1, &mut stats);
+        if stats.leaf_count == 0 {
+            stats.min_leaf_size = 0;
         }
+        stats
+    }
 
-        if (box_a.min[axis] - box_b.min[axis]) < f32::EPSILON {
-            return Ordering::Less;
-        } else if (box_a.min[axis] - box_b.min[axis]) > f32::EPSILON {
-            return Ordering::Greater;
-        } else {
-            return Ordering::Equal;
+    fn collect_stats(&self, depth: usize, stats: &mut BVHStats) {
+        stats.node_count += 1;
+        stats.depth = stats.depth.max(depth);
+
+        match &self.contents {
+            BVHContents::Node { left, right, .. } => {
+                left.collect_stats(depth + 1, stats);
+                right.collect_stats(depth + 1, stats);
+            },
+            BVHContents::Leaf { objects } => {
+                stats.leaf_count += 1;
+                stats.object_count += objects.len();
+                stats.min_leaf_size = stats.min_leaf_size.min(objects.len());
+                stats.max_leaf_size = stats.max_leaf_size.max(objects.len());
+            }
         }
     }
 }
 
+/// Shape of a built tree, for reporting.
+#[derive(Copy, Clone, Debug)]
+pub struct BVHStats {
+    /// Interior nodes and leaves
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Number of levels, a single leaf has depth 1
+    pub depth: usize,
+    pub object_count: usize,
+    pub min_leaf_size: usize,
+    pub max_leaf_size: usize
+}
+
+impl fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let average = if self.leaf_count > 0 { self.object_count as f32 / self.leaf_count as f32 } else { 0.0 };
+        write!(f, "{} objects, {} nodes, {} leaves, depth {}, leaf size min/avg/max {}/{:.2}/{}",
+            self.object_count, self.node_count, self.leaf_count, self.depth, self.min_leaf_size, average, self.max_leaf_size)
+    }
+}
+
 impl Hittable for BVHNode {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if self.bounding_box.hit(ray, t_min, t_max) == false {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        if !self.bounding_box.hit(ray, t_min, t_max) {
             return Option::None;
         }
 
-        let mut t_max0 = t_max;
-        let mut left_result = self.left.intersect(ray, t_min, t_max0);
+        match &self.contents {
+            BVHContents::Node { left, right, .. } => {
+                let mut t_max0 = t_max;
+                let left_result = left.intersect(ray, t_min, t_max0, sampler);
 
-        if let Option::Some(left) = left_result {
-            t_max0 = left.t;
-            left_result = Option::Some(left);
-        }
+                if let Option::Some(left) = &left_result {
+                    t_max0 = left.t;
+                }
 
-        let right_result = self.right.intersect(ray, t_min, t_max0);
+                let right_result = right.intersect(ray, t_min, t_max0, sampler);
+
+                match right_result {
+                    Option::Some(_) => right_result,
+                    Option::None => left_result
+                }
+            },
+            BVHContents::Leaf { objects } => {
+                let mut closest_so_far = t_max;
+                let mut result = Option::None;
+
+                for object in objects.iter() {
+                    if let Option::Some(record) = object.intersect(ray, t_min, closest_so_far, sampler) {
+                        closest_so_far = record.t;
+                        result = Option::Some(record);
+                    }
+                }
 
-        match right_result {
-            Option::Some(_) => return right_result,
-            Option::None => return left_result
+                result
+            }
         }
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
-        return Some(self.bounding_box);
+        Some(self.bounding_box)
     }
-}
 
-unsafe impl Send for BVHNode {}
-unsafe impl Sync for BVHNode {}
\ No newline at end of file
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        match &self.contents {
+            BVHContents::Node { left, right, .. } => {
+                left.visit_materials(visit);
+                right.visit_materials(visit);
+            },
+            BVHContents::Leaf { objects } => {
+                for object in objects.iter() {
+                    object.visit_materials(visit);
+                }
+            }
+        }
+    }
+}
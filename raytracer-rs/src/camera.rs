@@ -5,11 +5,43 @@ use crate::vec3_helpers;
 use glam::*;
 
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 
 
-extern crate rand; 
 
-use rand::prelude::*;
+
+/// The parameters a camera is built from, kept separately so they can be
+/// overridden (e.g. from the command line) before the aspect ratio is known.
+#[derive(Copy, Clone, Debug)]
+pub struct CameraSettings {
+    pub origin: Vec3A,
+    pub target: Vec3A,
+    pub up: Vec3A,
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus_distance: f32,
+    pub time_start: f32,
+    pub time_end: f32
+}
+
+impl CameraSettings {
+    pub fn new(origin: Vec3A, target: Vec3A, vfov: f32, aperture: f32) -> Self {
+        CameraSettings {
+            origin,
+            target,
+            up: Vec3A::Y,
+            vfov,
+            aperture,
+            focus_distance: 10.0,
+            time_start: 0.0,
+            time_end: 1.0
+        }
+    }
+
+    pub fn build(&self, aspect_ratio: f32) -> Camera {
+        Camera::new(self.origin, self.target, self.up, self.vfov, aspect_ratio, self.aperture, self.focus_distance, self.time_start, self.time_end)
+    }
+}
 
 pub struct Camera {
     origin: Vec3A,
@@ -25,6 +57,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(origin: Vec3A, target: Vec3A, up: Vec3A, vfov: f32, aspect_ratio: f32, aperture: f32, focus_distance: f32, time_start: f32, time_end: f32) -> Camera {
         let h = f32::tan(vfov / 2.0);
         let viewport_height = 2.0 * h;
@@ -38,29 +71,34 @@ impl Camera {
         let vertical = focus_distance * viewport_height * v;
 
         Camera {
-            origin: origin,
+            origin,
             lower_left_corner: origin - horizontal / 2.0 - vertical / 2.0 - focus_distance * w,
-            horizontal: horizontal,
-            vertical: vertical,
-            u: u,
-            v: v,
-            w: w,
+            horizontal,
+            vertical,
+            u,
+            v,
+            w,
             lens_radius: aperture / 2.0,
-            time_start: time_start,
-            time_end: time_end
+            time_start,
+            time_end
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * vec3_helpers::random_in_unit_disk();
-        let offset = self.u * rd.x + self.v * rd.y;
+    /// Distance of `point` from the camera along the viewing direction.
+    pub fn depth(&self, point: Vec3A) -> f32 {
+        (self.origin - point).dot(self.w)
+    }
 
-        let mut rng = rand::thread_rng();
+    /// Takes the lens position and the time from the next three dimensions of `sampler`.
+    /// The direction has unit length, so distances along the ray are its `t`.
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens_radius * vec3_helpers::sample_disk(sampler.get_2d());
+        let offset = self.u * rd.x + self.v * rd.y;
 
         Ray {
             origin: self.origin + offset,
-            direction: self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
-            time: rng.gen_range(self.time_start..self.time_end)
+            direction: (self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset).normalize(),
+            time: self.time_start + sampler.get_1d() * (self.time_end - self.time_start)
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,342 @@
+//! Saves the state of a render so a later run can pick it up again.
+//!
+//! A checkpoint file is a magic line, the length of a TOML header followed
+//! by the header itself, then for every pixel (top row first) the summed
+//! radiance as three little endian f32, the summed squared luminance as a
+//! little endian f32 and the sample count as a little endian u32. The
+//! header records everything that changes what a sample means, so a render
+//! is only ever resumed with the settings it was started with.
+
+extern crate glam;
+
+use glam::*;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::CameraSettings;
+use crate::render::Accumulator;
+use crate::sampler::SamplerType;
+use crate::environment::{EnvironmentSettings, SkySettings};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &str = "RAYTRACER-RS CHECKPOINT 2";
+
+/// The scene, camera and image parameters a checkpoint was rendered with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RenderConfig {
+    /// Built-in scene name, or the path of the scene file
+    pub scene: String,
+    /// FNV-1a hash of the scene file contents, empty for built-in scenes
+    pub scene_hash: String,
+    pub width: u32,
+    pub height: u32,
+    pub max_depth: i32,
+    pub background: [f32; 3],
+    pub light_sampling: bool,
+    /// Hexadecimal, TOML integers can't hold every u64
+    pub seed: String,
+    pub sampler: String,
+    pub origin: [f32; 3],
+    pub target: [f32; 3],
+    pub up: [f32; 3],
+    pub vfov: f32,
+    pub aspect_ratio: f32,
+    pub aperture: f32,
+    pub focus_distance: f32,
+    pub time_start: f32,
+    pub time_end: f32,
+    /// Path of the environment map, empty without one. Older checkpoints
+    /// have none of the environment fields.
+    #[serde(default)]
+    pub environment_map: String,
+    /// FNV-1a hash of the environment map file
+    #[serde(default)]
+    pub environment_hash: String,
+    #[serde(default)]
+    pub environment_rotation: f32,
+    #[serde(default)]
+    pub environment_intensity: f32,
+    /// `SkySettings` in their debug form, empty without a sky
+    #[serde(default)]
+    pub sky: String,
+    #[serde(default)]
+    pub spectral: bool
+}
+
+impl RenderConfig {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(scene: String, scene_hash: String, width: u32, height: u32, max_depth: i32, background: Vec3A,
+        light_sampling: bool, seed: u64, sampler: SamplerType, camera: &CameraSettings, aspect_ratio: f32) -> Self {
+        RenderConfig {
+            scene,
+            scene_hash,
+            width,
+            height,
+            max_depth,
+            background: background.into(),
+            light_sampling,
+            seed: format!("{:016x}", seed),
+            sampler: sampler.name().to_string(),
+            origin: camera.origin.into(),
+            target: camera.target.into(),
+            up: camera.up.into(),
+            vfov: camera.vfov,
+            aspect_ratio,
+            aperture: camera.aperture,
+            focus_distance: camera.focus_distance,
+            time_start: camera.time_start,
+            time_end: camera.time_end,
+            environment_map: String::new(),
+            environment_hash: String::new(),
+            environment_rotation: 0.0,
+            environment_intensity: 0.0,
+            sky: String::new(),
+            spectral: false
+        }
+    }
+
+    /// Records the environment map, `hash` being the hash of its file.
+    pub fn with_environment(mut self, environment: &EnvironmentSettings, hash: String) -> Self {
+        self.environment_map = environment.path.display().to_string();
+        self.environment_hash = hash;
+        self.environment_rotation = environment.rotation;
+        self.environment_intensity = environment.intensity;
+        self
+    }
+
+    pub fn with_sky(mut self, sky: &SkySettings) -> Self {
+        self.sky = format!("{:?}", sky);
+        self
+    }
+
+    pub fn with_spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+
+    /// The names of the fields that differ between the two configurations.
+    pub fn differences(&self, other: &RenderConfig) -> Vec<&'static str> {
+        let checks = [
+            ("scene", self.scene != other.scene),
+            ("scene file contents", self.scene_hash != other.scene_hash),
+            ("width", self.width != other.width),
+            ("height", self.height != other.height),
+            ("max depth", self.max_depth != other.max_depth),
+            ("background", self.background != other.background),
+            ("light sampling", self.light_sampling != other.light_sampling),
+            ("seed", self.seed != other.seed),
+            ("sampler", self.sampler != other.sampler),
+            ("camera origin", self.origin != other.origin),
+            ("camera target", self.target != other.target),
+            ("camera up", self.up != other.up),
+            ("field of view", self.vfov != other.vfov),
+            ("aspect ratio", self.aspect_ratio != other.aspect_ratio),
+            ("aperture", self.aperture != other.aperture),
+            ("focus distance", self.focus_distance != other.focus_distance),
+            ("shutter interval", self.time_start != other.time_start || self.time_end != other.time_end),
+            ("environment map", self.environment_map != other.environment_map || self.environment_hash != other.environment_hash),
+            ("environment rotation", self.environment_rotation != other.environment_rotation),
+            ("environment intensity", self.environment_intensity != other.environment_intensity),
+            ("sky", self.sky != other.sky),
+            ("spectral rendering", self.spectral != other.spectral),
+        ];
+        checks.iter().filter(|(_, differs)| *differs).map(|(name, _)| *name).collect()
+    }
+}
+
+/// 64-bit FNV-1a, used to notice when a scene file changed between runs.
+fn hash_bytes(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// `hash_bytes` of the contents of the file at `path`.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    Ok(hash_bytes(&fs::read(path)?))
+}
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Malformed(String),
+    /// The checkpoint was rendered with other settings, lists the differences
+    Mismatch(Vec<&'static str>)
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io(e) => write!(f, "{}", e),
+            CheckpointError::Malformed(message) => write!(f, "{}", message),
+            CheckpointError::Mismatch(fields) => write!(f, "it was rendered with a different {}", fields.join(", "))
+        }
+    }
+}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        CheckpointError::Io(e)
+    }
+}
+
+/// Writes `accumulator` and `config` to `path`. The file is written next to
+/// the destination first and then renamed, so an interrupted write never
+/// destroys the previous checkpoint.
+pub fn save<P: AsRef<Path>>(path: P, config: &RenderConfig, accumulator: &Accumulator) -> Result<(), CheckpointError> {
+    let path = path.as_ref();
+    let header = toml::to_string(config).map_err(|e| CheckpointError::Malformed(e.to_string()))?;
+
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = Path::new(&temp_name);
+
+    let mut writer = BufWriter::new(File::create(temp_path)?);
+    write!(writer, "{}\n{}\n{}", MAGIC, header.len(), header)?;
+    for i in 0..accumulator.sum.len() {
+        let sum = accumulator.sum[i];
+        for value in [sum.x, sum.y, sum.z, accumulator.sum_squared[i]].iter() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&accumulator.samples[i].to_le_bytes())?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+/// Reads the checkpoint at `path`, refusing it unless it was rendered with
+/// exactly `config`.
+pub fn load<P: AsRef<Path>>(path: P, config: &RenderConfig) -> Result<Accumulator, CheckpointError> {
+    let path = path.as_ref();
+    let mut reader = BufReader::new(File::open(path)?);
+    let malformed = || CheckpointError::Malformed(format!("{} is not a valid checkpoint file", path.display()));
+
+    let mut magic = String::new();
+    reader.read_line(&mut magic)?;
+    if magic.trim_end() != MAGIC {
+        return Err(malformed());
+    }
+
+    let mut length = String::new();
+    reader.read_line(&mut length)?;
+    let length: usize = length.trim_end().parse().map_err(|_| malformed())?;
+
+    // Not preallocated, a corrupt length must not decide how much memory
+    // to ask for
+    let mut header = Vec::new();
+    reader.by_ref().take(length as u64).read_to_end(&mut header)?;
+    if header.len() != length {
+        return Err(malformed());
+    }
+    let header = String::from_utf8(header).map_err(|_| malformed())?;
+    let saved: RenderConfig = toml::from_str(&header)
+        .map_err(|e| CheckpointError::Malformed(format!("{} has an invalid header: {}", path.display(), e)))?;
+
+    let differences = saved.differences(config);
+    if !differences.is_empty() {
+        return Err(CheckpointError::Mismatch(differences));
+    }
+
+    let mut accumulator = Accumulator::new(saved.width, saved.height);
+    let mut pixel = [0_u8; 20];
+    for i in 0..accumulator.sum.len() {
+        reader.read_exact(&mut pixel).map_err(|_| malformed())?;
+        let value = |offset: usize| f32::from_le_bytes([pixel[offset], pixel[offset + 1], pixel[offset + 2], pixel[offset + 3]]);
+        accumulator.sum[i] = Vec3A::new(value(0), value(4), value(8));
+        accumulator.sum_squared[i] = value(12);
+        accumulator.samples[i] = u32::from_le_bytes([pixel[16], pixel[17], pixel[18], pixel[19]]);
+    }
+    Ok(accumulator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("raytracer-rs-{}-{}", std::process::id(), name))
+    }
+
+    fn config() -> RenderConfig {
+        let camera = CameraSettings::new(Vec3A::new(0.0, 1.0, 5.0), Vec3A::ZERO, 40f32.to_radians(), 0.1);
+        RenderConfig::new(String::from("cornell-box"), String::new(), 3, 2, 8, Vec3A::ZERO, true, 42, SamplerType::Sobol, &camera, 1.5)
+    }
+
+    fn accumulator() -> Accumulator {
+        let mut accumulator = Accumulator::new(3, 2);
+        for i in 0..accumulator.sum.len() {
+            accumulator.sum[i] = Vec3A::new(i as f32, 0.5 * i as f32, 1e6 + i as f32);
+            accumulator.sum_squared[i] = 0.25 * i as f32;
+            accumulator.samples[i] = 16 + i as u32;
+        }
+        accumulator
+    }
+
+    #[test]
+    fn round_trips_the_accumulator() {
+        let path = temp_path("round-trip.checkpoint");
+        let saved = accumulator();
+        save(&path, &config(), &saved).unwrap();
+        let loaded = load(&path, &config());
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!((loaded.width, loaded.height), (saved.width, saved.height));
+        assert_eq!(loaded.sum, saved.sum);
+        assert_eq!(loaded.sum_squared, saved.sum_squared);
+        assert_eq!(loaded.samples, saved.samples);
+    }
+
+    #[test]
+    fn refuses_truncated_files() {
+        let path = temp_path("truncated.checkpoint");
+        save(&path, &config(), &accumulator()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        let mut results = Vec::new();
+        // Cut into the last pixel, into the header and before the header length
+        for length in [bytes.len() - 7, MAGIC.len() + 20, MAGIC.len() + 1].iter() {
+            fs::write(&path, &bytes[..*length]).unwrap();
+            results.push(load(&path, &config()));
+        }
+        fs::remove_file(&path).unwrap();
+
+        for result in results {
+            assert!(matches!(result, Err(CheckpointError::Malformed(_))), "{:?}", result.map(|a| a.samples));
+        }
+    }
+
+    #[test]
+    fn refuses_absurd_header_lengths() {
+        let path = temp_path("header-length.checkpoint");
+        fs::write(&path, format!("{}\n{}\nscene = \"x\"\n", MAGIC, u64::MAX)).unwrap();
+        let result = load(&path, &config());
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(CheckpointError::Malformed(_))), "{:?}", result.map(|a| a.samples));
+    }
+
+    #[test]
+    fn refuses_other_settings() {
+        let path = temp_path("mismatch.checkpoint");
+        save(&path, &config(), &accumulator()).unwrap();
+        let mut other = config();
+        other.max_depth = 4;
+        other.seed = format!("{:016x}", 7);
+        let result = load(&path, &other);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(CheckpointError::Mismatch(fields)) => assert_eq!(fields, vec!["max depth", "seed"]),
+            _ => panic!("{:?}", result.map(|a| a.samples))
+        }
+    }
+}
@@ -0,0 +1,695 @@
+extern crate glam;
+
+use glam::*;
+
+use crate::framebuffer::OutputFormat;
+use crate::tonemap::{ToneMapOperator, ToneMapSettings};
+use crate::sampler::SamplerType;
+use crate::aov::AovType;
+use crate::environment::SkyModel;
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageQuality {
+    Low,
+    High,
+    Cornell,
+    Final
+}
+
+impl ImageQuality {
+    const NAMES: [(&'static str, ImageQuality); 4] = [
+        ("low", ImageQuality::Low),
+        ("high", ImageQuality::High),
+        ("cornell", ImageQuality::Cornell),
+        ("final", ImageQuality::Final),
+    ];
+
+    pub fn from_name(name: &str) -> Option<ImageQuality> {
+        ImageQuality::NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, q)| *q)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SceneType {
+    Random,
+    TwoSpheres,
+    PerlinSpheres,
+    Earth,
+    SimpleLight,
+    CornellBox,
+    CornellSmoke,
+    FinalScene
+}
+
+impl SceneType {
+    const NAMES: [(&'static str, SceneType); 8] = [
+        ("random", SceneType::Random),
+        ("two-spheres", SceneType::TwoSpheres),
+        ("perlin-spheres", SceneType::PerlinSpheres),
+        ("earth", SceneType::Earth),
+        ("simple-light", SceneType::SimpleLight),
+        ("cornell-box", SceneType::CornellBox),
+        ("cornell-smoke", SceneType::CornellSmoke),
+        ("final", SceneType::FinalScene),
+    ];
+
+    pub fn from_name(name: &str) -> Option<SceneType> {
+        SceneType::NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, s)| *s)
+    }
+
+    pub fn name(&self) -> &'static str {
+        SceneType::NAMES.iter().find(|(_, s)| s == self).map(|(n, _)| *n).unwrap()
+    }
+
+    pub fn all() -> impl Iterator<Item = SceneType> {
+        SceneType::NAMES.iter().map(|(_, s)| *s)
+    }
+}
+
+fn name_list<T>(names: &[(&'static str, T)]) -> String {
+    names.iter().map(|(n, _)| *n).collect::<Vec<&str>>().join(", ")
+}
+
+#[derive(Debug)]
+pub struct CliError {
+    pub message: String
+}
+
+impl CliError {
+    fn new<S: Into<String>>(message: S) -> Self {
+        CliError {
+            message: message.into()
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Everything the user can override from the command line. Camera and image
+/// values left as `None` fall back to the defaults of the selected scene and
+/// quality preset.
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub scene: Option<SceneType>,
+    pub scene_file: Option<String>,
+    pub quality: ImageQuality,
+    pub image_width: Option<u32>,
+    pub aspect_ratio: Option<f32>,
+    pub samples_per_pixel: Option<u32>,
+    pub max_depth: Option<i32>,
+    pub origin: Option<Vec3A>,
+    pub target: Option<Vec3A>,
+    pub fov: Option<f32>,
+    pub aperture: Option<f32>,
+    pub focus_distance: Option<f32>,
+    pub background: Option<Vec3A>,
+    /// Equirectangular HDR image lighting the scene, replaces the background
+    pub environment: Option<String>,
+    /// Degrees around +Y
+    pub environment_rotation: Option<f32>,
+    pub environment_intensity: Option<f32>,
+    /// Procedural sky replacing the background
+    pub sky: Option<SkyModel>,
+    /// Elevation and azimuth in degrees
+    pub sun: Option<(f32, f32)>,
+    pub turbidity: Option<f32>,
+    /// Degrees
+    pub sun_radius: Option<f32>,
+    pub bvh_leaf_size: Option<usize>,
+    pub benchmark_bvh: bool,
+    pub light_sampling: bool,
+    /// Drives the scene generators and every sample, the same seed gives the same image
+    pub seed: u64,
+    pub sampler: SamplerType,
+    pub tone_map: ToneMapSettings,
+    pub progressive: bool,
+    pub pass_samples: Option<u32>,
+    /// Seconds between progressive previews
+    pub preview_interval: Option<f32>,
+    pub adaptive: bool,
+    pub min_samples: Option<u32>,
+    pub max_samples: Option<u32>,
+    /// Relative error below which adaptive sampling leaves a pixel alone
+    pub adaptive_threshold: Option<f32>,
+    /// Also write the number of samples of every pixel to this image
+    pub sample_count_output: Option<String>,
+    /// Also write these AOVs next to --output
+    pub aovs: Vec<AovType>,
+    /// Overrides the `denoise` setting of the scene file
+    pub denoise: Option<bool>,
+    /// Overrides the `spectral` setting of the scene file
+    pub spectral: Option<bool>,
+    pub checkpoint: Option<String>,
+    /// Seconds between checkpoint writes
+    pub checkpoint_interval: Option<f32>,
+    pub resume: bool,
+    /// Tone map (and optionally denoise) this HDR image instead of rendering
+    pub input: Option<String>,
+    pub output: String
+}
+
+impl RenderOptions {
+    pub fn new() -> Self {
+        RenderOptions {
+            scene: None,
+            scene_file: None,
+            quality: ImageQuality::Final,
+            image_width: None,
+            aspect_ratio: None,
+            samples_per_pixel: None,
+            max_depth: None,
+            origin: None,
+            target: None,
+            fov: None,
+            aperture: None,
+            focus_distance: None,
+            background: None,
+            environment: None,
+            environment_rotation: None,
+            environment_intensity: None,
+            sky: None,
+            sun: None,
+            turbidity: None,
+            sun_radius: None,
+            bvh_leaf_size: None,
+            benchmark_bvh: false,
+            light_sampling: true,
+            seed: 0,
+            sampler: SamplerType::Sobol,
+            tone_map: ToneMapSettings::default(),
+            progressive: false,
+            pass_samples: None,
+            preview_interval: None,
+            adaptive: false,
+            min_samples: None,
+            max_samples: None,
+            adaptive_threshold: None,
+            sample_count_output: None,
+            aovs: Vec::new(),
+            denoise: None,
+            spectral: None,
+            checkpoint: None,
+            checkpoint_interval: None,
+            resume: false,
+            input: None,
+            output: String::from("./test.png")
+        }
+    }
+}
+
+pub enum Command {
+    Render(Box<RenderOptions>),
+    Help
+}
+
+pub fn usage() -> String {
+    format!(
+"Usage: raytracer-rs [OPTIONS]
+
+Options:
+  -s, --scene <NAME>           Scene to render [{}] (default: final)
+  -f, --scene-file <PATH>      Load the scene, camera and background from a TOML scene description
+  -q, --quality <PRESET>       Resolution/sampling preset [{}] (default: final)
+  -w, --width <PIXELS>         Image width in pixels
+  -a, --aspect <RATIO>         Aspect ratio, either a number (1.5) or W:H (16:9)
+  -n, --spp <COUNT>            Samples per pixel
+  -d, --max-depth <COUNT>      Maximum number of bounces per path
+      --origin <X,Y,Z>         Camera position
+      --target <X,Y,Z>         Point the camera looks at
+      --fov <DEGREES>          Vertical field of view
+      --aperture <SIZE>        Lens aperture (0 disables depth of field)
+      --focus-distance <DIST>  Distance to the plane in focus
+      --background <R,G,B>     Radiance returned by rays that escape the scene
+      --environment <FILE>     Light the scene with an equirectangular .hdr, .exr or .pfm image instead of
+                               the background; it is importance sampled like the other lights
+      --environment-rotation <DEGREES>
+                               Turn the environment map around the vertical axis
+      --environment-intensity <SCALE>
+                               Multiply the environment map's radiance (default: 1)
+      --sky <MODEL>            Procedural sky instead of the background [gradient, preetham]; the
+                               preetham daylight sky has a sun and is sampled like the other lights
+      --sun <ELEVATION,AZIMUTH>
+                               Sun position in degrees above the horizon and from -Z towards +X
+                               (default: 45,0)
+      --turbidity <VALUE>      Haziness of the preetham sky, from 2 (clear) to 10 (default: 3)
+      --sun-radius <DEGREES>   Angular radius of the sun; larger suns give softer shadows (default: 0.27)
+      --bvh-leaf-size <COUNT>  Largest number of objects the scene BVH keeps in one leaf (default: 4)
+      --benchmark-bvh          Compare the tree and flattened BVH layouts on the selected scene
+                               (or every built-in scene) instead of rendering
+      --no-light-sampling      Only find lights by following BSDF samples (brute-force path tracing)
+      --spectral               Trace wavelengths instead of RGB so dispersive glass splits light into colours;
+                               colours are turned into spectra and back, which costs some saturation
+      --no-spectral            Render in RGB even if the scene file asks for spectral rendering
+      --seed <NUMBER>          Seed for the random scenes and the sampling; the same seed renders the
+                               same image whatever the thread count (default: 0)
+      --sampler <NAME>         How sample values are distributed [{}] (default: sobol)
+      --exposure <STOPS>       Exposure adjustment applied before tone mapping (default: 0)
+      --tonemap <OPERATOR>     Tone mapping operator for 8-bit output [{}] (default: clamp)
+      --linear                 Write 8-bit output without the sRGB transfer curve
+  -p, --progressive            Render in passes over the whole image, saving --output after each pass
+      --pass-spp <COUNT>       Samples per pixel in each progressive pass (default: 4, implies --progressive)
+      --preview-every <SECS>   Save previews at most this often instead of after every pass (implies --progressive)
+      --adaptive               Stop sampling pixels once they are converged and spend the rest of the
+                               --spp budget on the noisy ones
+      --min-spp <COUNT>        Samples every pixel gets in adaptive mode (default: 16, implies --adaptive)
+      --max-spp <COUNT>        Most samples a pixel gets in adaptive mode (default: 4x --spp, implies --adaptive)
+      --adaptive-threshold <ERROR>
+                               Relative standard error at which a pixel counts as converged
+                               (default: 0.01, implies --adaptive)
+      --sample-count-image <FILE>
+                               Also write the number of samples per pixel; 8-bit formats are scaled so
+                               the most sampled pixel is white
+      --aovs <LIST>            Also write first-hit AOVs next to --output, a comma separated list of
+                               [{}] or 'all'; render.exr gives render.albedo.exr etc.
+                               Float formats keep the raw values, 8-bit formats are scaled for viewing
+      --denoise                Filter the noise out of the image and the previews, guided by the albedo,
+                               emission, normal and variance; with --input the guides are read from the AOV files
+                               next to the input (render.albedo.exr etc.) where they exist
+      --no-denoise             Don't denoise even if the scene file asks for it
+      --checkpoint <FILE>      Save the accumulated samples to FILE periodically and on Ctrl-C
+      --checkpoint-every <SECS>
+                               Seconds between checkpoint writes (default: 300)
+      --resume                 Continue the render stored in --checkpoint; the scene, camera and image
+                               settings must match the ones it was started with
+  -i, --input <FILE>           Tone map (and with --denoise denoise) an existing .exr, .hdr or .pfm image to
+                               --output instead of rendering
+  -o, --output <FILE>          Output image; the format follows the extension (default: ./test.png).
+                               .exr, .hdr and .pfm keep the linear radiance, other formats are 8-bit
+  -h, --help                   Print this message",
+        name_list(&SceneType::NAMES), name_list(&ImageQuality::NAMES), name_list(&SamplerType::NAMES), name_list(&ToneMapOperator::NAMES),
+        name_list(&AovType::NAMES))
+}
+
+fn parse_f32(option: &str, value: &str) -> Result<f32, CliError> {
+    match value.trim().parse::<f32>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err(CliError::new(format!("invalid value '{}' for {}: expected a number", value, option)))
+    }
+}
+
+fn parse_positive_int<T: std::str::FromStr + PartialOrd + Default>(option: &str, value: &str) -> Result<T, CliError> {
+    match value.trim().parse::<T>() {
+        Ok(v) if v > T::default() => Ok(v),
+        _ => Err(CliError::new(format!("invalid value '{}' for {}: expected a positive integer", value, option)))
+    }
+}
+
+fn parse_vec3(option: &str, value: &str) -> Result<Vec3A, CliError> {
+    let parts: Vec<&str> = value.split(',').collect();
+    if parts.len() != 3 {
+        return Err(CliError::new(format!("invalid value '{}' for {}: expected three comma separated numbers", value, option)));
+    }
+
+    Ok(Vec3A::new(
+        parse_f32(option, parts[0])?,
+        parse_f32(option, parts[1])?,
+        parse_f32(option, parts[2])?
+    ))
+}
+
+fn parse_sun(option: &str, value: &str) -> Result<(f32, f32), CliError> {
+    let parts: Vec<&str> = value.split(',').collect();
+    if parts.len() != 2 {
+        return Err(CliError::new(format!("invalid value '{}' for {}: expected elevation,azimuth in degrees", value, option)));
+    }
+    let elevation = parse_f32(option, parts[0])?;
+    if elevation.abs() > 90.0 {
+        return Err(CliError::new(format!("invalid value '{}' for {}: the elevation must be between -90 and 90", value, option)));
+    }
+    Ok((elevation, parse_f32(option, parts[1])?))
+}
+
+fn parse_aovs(value: &str) -> Result<Vec<AovType>, CliError> {
+    if value.trim().eq_ignore_ascii_case("all") {
+        return Ok(AovType::all());
+    }
+
+    let mut aovs = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        let aov = AovType::from_name(name).ok_or_else(|| CliError::new(format!(
+            "unknown AOV '{}', expected 'all' or a list of: {}", name, name_list(&AovType::NAMES))))?;
+        if !aovs.contains(&aov) {
+            aovs.push(aov);
+        }
+    }
+    Ok(aovs)
+}
+
+fn parse_aspect(option: &str, value: &str) -> Result<f32, CliError> {
+    let ratio = if let Some((w, h)) = value.split_once(':') {
+        parse_f32(option, w)? / parse_f32(option, h)?
+    } else {
+        parse_f32(option, value)?
+    };
+
+    if !ratio.is_finite() || ratio <= 0.0 {
+        return Err(CliError::new(format!("invalid value '{}' for {}: the aspect ratio must be positive", value, option)));
+    }
+    Ok(ratio)
+}
+
+/// Parses the program arguments (without the executable name).
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, CliError> {
+    let mut options = RenderOptions::new();
+
+    while let Some(arg) = args.next() {
+        // Accept both `--opt value` and `--opt=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+            _ => (arg.clone(), None)
+        };
+
+        if flag == "-h" || flag == "--help" {
+            return Ok(Command::Help);
+        }
+
+        let mut value = || -> Result<String, CliError> {
+            if let Some(v) = inline_value.clone() {
+                return Ok(v);
+            }
+            match args.next() {
+                Some(v) => Ok(v),
+                None => Err(CliError::new(format!("missing value for {}", flag)))
+            }
+        };
+
+        match flag.as_str() {
+            "-s" | "--scene" => {
+                let name = value()?;
+                options.scene = Some(SceneType::from_name(&name).ok_or_else(|| CliError::new(format!(
+                    "unknown scene '{}', expected one of: {}", name, name_list(&SceneType::NAMES))))?);
+            },
+            "-f" | "--scene-file" => options.scene_file = Some(value()?),
+            "-q" | "--quality" => {
+                let name = value()?;
+                options.quality = ImageQuality::from_name(&name).ok_or_else(|| CliError::new(format!(
+                    "unknown quality preset '{}', expected one of: {}", name, name_list(&ImageQuality::NAMES))))?;
+            },
+            "-w" | "--width" => options.image_width = Some(parse_positive_int(&flag, &value()?)?),
+            "-a" | "--aspect" => options.aspect_ratio = Some(parse_aspect(&flag, &value()?)?),
+            "-n" | "--spp" => options.samples_per_pixel = Some(parse_positive_int(&flag, &value()?)?),
+            "-d" | "--max-depth" => options.max_depth = Some(parse_positive_int(&flag, &value()?)?),
+            "--origin" => options.origin = Some(parse_vec3(&flag, &value()?)?),
+            "--target" => options.target = Some(parse_vec3(&flag, &value()?)?),
+            "--fov" => options.fov = Some(parse_f32(&flag, &value()?)?),
+            "--aperture" => options.aperture = Some(parse_f32(&flag, &value()?)?),
+            "--focus-distance" => options.focus_distance = Some(parse_f32(&flag, &value()?)?),
+            "--background" => options.background = Some(parse_vec3(&flag, &value()?)?),
+            "--environment" => options.environment = Some(value()?),
+            "--environment-rotation" => options.environment_rotation = Some(parse_f32(&flag, &value()?)?),
+            "--environment-intensity" => options.environment_intensity = Some(parse_f32(&flag, &value()?)?),
+            "--sky" => {
+                let name = value()?;
+                options.sky = Some(SkyModel::from_name(&name).ok_or_else(|| CliError::new(format!(
+                    "unknown sky model '{}', expected one of: {}", name, name_list(&SkyModel::NAMES))))?);
+            },
+            "--sun" => options.sun = Some(parse_sun(&flag, &value()?)?),
+            "--turbidity" => options.turbidity = Some(parse_f32(&flag, &value()?)?),
+            "--sun-radius" => options.sun_radius = Some(parse_f32(&flag, &value()?)?),
+            "--bvh-leaf-size" => options.bvh_leaf_size = Some(parse_positive_int(&flag, &value()?)?),
+            "--benchmark-bvh" => options.benchmark_bvh = true,
+            "--no-light-sampling" => options.light_sampling = false,
+            "--spectral" => options.spectral = Some(true),
+            "--no-spectral" => options.spectral = Some(false),
+            "--seed" => {
+                let seed = value()?;
+                options.seed = seed.trim().parse().map_err(|_| CliError::new(format!(
+                    "invalid value '{}' for {}: expected a non-negative integer", seed, flag)))?;
+            },
+            "--sampler" => {
+                let name = value()?;
+                options.sampler = SamplerType::from_name(&name).ok_or_else(|| CliError::new(format!(
+                    "unknown sampler '{}', expected one of: {}", name, name_list(&SamplerType::NAMES))))?;
+            },
+            "--exposure" => options.tone_map.exposure = parse_f32(&flag, &value()?)?,
+            "--tonemap" => {
+                let name = value()?;
+                options.tone_map.operator = ToneMapOperator::from_name(&name).ok_or_else(|| CliError::new(format!(
+                    "unknown tone mapping operator '{}', expected one of: {}", name, name_list(&ToneMapOperator::NAMES))))?;
+            },
+            "--linear" => options.tone_map.srgb = false,
+            "-p" | "--progressive" => options.progressive = true,
+            "--pass-spp" => {
+                options.pass_samples = Some(parse_positive_int(&flag, &value()?)?);
+                options.progressive = true;
+            },
+            "--preview-every" => {
+                options.preview_interval = Some(parse_f32(&flag, &value()?)?);
+                options.progressive = true;
+            },
+            "--adaptive" => options.adaptive = true,
+            "--min-spp" => {
+                options.min_samples = Some(parse_positive_int(&flag, &value()?)?);
+                options.adaptive = true;
+            },
+            "--max-spp" => {
+                options.max_samples = Some(parse_positive_int(&flag, &value()?)?);
+                options.adaptive = true;
+            },
+            "--adaptive-threshold" => {
+                options.adaptive_threshold = Some(parse_f32(&flag, &value()?)?);
+                options.adaptive = true;
+            },
+            "--sample-count-image" => options.sample_count_output = Some(value()?),
+            "--aovs" => options.aovs = parse_aovs(&value()?)?,
+            "--denoise" => options.denoise = Some(true),
+            "--no-denoise" => options.denoise = Some(false),
+            "--checkpoint" => options.checkpoint = Some(value()?),
+            "--checkpoint-every" => options.checkpoint_interval = Some(parse_f32(&flag, &value()?)?),
+            "--resume" => options.resume = true,
+            "-i" | "--input" => options.input = Some(value()?),
+            "-o" | "--output" => options.output = value()?,
+            _ => return Err(CliError::new(format!("unknown option '{}'", arg)))
+        }
+    }
+
+    validate(&options)?;
+
+    Ok(Command::Render(Box::new(options)))
+}
+
+/// Checks the values that can be validated without knowing the scene defaults.
+fn validate(options: &RenderOptions) -> Result<(), CliError> {
+    if options.scene.is_some() && options.scene_file.is_some() {
+        return Err(CliError::new("--scene and --scene-file cannot be used together"));
+    }
+
+    if options.input.is_some() && (options.scene.is_some() || options.scene_file.is_some() || options.benchmark_bvh) {
+        return Err(CliError::new("--input only converts an existing image and cannot be combined with --scene, --scene-file or --benchmark-bvh"));
+    }
+
+    if let Some(fov) = options.fov {
+        if fov <= 0.0 || fov >= 180.0 {
+            return Err(CliError::new(format!("--fov must be between 0 and 180 degrees, got {}", fov)));
+        }
+    }
+
+    if let Some(interval) = options.preview_interval {
+        if interval <= 0.0 {
+            return Err(CliError::new(format!("--preview-every must be positive, got {}", interval)));
+        }
+    }
+
+    if let (Some(min_samples), Some(max_samples)) = (options.min_samples, options.max_samples) {
+        if min_samples > max_samples {
+            return Err(CliError::new(format!("--min-spp ({}) must not be larger than --max-spp ({})", min_samples, max_samples)));
+        }
+    }
+
+    if let Some(threshold) = options.adaptive_threshold {
+        if threshold <= 0.0 {
+            return Err(CliError::new(format!("--adaptive-threshold must be positive, got {}", threshold)));
+        }
+    }
+
+    if let Some(path) = &options.sample_count_output {
+        if OutputFormat::from_path(path).is_none() {
+            return Err(CliError::new(format!("cannot determine the image format of '{}' from its extension", path)));
+        }
+    }
+
+    if !options.aovs.is_empty() && (options.input.is_some() || options.benchmark_bvh) {
+        return Err(CliError::new("--aovs cannot be combined with --input or --benchmark-bvh"));
+    }
+
+    if options.checkpoint.is_none() && (options.resume || options.checkpoint_interval.is_some()) {
+        return Err(CliError::new("--resume and --checkpoint-every need a --checkpoint file"));
+    }
+
+    if options.checkpoint.is_some() && (options.input.is_some() || options.benchmark_bvh) {
+        return Err(CliError::new("--checkpoint cannot be combined with --input or --benchmark-bvh"));
+    }
+
+    if let Some(interval) = options.checkpoint_interval {
+        if interval <= 0.0 {
+            return Err(CliError::new(format!("--checkpoint-every must be positive, got {}", interval)));
+        }
+    }
+
+    if let Some(aperture) = options.aperture {
+        if aperture < 0.0 {
+            return Err(CliError::new(format!("--aperture must not be negative, got {}", aperture)));
+        }
+    }
+
+    if let Some(focus_distance) = options.focus_distance {
+        if focus_distance <= 0.0 {
+            return Err(CliError::new(format!("--focus-distance must be positive, got {}", focus_distance)));
+        }
+    }
+
+    if let Some(background) = options.background {
+        if background.min_element() < 0.0 {
+            return Err(CliError::new("--background components must not be negative"));
+        }
+    }
+
+    if let Some(intensity) = options.environment_intensity {
+        if intensity < 0.0 {
+            return Err(CliError::new(format!("--environment-intensity must not be negative, got {}", intensity)));
+        }
+    }
+
+    if options.sky.is_some() && options.environment.is_some() {
+        return Err(CliError::new("--sky and --environment cannot be used together"));
+    }
+
+    if let Some(turbidity) = options.turbidity {
+        if !(2.0..=10.0).contains(&turbidity) {
+            return Err(CliError::new(format!("--turbidity must be between 2 and 10, got {}", turbidity)));
+        }
+    }
+
+    if let Some(radius) = options.sun_radius {
+        if !(radius > 0.0 && radius <= 90.0) {
+            return Err(CliError::new(format!("--sun-radius must be above 0 and at most 90, got {}", radius)));
+        }
+    }
+
+    if OutputFormat::from_path(&options.output).is_none() {
+        return Err(CliError::new(format!("cannot determine the image format of '{}' from its extension", options.output)));
+    }
+
+    Ok(())
+}
+
+/// Checks the final camera and image settings once the scene defaults have
+/// been merged with the command line overrides.
+pub fn validate_view(image_width: u32, image_height: u32, origin: Vec3A, target: Vec3A, up: Vec3A) -> Result<(), CliError> {
+    if image_width < 2 || image_height < 2 {
+        return Err(CliError::new(format!(
+            "the image must be at least 2x2 pixels, got {}x{} (check --width and --aspect)", image_width, image_height)));
+    }
+
+    let forward = target - origin;
+    if forward.length_squared() < f32::EPSILON {
+        return Err(CliError::new("--origin and --target must be different points"));
+    }
+
+    if forward.normalize().cross(up).length_squared() < 1e-8 {
+        return Err(CliError::new("the camera cannot look straight up or down (--origin and --target are vertically aligned)"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, CliError> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn render_options(args: &[&str]) -> RenderOptions {
+        match parse(args) {
+            Ok(Command::Render(options)) => *options,
+            Ok(Command::Help) => panic!("{:?} asked for help", args),
+            Err(error) => panic!("{:?} failed: {}", args, error)
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse(args) {
+            Err(error) => error.message,
+            Ok(_) => panic!("{:?} was accepted", args)
+        }
+    }
+
+    #[test]
+    fn parses_a_command_line() {
+        let options = render_options(&["-s", "cornell-box", "-w", "640", "--aspect=16:9", "-n", "64", "-d", "10",
+            "--origin", "1,2,3", "--target=0,0,-1", "--fov", "40", "--seed", "7", "--sampler", "halton",
+            "--aovs", "albedo,normal,albedo", "-o", "out.exr"]);
+        assert_eq!(options.scene, Some(SceneType::CornellBox));
+        assert_eq!(options.image_width, Some(640));
+        assert_eq!(options.aspect_ratio, Some(16.0 / 9.0));
+        assert_eq!(options.samples_per_pixel, Some(64));
+        assert_eq!(options.max_depth, Some(10));
+        assert_eq!(options.origin, Some(Vec3A::new(1.0, 2.0, 3.0)));
+        assert_eq!(options.target, Some(Vec3A::new(0.0, 0.0, -1.0)));
+        assert_eq!(options.fov, Some(40.0));
+        assert_eq!(options.seed, 7);
+        assert_eq!(options.sampler, SamplerType::Halton);
+        assert_eq!(options.aovs, vec![AovType::Albedo, AovType::Normal]);
+        assert_eq!(options.output, "out.exr");
+
+        // Options that imply their mode
+        assert!(render_options(&["--pass-spp", "8"]).progressive);
+        assert!(render_options(&["--max-spp", "256"]).adaptive);
+        assert!(matches!(parse(&["-w", "640", "--help"]), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn reports_missing_values_and_unknown_options() {
+        assert_eq!(error(&["-w"]), "missing value for -w");
+        assert_eq!(error(&["-s", "final", "--output"]), "missing value for --output");
+        assert_eq!(error(&["--frobnicate"]), "unknown option '--frobnicate'");
+        assert!(error(&["-s", "teapot"]).starts_with("unknown scene 'teapot'"));
+        assert!(error(&["-q", "ultra"]).starts_with("unknown quality preset 'ultra'"));
+        assert!(error(&["--sampler", "magic"]).starts_with("unknown sampler 'magic'"));
+        assert!(error(&["--tonemap", "magic"]).starts_with("unknown tone mapping operator 'magic'"));
+        assert!(error(&["--sky", "cloudy"]).starts_with("unknown sky model 'cloudy'"));
+        assert!(error(&["--aovs", "albedo,colour"]).starts_with("unknown AOV 'colour'"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for args in [["-w", "0"], ["-w", "-5"], ["-w", "wide"], ["-n", "1.5"], ["--seed", "-1"], ["--fov", "NaN"]].iter() {
+            assert!(error(args).starts_with(&format!("invalid value '{}' for {}", args[1], args[0])), "{:?}", args);
+        }
+        assert!(error(&["--origin", "1,2"]).contains("expected three comma separated numbers"));
+        assert!(error(&["--aspect", "16:0"]).contains("the aspect ratio must be positive"));
+        assert!(error(&["--sun", "95,0"]).contains("the elevation must be between -90 and 90"));
+        assert!(error(&["--fov", "180"]).starts_with("--fov must be between 0 and 180 degrees"));
+        assert!(error(&["--turbidity", "11"]).starts_with("--turbidity must be between 2 and 10"));
+        assert!(error(&["--aperture", "-1"]).starts_with("--aperture must not be negative"));
+        assert!(error(&["--background", "1,-1,1"]).starts_with("--background components must not be negative"));
+        assert!(error(&["-o", "out.xyz"]).starts_with("cannot determine the image format of 'out.xyz'"));
+    }
+
+    #[test]
+    fn rejects_conflicting_options() {
+        assert_eq!(error(&["-s", "final", "-f", "scene.toml"]), "--scene and --scene-file cannot be used together");
+        assert!(error(&["-i", "in.exr", "-s", "final"]).starts_with("--input only converts an existing image"));
+        assert_eq!(error(&["--sky", "preetham", "--environment", "sky.hdr"]), "--sky and --environment cannot be used together");
+        assert_eq!(error(&["--resume"]), "--resume and --checkpoint-every need a --checkpoint file");
+        assert_eq!(error(&["--checkpoint", "render.ckpt", "--benchmark-bvh"]), "--checkpoint cannot be combined with --input or --benchmark-bvh");
+        assert_eq!(error(&["--aovs", "all", "-i", "in.exr"]), "--aovs cannot be combined with --input or --benchmark-bvh");
+        assert_eq!(error(&["--min-spp", "32", "--max-spp", "16"]), "--min-spp (32) must not be larger than --max-spp (16)");
+    }
+
+    #[test]
+    fn rejects_degenerate_views() {
+        assert!(validate_view(640, 480, Vec3A::ZERO, -Vec3A::Z, Vec3A::Y).is_ok());
+        assert!(validate_view(640, 1, Vec3A::ZERO, -Vec3A::Z, Vec3A::Y).unwrap_err().message.starts_with("the image must be at least 2x2 pixels"));
+        assert_eq!(validate_view(640, 480, Vec3A::ONE, Vec3A::ONE, Vec3A::Y).unwrap_err().message, "--origin and --target must be different points");
+        assert!(validate_view(640, 480, Vec3A::ZERO, Vec3A::Y, Vec3A::Y).unwrap_err().message.starts_with("the camera cannot look straight up or down"));
+    }
+}
@@ -0,0 +1,190 @@
+//! Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010) with the
+//! variance guided luminance weights of SVGF (Schied et al. 2017).
+//!
+//! Directly visible emission is taken out of the beauty image and the rest
+//! is divided by the albedo so lights and textures are not blurred. It is
+//! then filtered with a 5x5 B3 spline kernel whose taps spread further apart
+//! every iteration, multiplied by the albedo again and the emission added
+//! back. Taps across normal, albedo or luminance edges get small weights; the
+//! luminance tolerance follows the noise of the pixel, so converged pixels
+//! are left nearly alone.
+
+extern crate glam;
+
+use glam::*;
+use rayon::prelude::*;
+
+use crate::framebuffer::Framebuffer;
+use crate::tonemap::luminance;
+
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Albedo below this isn't divided out, it would only amplify the noise.
+const MIN_ALBEDO: f32 = 0.01;
+
+/// The buffers that tell the filter where the edges are. Missing guides are
+/// left out of the weights, without a variance buffer it is estimated from
+/// the neighbourhood of every pixel.
+#[derive(Copy, Clone, Default)]
+pub struct DenoiseGuides<'a> {
+    pub albedo: Option<&'a Framebuffer>,
+    /// Emission seen directly by the camera, passed through unfiltered
+    pub emission: Option<&'a Framebuffer>,
+    pub normal: Option<&'a Framebuffer>,
+    /// Variance of the mean luminance of every pixel
+    pub variance: Option<&'a [f32]>
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DenoiseSettings {
+    /// Each one doubles the filter radius, five reach 61 pixels across
+    pub iterations: u32,
+    /// Luminance differences of this many standard deviations still get a
+    /// weight of 1/e
+    pub sigma_luminance: f32,
+    /// Exponent on the cosine between two normals
+    pub normal_power: f32,
+    pub sigma_albedo: f32
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> Self {
+        DenoiseSettings {
+            iterations: 5,
+            sigma_luminance: 4.0,
+            normal_power: 16.0,
+            sigma_albedo: 0.3
+        }
+    }
+}
+
+/// The factor `pixel` is divided by before filtering.
+fn demodulation(guides: &DenoiseGuides, pixel: usize) -> Vec3A {
+    match guides.albedo {
+        Some(albedo) => {
+            let a = albedo.pixels[pixel];
+            Vec3A::select(a.cmpge(Vec3A::splat(MIN_ALBEDO)), a, Vec3A::ONE)
+        },
+        None => Vec3A::ONE
+    }
+}
+
+/// The variance of the luminance in the 3x3 neighbourhood of every pixel,
+/// for when the renderer's estimate isn't available.
+fn spatial_variance(width: usize, height: usize, color: &[Vec3A]) -> Vec<f32> {
+    (0..width * height).into_par_iter().map(|pixel| {
+        let (x, y) = ((pixel % width) as i64, (pixel / width) as i64);
+        let mut sum = 0.0;
+        let mut sum_squared = 0.0;
+        let mut count = 0.0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let (qx, qy) = (x + dx, y + dy);
+                if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                    continue;
+                }
+                let l = luminance(color[qy as usize * width + qx as usize]);
+                sum += l;
+                sum_squared += l * l;
+                count += 1.0;
+            }
+        }
+        let mean = sum / count;
+        f32::max(0.0, sum_squared / count - mean * mean)
+    }).collect()
+}
+
+/// The variance blurred with a 3x3 Gaussian, which steadies the luminance
+/// weights.
+fn blur_variance(width: usize, height: usize, variance: &[f32]) -> Vec<f32> {
+    const WEIGHTS: [f32; 3] = [0.25, 0.5, 0.25];
+    (0..width * height).into_par_iter().map(|pixel| {
+        let (x, y) = ((pixel % width) as i64, (pixel / width) as i64);
+        let mut sum = 0.0;
+        let mut total = 0.0;
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let (qx, qy) = (x + dx, y + dy);
+                if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                    continue;
+                }
+                let w = WEIGHTS[(dx + 1) as usize] * WEIGHTS[(dy + 1) as usize];
+                sum += w * variance[qy as usize * width + qx as usize];
+                total += w;
+            }
+        }
+        sum / total
+    }).collect()
+}
+
+/// Returns a filtered copy of `beauty`, which holds linear radiance.
+pub fn denoise(beauty: &Framebuffer, guides: &DenoiseGuides, settings: &DenoiseSettings) -> Framebuffer {
+    let width = beauty.width as usize;
+    let height = beauty.height as usize;
+
+    let emission = |pixel: usize| guides.emission.map_or(Vec3A::ZERO, |e| e.pixels[pixel]);
+    let modulation: Vec<Vec3A> = (0..width * height).map(|i| demodulation(guides, i)).collect();
+    let mut color: Vec<Vec3A> = (0..width * height)
+        .map(|i| (beauty.pixels[i] - emission(i)).max(Vec3A::ZERO) / modulation[i])
+        .collect();
+    let mut variance: Vec<f32> = match guides.variance {
+        Some(variance) => variance.iter().zip(modulation.iter())
+            .map(|(v, m)| *v / luminance(*m).powi(2))
+            .collect(),
+        None => spatial_variance(width, height, &color)
+    };
+
+    for iteration in 0..settings.iterations {
+        let step = 1_i64 << iteration;
+        let blurred = blur_variance(width, height, &variance);
+
+        let (next_color, next_variance): (Vec<Vec3A>, Vec<f32>) = (0..width * height).into_par_iter().map(|p| {
+            let (x, y) = ((p % width) as i64, (p / width) as i64);
+            let luminance_p = luminance(color[p]);
+            let tolerance = settings.sigma_luminance * blurred[p].max(0.0).sqrt() + 1e-6;
+
+            let mut color_sum = Vec3A::ZERO;
+            let mut variance_sum = 0.0;
+            let mut weight_sum = 0.0;
+            for (j, ky) in KERNEL.iter().enumerate() {
+                for (i, kx) in KERNEL.iter().enumerate() {
+                    let qx = x + (i as i64 - 2) * step;
+                    let qy = y + (j as i64 - 2) * step;
+                    if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                        continue;
+                    }
+                    let q = qy as usize * width + qx as usize;
+
+                    let mut weight = kx * ky * f32::exp(-f32::abs(luminance_p - luminance(color[q])) / tolerance);
+                    if let Some(normal) = guides.normal {
+                        let (np, nq) = (normal.pixels[p], normal.pixels[q]);
+                        // Pixels where nothing was hit have no normal
+                        if np != Vec3A::ZERO || nq != Vec3A::ZERO {
+                            weight *= f32::max(0.0, np.dot(nq)).powf(settings.normal_power);
+                        }
+                    }
+                    if let Some(albedo) = guides.albedo {
+                        let difference = (albedo.pixels[p] - albedo.pixels[q]).length_squared();
+                        weight *= f32::exp(-difference / (settings.sigma_albedo * settings.sigma_albedo));
+                    }
+
+                    color_sum += weight * color[q];
+                    variance_sum += weight * weight * variance[q];
+                    weight_sum += weight;
+                }
+            }
+
+            // The centre tap always has weight, unless its own guides are broken
+            if weight_sum <= 0.0 || !weight_sum.is_finite() {
+                return (color[p], variance[p]);
+            }
+            (color_sum / weight_sum, variance_sum / (weight_sum * weight_sum))
+        }).unzip();
+
+        color = next_color;
+        variance = next_variance;
+    }
+
+    let pixels = (0..width * height).map(|i| color[i] * modulation[i] + emission(i)).collect();
+    Framebuffer::from_pixels(beauty.width, beauty.height, pixels)
+}
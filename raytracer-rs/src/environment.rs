@@ -0,0 +1,623 @@
+//! Light arriving from infinitely far away, seen by every ray that leaves
+//! the scene.
+
+extern crate glam;
+
+use glam::*;
+
+use crate::framebuffer::{Framebuffer, FramebufferError};
+use crate::tonemap::luminance;
+use crate::vec3_helpers;
+use crate::spectrum::xyz_to_rgb;
+
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Directions are unit vectors pointing away from the scene.
+pub trait Environment: Send + Sync {
+    fn radiance(&self, direction: Vec3A) -> Vec3A;
+
+    /// Whether the integrator should sample the environment like a light,
+    /// which needs `pdf` and `sample_direction`.
+    fn is_light(&self) -> bool {
+        false
+    }
+
+    /// Solid angle density with which `sample_direction` picks `direction`.
+    fn pdf(&self, _direction: Vec3A) -> f32 {
+        0.0
+    }
+
+    /// A direction picked by the uniform sample `u`, preferring the bright
+    /// parts of the environment.
+    fn sample_direction(&self, _u: Vec2) -> Vec3A {
+        Vec3A::Y
+    }
+}
+
+/// The same radiance from every direction.
+pub struct ConstantEnvironment {
+    pub color: Vec3A
+}
+
+impl ConstantEnvironment {
+    pub fn new(color: Vec3A) -> Self {
+        ConstantEnvironment {
+            color
+        }
+    }
+}
+
+impl Environment for ConstantEnvironment {
+    fn radiance(&self, _direction: Vec3A) -> Vec3A {
+        self.color
+    }
+}
+
+/// A piecewise constant density over [0, 1) with one piece per entry of
+/// `function`.
+struct Distribution1D {
+    function: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32
+}
+
+impl Distribution1D {
+    fn new(function: Vec<f32>) -> Self {
+        let n = function.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + function[i] / n as f32;
+        }
+        let integral = cdf[n];
+        for (i, value) in cdf.iter_mut().enumerate() {
+            // A black function is sampled uniformly
+            *value = if integral > 0.0 { *value / integral } else { i as f32 / n as f32 };
+        }
+        Distribution1D {
+            function,
+            cdf,
+            integral
+        }
+    }
+
+    /// The density at piece `index`.
+    fn pdf(&self, index: usize) -> f32 {
+        if self.integral > 0.0 { self.function[index] / self.integral } else { 1.0 }
+    }
+
+    /// Maps the uniform `u` to a point in [0, 1), its density and its piece.
+    fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let n = self.function.len();
+        let index = (self.cdf.partition_point(|c| *c <= u) - 1).min(n - 1);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 { (u - self.cdf[index]) / width } else { 0.0 };
+        let x = ((index as f32 + offset) / n as f32).min(1.0 - f32::EPSILON);
+        (x, self.pdf(index), index)
+    }
+}
+
+/// An equirectangular (latitude-longitude) image of the environment. The
+/// top row looks straight up and the centre of the image along -Z, with +X
+/// to its right. Sampled in proportion to the luminance of every pixel
+/// times the solid angle it covers.
+pub struct EnvironmentMap {
+    image: Framebuffer,
+    /// Turns the map around +Y, in radians
+    rotation: f32,
+    intensity: f32,
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D
+}
+
+impl EnvironmentMap {
+    pub fn new(image: Framebuffer, rotation: f32, intensity: f32) -> Self {
+        let width = image.width as usize;
+        let height = image.height as usize;
+
+        let rows: Vec<Distribution1D> = (0..height).map(|y| {
+            // Rows near the poles cover less of the sphere
+            let sin_theta = f32::sin(PI * (y as f32 + 0.5) / height as f32);
+            let function = (0..width).map(|x| luminance(image.pixels[y * width + x]).max(0.0) * sin_theta).collect();
+            Distribution1D::new(function)
+        }).collect();
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral).collect());
+
+        EnvironmentMap {
+            image,
+            rotation,
+            intensity,
+            rows,
+            marginal
+        }
+    }
+
+    /// Image coordinates in [0, 1) of `direction`.
+    fn direction_to_uv(&self, direction: Vec3A) -> Vec2 {
+        let phi = f32::atan2(direction.x, -direction.z) - self.rotation;
+        let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = f32::acos(direction.y.clamp(-1.0, 1.0)) / PI;
+        Vec2::new(u, v)
+    }
+
+    fn uv_to_direction(&self, uv: Vec2) -> Vec3A {
+        let phi = (uv.x - 0.5) * 2.0 * PI + self.rotation;
+        let theta = uv.y * PI;
+        let sin_theta = theta.sin();
+        Vec3A::new(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos())
+    }
+
+    /// Bilinear lookup, wrapping around horizontally.
+    fn lookup(&self, uv: Vec2) -> Vec3A {
+        let width = self.image.width as i64;
+        let height = self.image.height as i64;
+        let x = uv.x * width as f32 - 0.5;
+        let y = uv.y * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let pixel = |px: i64, py: i64| {
+            let px = px.rem_euclid(width);
+            let py = py.clamp(0, height - 1);
+            self.image.pixels[(py * width + px) as usize]
+        };
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1, y0) * fx;
+        let bottom = pixel(x0, y0 + 1) * (1.0 - fx) + pixel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, direction: Vec3A) -> Vec3A {
+        self.intensity * self.lookup(self.direction_to_uv(direction))
+    }
+
+    fn is_light(&self) -> bool {
+        self.marginal.integral > 0.0
+    }
+
+    fn pdf(&self, direction: Vec3A) -> f32 {
+        let direction = direction.normalize();
+        let uv = self.direction_to_uv(direction);
+        // Not sin(pi v): next to the poles y rounds to one before v does
+        let sin_theta = Vec2::new(direction.x, direction.z).length();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let row = ((uv.y * self.rows.len() as f32) as usize).min(self.rows.len() - 1);
+        let column = ((uv.x * self.image.width as f32) as usize).min(self.image.width as usize - 1);
+        let pdf_uv = self.marginal.pdf(row) * self.rows[row].pdf(column);
+        // The image covers 2 pi by pi radians
+        pdf_uv / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn sample_direction(&self, u: Vec2) -> Vec3A {
+        let (v, _, row) = self.marginal.sample(u.y);
+        let (u, _, _) = self.rows[row].sample(u.x);
+        self.uv_to_direction(Vec2::new(u, v))
+    }
+}
+
+/// An environment map and how it is placed, kept separately so it can be
+/// overridden from the command line before it is loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvironmentSettings {
+    pub path: PathBuf,
+    /// Radians around +Y
+    pub rotation: f32,
+    pub intensity: f32
+}
+
+impl EnvironmentSettings {
+    pub fn new(path: PathBuf) -> Self {
+        EnvironmentSettings {
+            path,
+            rotation: 0.0,
+            intensity: 1.0
+        }
+    }
+
+    /// Reads the image, which may be anything `Framebuffer::load` accepts
+    /// (Radiance .hdr, OpenEXR or PFM).
+    pub fn load(&self) -> Result<EnvironmentMap, FramebufferError> {
+        let image = Framebuffer::load(&self.path)?;
+        Ok(EnvironmentMap::new(image, self.rotation, self.intensity))
+    }
+}
+
+/// The RTIOW background: a blend from `bottom` straight down to `top`
+/// straight up.
+pub struct GradientSky {
+    pub bottom: Vec3A,
+    pub top: Vec3A
+}
+
+impl GradientSky {
+    pub fn new(bottom: Vec3A, top: Vec3A) -> Self {
+        GradientSky {
+            bottom,
+            top
+        }
+    }
+}
+
+impl Environment for GradientSky {
+    fn radiance(&self, direction: Vec3A) -> Vec3A {
+        let t = 0.5 * (direction.y + 1.0);
+        self.bottom.lerp(self.top, t)
+    }
+}
+
+/// Converts the kcd/m² of the Preetham model to radiance. A clear midday sky
+/// and sun light a white diffuse surface to about 1.
+const SKY_SCALE: f32 = 0.05;
+
+/// Illuminance of the sun above the atmosphere, in klx.
+const SOLAR_ILLUMINANCE: f32 = 128.0;
+
+/// Coefficients A to E of the Perez distribution for Y, x and y at
+/// turbidity `t`.
+fn perez_coefficients(t: f32) -> [[f32; 5]; 3] {
+    [
+        [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+        [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+        [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529]
+    ]
+}
+
+/// Relative sky brightness at zenith angle `cos_theta` and angle `gamma`
+/// from the sun.
+fn perez(c: &[f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * f32::exp(c[1] / cos_theta)) * (1.0 + c[2] * f32::exp(c[3] * gamma) + c[4] * cos_gamma * cos_gamma)
+}
+
+/// Fraction of the sun's light at `wavelength` (in micrometres) that gets
+/// through Rayleigh and aerosol scattering at `optical_mass` (Preetham et
+/// al., appendix; ozone and water absorption are left out).
+fn sun_transmittance(wavelength: f32, optical_mass: f32, turbidity: f32) -> f32 {
+    let rayleigh = f32::exp(-0.008735 * wavelength.powf(-4.08) * optical_mass);
+    let beta = 0.04608 * turbidity - 0.04586;
+    let aerosol = f32::exp(-beta * wavelength.powf(-1.3) * optical_mass);
+    rayleigh * aerosol
+}
+
+/// The analytic daylight sky of Preetham, Shirley and Smits (1999) with a
+/// sun disk. Sampled by picking the sun or a uniform direction, weighted by
+/// how much light each gives.
+pub struct PreethamSky {
+    sun_direction: Vec3A,
+    /// 1 - cos of the sun's angular radius
+    sun_cap: f32,
+    /// Squared distance from `sun_direction` to the edge of the disk
+    sun_chord_squared: f32,
+    sun_radiance: Vec3A,
+    /// Y, x and y at the zenith divided by the Perez distribution there
+    zenith: Vec3A,
+    coefficients: [[f32; 5]; 3],
+    intensity: f32,
+    /// Chance that `sample_direction` aims at the sun
+    sun_probability: f32
+}
+
+impl PreethamSky {
+    /// `sun_direction` is a unit vector, `sun_radius` an angle in radians
+    /// and `turbidity` from 2 (very clear) to 10 (hazy).
+    pub fn new(sun_direction: Vec3A, turbidity: f32, sun_radius: f32, intensity: f32) -> Self {
+        // The model only describes the sky for the sun above the horizon
+        let cos_sun_theta = sun_direction.y.clamp(0.0, 1.0);
+        let theta_s = cos_sun_theta.acos();
+        let t = turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = Vec4::new(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s, 1.0);
+        let zenith_x = t * t * Vec4::new(0.00166, -0.00375, 0.00209, 0.0).dot(theta)
+            + t * Vec4::new(-0.02903, 0.06377, -0.03202, 0.00394).dot(theta)
+            + Vec4::new(0.11693, -0.21196, 0.06052, 0.25886).dot(theta);
+        let zenith_y = t * t * Vec4::new(0.00275, -0.00610, 0.00317, 0.0).dot(theta)
+            + t * Vec4::new(-0.04214, 0.08970, -0.04153, 0.00516).dot(theta)
+            + Vec4::new(0.15346, -0.26756, 0.06670, 0.26688).dot(theta);
+
+        let coefficients = perez_coefficients(t);
+        let zenith = Vec3A::new(
+            zenith_luminance.max(0.0) / perez(&coefficients[0], 1.0, theta_s),
+            zenith_x / perez(&coefficients[1], 1.0, theta_s),
+            zenith_y / perez(&coefficients[2], 1.0, theta_s)
+        );
+
+        let half_radius = f32::sin(0.5 * sun_radius);
+        let sun_cap = 2.0 * half_radius * half_radius;
+        let sun_radiance = if sun_direction.y > 0.0 {
+            let theta_degrees = theta_s.to_degrees();
+            let optical_mass = 1.0 / (cos_sun_theta + 0.15 * (93.885 - theta_degrees).powf(-1.253));
+            let transmittance = Vec3A::new(
+                sun_transmittance(0.65, optical_mass, t),
+                sun_transmittance(0.55, optical_mass, t),
+                sun_transmittance(0.45, optical_mass, t)
+            );
+            // The same light spread over the disk, however large it is
+            transmittance * SOLAR_ILLUMINANCE * SKY_SCALE / (2.0 * PI * sun_cap)
+        } else {
+            Vec3A::ZERO
+        };
+
+        let mut sky = PreethamSky {
+            sun_direction,
+            sun_cap,
+            sun_chord_squared: 4.0 * half_radius * half_radius,
+            sun_radiance,
+            zenith,
+            coefficients,
+            intensity,
+            sun_probability: 0.0
+        };
+        if sun_radiance != Vec3A::ZERO {
+            let sun_power = luminance(sun_radiance) * 2.0 * PI * sun_cap;
+            let sky_power = luminance(sky.sky_radiance(Vec3A::Y)) * PI;
+            sky.sun_probability = (sun_power / (sun_power + sky_power)).clamp(0.1, 0.9);
+        }
+        sky
+    }
+
+    fn in_sun(&self, direction: Vec3A) -> bool {
+        (direction - self.sun_direction).length_squared() <= self.sun_chord_squared
+    }
+
+    /// The sky without the sun. Below the horizon it keeps the colour of the
+    /// horizon.
+    fn sky_radiance(&self, direction: Vec3A) -> Vec3A {
+        let cos_theta = direction.y.max(0.0);
+        let gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+        let luminance = self.zenith.x * perez(&self.coefficients[0], cos_theta, gamma);
+        let x = self.zenith.y * perez(&self.coefficients[1], cos_theta, gamma);
+        let y = self.zenith.z * perez(&self.coefficients[2], cos_theta, gamma);
+        if y <= 0.0 {
+            return Vec3A::ZERO;
+        }
+        let xyz = Vec3A::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        xyz_to_rgb(xyz).max(Vec3A::ZERO) * SKY_SCALE
+    }
+}
+
+impl Environment for PreethamSky {
+    fn radiance(&self, direction: Vec3A) -> Vec3A {
+        let mut radiance = self.sky_radiance(direction);
+        if self.in_sun(direction) {
+            radiance += self.sun_radiance;
+        }
+        self.intensity * radiance
+    }
+
+    fn is_light(&self) -> bool {
+        self.intensity > 0.0
+    }
+
+    fn pdf(&self, direction: Vec3A) -> f32 {
+        let direction = direction.normalize();
+        let mut pdf = (1.0 - self.sun_probability) / (4.0 * PI);
+        if self.sun_probability > 0.0 && self.in_sun(direction) {
+            pdf += self.sun_probability / (2.0 * PI * self.sun_cap);
+        }
+        pdf
+    }
+
+    fn sample_direction(&self, u: Vec2) -> Vec3A {
+        if u.x < self.sun_probability {
+            let u = Vec2::new(u.x / self.sun_probability, u.y);
+            return vec3_helpers::sample_cone(self.sun_direction, 1.0 - self.sun_cap, u);
+        }
+        let u = Vec2::new((u.x - self.sun_probability) / (1.0 - self.sun_probability), u.y);
+        vec3_helpers::sample_sphere(u)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SkyModel {
+    Gradient,
+    Preetham
+}
+
+impl SkyModel {
+    pub const NAMES: [(&'static str, SkyModel); 2] = [
+        ("gradient", SkyModel::Gradient),
+        ("preetham", SkyModel::Preetham),
+    ];
+
+    pub fn from_name(name: &str) -> Option<SkyModel> {
+        SkyModel::NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, m)| *m)
+    }
+
+    pub fn name(&self) -> &'static str {
+        SkyModel::NAMES.iter().find(|(_, m)| m == self).map(|(n, _)| *n).unwrap()
+    }
+}
+
+/// A procedural sky, built once every override is applied. The gradient
+/// only uses `bottom`, `top` and `intensity`, the Preetham sky everything
+/// else.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SkySettings {
+    pub model: SkyModel,
+    /// Radians above the horizon
+    pub sun_elevation: f32,
+    /// Radians around +Y from -Z towards +X, like environment map rotations
+    pub sun_azimuth: f32,
+    pub turbidity: f32,
+    /// Angular radius of the sun's disk in radians
+    pub sun_radius: f32,
+    pub intensity: f32,
+    pub bottom: Vec3A,
+    pub top: Vec3A
+}
+
+impl SkySettings {
+    pub fn new(model: SkyModel) -> Self {
+        SkySettings {
+            model,
+            sun_elevation: 45_f32.to_radians(),
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+            sun_radius: 0.27_f32.to_radians(),
+            intensity: 1.0,
+            bottom: Vec3A::ONE,
+            top: Vec3A::new(0.5, 0.7, 1.0)
+        }
+    }
+
+    /// Checks the ranges the sky models can handle.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(2.0..=10.0).contains(&self.turbidity) {
+            return Err(format!("sky turbidity must be between 2 and 10, got {}", self.turbidity));
+        }
+        if !(self.sun_radius > 0.0 && self.sun_radius <= PI / 2.0) {
+            return Err(format!("sun radius must be above 0 and at most 90 degrees, got {}", self.sun_radius.to_degrees()));
+        }
+        if self.sun_elevation.abs() > PI / 2.0 || !self.sun_azimuth.is_finite() {
+            return Err(String::from("sun elevation must be between -90 and 90 degrees"));
+        }
+        if !(self.intensity >= 0.0 && self.intensity.is_finite()) {
+            return Err(String::from("sky intensity must not be negative"));
+        }
+        if self.bottom.min_element() < 0.0 || self.top.min_element() < 0.0 {
+            return Err(String::from("sky colours must not be negative"));
+        }
+        Ok(())
+    }
+
+    pub fn sun_direction(&self) -> Vec3A {
+        let (sin_elevation, cos_elevation) = self.sun_elevation.sin_cos();
+        Vec3A::new(cos_elevation * self.sun_azimuth.sin(), sin_elevation, -cos_elevation * self.sun_azimuth.cos())
+    }
+
+    pub fn build(&self) -> Arc<dyn Environment> {
+        match self.model {
+            SkyModel::Gradient => Arc::new(GradientSky::new(self.intensity * self.bottom, self.intensity * self.top)),
+            SkyModel::Preetham => Arc::new(PreethamSky::new(self.sun_direction(), self.turbidity, self.sun_radius, self.intensity))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerType;
+
+    /// Half uniform over the sphere and half uniform inside the cone of
+    /// directions within `cos_theta_max` of `axis`, so small bright spots
+    /// are probed too, and the density of the direction.
+    fn sample_probe(axis: Vec3A, cos_theta_max: f32, u: Vec2, choice: f32) -> (Vec3A, f32) {
+        let direction = if choice < 0.5 {
+            vec3_helpers::sample_sphere(u)
+        } else {
+            vec3_helpers::sample_cone(axis, cos_theta_max, u)
+        };
+        let cone_pdf = if direction.dot(axis) >= cos_theta_max { 1.0 / (2.0 * PI * (1.0 - cos_theta_max)) } else { 0.0 };
+        (direction, 0.5 / (4.0 * PI) + 0.5 * cone_pdf)
+    }
+
+    /// `pdf` over the whole sphere of directions must be one.
+    fn assert_pdf_integrates_to_one(environment: &dyn Environment, axis: Vec3A, cos_theta_max: f32) {
+        let count = 100000;
+        // A low discrepancy sequence keeps the estimate's noise down
+        let mut probe = SamplerType::Halton.create(2, count);
+        let mut integral = 0.0;
+        for i in 0..count {
+            probe.start_sample(0, i);
+            let (direction, probe_pdf) = sample_probe(axis, cos_theta_max, probe.get_2d(), probe.get_1d());
+            integral += environment.pdf(direction) / probe_pdf;
+        }
+        integral /= count as f32;
+        assert!((integral - 1.0).abs() <= 0.02, "pdf integrates to {}", integral);
+    }
+
+    /// Directions from `sample_direction` must have the density `pdf`
+    /// reports: weighted by one over it, the samples landing in a cone must
+    /// add up to the cone's solid angle.
+    fn assert_samples_follow_pdf(environment: &dyn Environment, cones: &[(Vec3A, f32)]) {
+        let count = 100000;
+        let mut sampler = SamplerType::Halton.create(3, count);
+        let mut estimates = vec![0.0; cones.len()];
+        for i in 0..count {
+            sampler.start_sample(0, i);
+            let direction = environment.sample_direction(sampler.get_2d());
+            assert!((direction.length() - 1.0).abs() < 1e-3, "{:?} is not a unit vector", direction);
+            let pdf = environment.pdf(direction);
+            assert!(pdf > 0.0, "sampled {:?} with zero density", direction);
+            for (estimate, (axis, cos_theta_max)) in estimates.iter_mut().zip(cones) {
+                if direction.dot(*axis) >= *cos_theta_max {
+                    *estimate += 1.0 / pdf;
+                }
+            }
+        }
+        for (estimate, (axis, cos_theta_max)) in estimates.iter().zip(cones) {
+            let estimate = estimate / count as f32;
+            let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+            assert!((estimate - solid_angle).abs() <= 0.03 * solid_angle,
+                "cone around {:?} covers {} but the samples add up to {}", axis, solid_angle, estimate);
+        }
+    }
+
+    /// Wide cones around the axes, plus a narrow one around `axis`.
+    fn probe_cones(axis: Vec3A, cos_theta_max: f32) -> Vec<(Vec3A, f32)> {
+        vec![
+            (Vec3A::X, 0.5), (-Vec3A::X, 0.5),
+            (Vec3A::Y, 0.5), (-Vec3A::Y, 0.5),
+            (Vec3A::Z, 0.5), (-Vec3A::Z, 0.5),
+            (axis, cos_theta_max)
+        ]
+    }
+
+    /// A map with some variation everywhere and one very bright pixel,
+    /// turned so the seam isn't along -Z.
+    fn test_map() -> (EnvironmentMap, Vec3A) {
+        let mut image = Framebuffer::new(16, 8);
+        for y in 0..8 {
+            for x in 0..16 {
+                image.pixels[y * 16 + x] = Vec3A::new(0.2, 0.1, 0.05) * (1 + (x * 7 + y * 3) % 11) as f32;
+            }
+        }
+        image.pixels[2 * 16 + 11] = Vec3A::splat(500.0);
+        let map = EnvironmentMap::new(image, 0.7, 2.0);
+        let bright = map.uv_to_direction(Vec2::new(11.5 / 16.0, 2.5 / 8.0));
+        (map, bright)
+    }
+
+    #[test]
+    fn environment_map_pdf_integrates_to_one() {
+        let (map, bright) = test_map();
+        assert!(map.is_light());
+        assert_pdf_integrates_to_one(&map, bright, 0.95);
+    }
+
+    #[test]
+    fn environment_map_samples_follow_its_pdf() {
+        let (map, bright) = test_map();
+        assert_samples_follow_pdf(&map, &probe_cones(bright, 0.98));
+    }
+
+    #[test]
+    fn sky_pdf_integrates_to_one() {
+        let sun = Vec3A::new(0.3, 0.5, -0.6).normalize();
+        for sun_radius in [0.27_f32.to_radians(), 0.1].iter() {
+            let sky = PreethamSky::new(sun, 3.0, *sun_radius, 1.0);
+            assert!(sky.sun_probability > 0.0);
+            assert_pdf_integrates_to_one(&sky, sun, 1.0 - sky.sun_cap);
+        }
+
+        // Without the sun only the uniform part is left
+        let set = PreethamSky::new(Vec3A::new(0.0, -0.2, -1.0).normalize(), 3.0, 0.1, 1.0);
+        assert_eq!(set.sun_probability, 0.0);
+        assert_pdf_integrates_to_one(&set, Vec3A::Y, 0.5);
+    }
+
+    #[test]
+    fn sky_samples_follow_its_pdf() {
+        let sun = Vec3A::new(0.3, 0.5, -0.6).normalize();
+        let sky = PreethamSky::new(sun, 3.0, 0.1, 1.0);
+        // A cone twice the size of the sun, half of it in the sun
+        assert_samples_follow_pdf(&sky, &probe_cones(sun, 1.0 - 2.0 * sky.sun_cap));
+    }
+}
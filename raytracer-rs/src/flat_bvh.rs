@@ -0,0 +1,233 @@
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::shapes::Hittable;
+use crate::scene::Scene;
+use crate::hit_record::HitRecord;
+use crate::sampler::Sampler;
+use crate::materials::Material;
+use crate::bvh_node::{BVHNode, BVHContents, BVHBuildOptions, BVHStats};
+
+use std::sync::Arc;
+
+/// Deep enough for any tree the SAH builder produces on real scenes; deeper
+/// trees fall back to a growable stack.
+const STACK_SIZE: usize = 64;
+
+#[derive(Copy, Clone)]
+struct LinearNode {
+    bounds: AABB,
+    /// Leaves: index of the first object. Interior nodes: index of the second
+    /// child, the first child always directly follows its parent.
+    offset: u32,
+    /// Number of objects in a leaf, zero for interior nodes
+    count: u32,
+    axis: u32
+}
+
+/// A BVH stored as a flat array of nodes in depth-first order, with the
+/// objects of each leaf stored contiguously. It is built with the same SAH
+/// builder as `BVHNode` and is traversed without recursion, nearest child
+/// first.
+pub struct FlatBVH {
+    nodes: Vec<LinearNode>,
+    objects: Vec<Arc<dyn Hittable>>,
+    stats: BVHStats
+}
+
+impl FlatBVH {
+    pub fn from_scene(scene: &Scene, t0: f32, t1: f32) -> FlatBVH {
+        FlatBVH::from_vector(scene.shapes[0..].to_vec(), t0, t1)
+    }
+
+    pub fn from_scene_with_options(scene: &Scene, t0: f32, t1: f32, options: BVHBuildOptions) -> FlatBVH {
+        FlatBVH::from_vector_with_options(scene.shapes[0..].to_vec(), t0, t1, options)
+    }
+
+    pub fn from_vector(objects: Vec<Arc<dyn Hittable>>, t0: f32, t1: f32) -> FlatBVH {
+        FlatBVH::from_vector_with_options(objects, t0, t1, BVHBuildOptions::default())
+    }
+
+    pub fn from_vector_with_options(objects: Vec<Arc<dyn Hittable>>, t0: f32, t1: f32, options: BVHBuildOptions) -> FlatBVH {
+        FlatBVH::from_tree(BVHNode::from_vector_with_options(objects, t0, t1, options))
+    }
+
+    pub fn from_tree(tree: BVHNode) -> FlatBVH {
+        let stats = tree.stats();
+        let mut flat = FlatBVH {
+            nodes: Vec::with_capacity(stats.node_count),
+            objects: Vec::with_capacity(stats.object_count),
+            stats
+        };
+        flat.flatten(tree);
+        flat
+    }
+
+    fn flatten(&mut self, node: BVHNode) {
+        let index = self.nodes.len();
+        self.nodes.push(LinearNode {
+            bounds: node.bounding_box,
+            offset: 0,
+            count: 0,
+            axis: 0
+        });
+
+        match node.contents {
+            BVHContents::Node { left, right, axis } => {
+                self.flatten(*left);
+                self.nodes[index].offset = self.nodes.len() as u32;
+                self.nodes[index].axis = axis as u32;
+                self.flatten(*right);
+            },
+            BVHContents::Leaf { objects } => {
+                self.nodes[index].offset = self.objects.len() as u32;
+                self.nodes[index].count = objects.len() as u32;
+                self.objects.extend(objects);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> BVHStats {
+        self.stats
+    }
+}
+
+impl Hittable for FlatBVH {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_direction = ray.direction.recip();
+        let direction_is_negative = [ray.direction.x < 0.0, ray.direction.y < 0.0, ray.direction.z < 0.0];
+
+        let mut closest_so_far = t_max;
+        let mut result = None;
+
+        let mut stack = [0_u32; STACK_SIZE];
+        let mut overflow = Vec::new();
+        let mut stack_size = 0;
+        let mut current = 0;
+
+        loop {
+            let node = &self.nodes[current];
+
+            // Boxes behind the closest hit are rejected here, which is what
+            // skips the far child once the near one produced a hit
+            if node.bounds.hit_inverse(ray.origin, inv_direction, t_min, closest_so_far) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    for object in self.objects[first..first + node.count as usize].iter() {
+                        if let Some(record) = object.intersect(ray, t_min, closest_so_far, sampler) {
+                            closest_so_far = record.t;
+                            result = Some(record);
+                        }
+                    }
+                } else {
+                    // Visit the child on the near side of the split plane
+                    // first and remember the other one
+                    let (near, far) = if direction_is_negative[node.axis as usize] {
+                        (node.offset as usize, current + 1)
+                    } else {
+                        (current + 1, node.offset as usize)
+                    };
+
+                    if stack_size < STACK_SIZE {
+                        stack[stack_size] = far as u32;
+                        stack_size += 1;
+                    } else {
+                        overflow.push(far);
+                    }
+                    current = near;
+                    continue;
+                }
+            }
+
+            if let Some(next) = overflow.pop() {
+                current = next;
+            } else if stack_size > 0 {
+                stack_size -= 1;
+                current = stack[stack_size] as usize;
+            } else {
+                break;
+            }
+        }
+
+        result
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        for object in self.objects.iter() {
+            object.visit_materials(visit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::LambertianMat;
+    use crate::rng::Pcg32;
+    use crate::sampler::IndependentSampler;
+    use crate::shapes::{Sphere, Triangle};
+    use glam::Vec3A;
+    use rand::Rng;
+
+    fn random_point(rng: &mut Pcg32) -> Vec3A {
+        Vec3A::new(rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0))
+    }
+
+    /// Overlapping spheres and triangles of all sizes.
+    fn random_soup(rng: &mut Pcg32) -> Scene {
+        let mut scene = Scene::new();
+        for _ in 0..150 {
+            let radius = rng.gen_range(0.05..2.0);
+            scene.add_shape(Sphere::new(random_point(rng), radius, LambertianMat::from_color(Vec3A::ONE)));
+
+            let center = random_point(rng);
+            let size = rng.gen_range(0.1..4.0);
+            let corner = |rng: &mut Pcg32| center + size * (random_point(rng) / 10.0);
+            let (p0, p1, p2) = (corner(rng), corner(rng), corner(rng));
+            scene.add_shape(Triangle::new(p0, p1, p2, LambertianMat::from_color(Vec3A::ONE)));
+        }
+        scene
+    }
+
+    #[test]
+    fn bvhs_find_the_same_hits_as_a_linear_scan() {
+        let mut rng = Pcg32::new(3, 0);
+        let scene = random_soup(&mut rng);
+        let mut sampler = IndependentSampler::new(0);
+
+        for options in [BVHBuildOptions::default(), BVHBuildOptions { max_leaf_size: 1, bin_count: 4 }].iter() {
+            let tree = BVHNode::from_scene_with_options(&scene, 0.0, 1.0, *options);
+            let flat = FlatBVH::from_scene_with_options(&scene, 0.0, 1.0, *options);
+
+            let mut hits = 0;
+            for _ in 0..2000 {
+                let origin = 1.5 * random_point(&mut rng);
+                let direction = random_point(&mut rng) - origin;
+                let ray = Ray{ origin, direction, time: 0.0 };
+
+                let expected = scene.intersect(&ray, 0.001, f32::INFINITY, &mut sampler);
+                for (name, found) in [("BVHNode", tree.intersect(&ray, 0.001, f32::INFINITY, &mut sampler)),
+                    ("FlatBVH", flat.intersect(&ray, 0.001, f32::INFINITY, &mut sampler))].iter() {
+                    match (&expected, found) {
+                        (None, None) => {},
+                        (Some(expected), Some(found)) => {
+                            assert_eq!(expected.t, found.t, "{} with {:?}", name, ray.direction);
+                            assert_eq!(expected.object_id, found.object_id, "{} with {:?}", name, ray.direction);
+                        },
+                        _ => panic!("{} disagrees on whether {:?} hits anything", name, ray.direction)
+                    }
+                }
+                hits += expected.is_some() as usize;
+            }
+            // Most rays should hit something or the test says little
+            assert!(hits > 1000, "{} hits", hits);
+        }
+    }
+}
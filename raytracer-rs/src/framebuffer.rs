@@ -0,0 +1,322 @@
+extern crate glam;
+extern crate image;
+
+use glam::*;
+use image::{ImageError, ImageFormat, Rgb};
+use image::codecs::hdr::{HdrDecoder, HdrEncoder};
+
+use crate::tonemap::ToneMapSettings;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// How an image is written, picked from the file extension.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// OpenEXR, 32-bit float RGB
+    Exr,
+    /// Radiance RGBE
+    Hdr,
+    /// Portable float map, 32-bit float RGB
+    Pfm,
+    /// Any 8-bit format the `image` crate can write, values are tone mapped
+    Ldr(ImageFormat)
+}
+
+impl OutputFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<OutputFormat> {
+        let path = path.as_ref();
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "exr" => Some(OutputFormat::Exr),
+            "hdr" => Some(OutputFormat::Hdr),
+            "pfm" => Some(OutputFormat::Pfm),
+            _ => ImageFormat::from_path(path).ok().map(OutputFormat::Ldr)
+        }
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        !matches!(self, OutputFormat::Ldr(_))
+    }
+}
+
+#[derive(Debug)]
+pub enum FramebufferError {
+    UnknownFormat,
+    /// Only the float formats can be read back
+    NotHdr,
+    Malformed(String),
+    Io(io::Error),
+    Image(ImageError),
+    Exr(exr::error::Error)
+}
+
+impl fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FramebufferError::UnknownFormat => write!(f, "cannot determine the image format from the file extension"),
+            FramebufferError::NotHdr => write!(f, "only .exr, .hdr and .pfm images can be loaded"),
+            FramebufferError::Malformed(message) => write!(f, "{}", message),
+            FramebufferError::Io(e) => write!(f, "{}", e),
+            FramebufferError::Image(e) => write!(f, "{}", e),
+            FramebufferError::Exr(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl From<io::Error> for FramebufferError {
+    fn from(e: io::Error) -> Self {
+        FramebufferError::Io(e)
+    }
+}
+
+/// Linear radiance for every pixel, top row first.
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec3A>
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Vec3A::ZERO; (width * height) as usize]
+        }
+    }
+
+    pub fn from_pixels(width: u32, height: u32, pixels: Vec<Vec3A>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize);
+        Framebuffer {
+            width,
+            height,
+            pixels
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Vec3A {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// Writes the buffer in the format matching the extension of `path`.
+    /// EXR, HDR and PFM files keep the linear values, 8-bit formats go
+    /// through `tone_map`.
+    pub fn save<P: AsRef<Path>>(&self, path: P, tone_map: &ToneMapSettings) -> Result<(), FramebufferError> {
+        let path = path.as_ref();
+        match OutputFormat::from_path(path) {
+            Some(OutputFormat::Exr) => self.save_exr(path),
+            Some(OutputFormat::Hdr) => self.save_hdr(path),
+            Some(OutputFormat::Pfm) => self.save_pfm(path),
+            Some(OutputFormat::Ldr(format)) => tone_map.map_to_rgb8(self).save_with_format(path, format).map_err(FramebufferError::Image),
+            None => Err(FramebufferError::UnknownFormat)
+        }
+    }
+
+    /// Reads an image written by `save` in one of the float formats.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Framebuffer, FramebufferError> {
+        let path = path.as_ref();
+        match OutputFormat::from_path(path) {
+            Some(OutputFormat::Exr) => Framebuffer::load_exr(path),
+            Some(OutputFormat::Hdr) => Framebuffer::load_hdr(path),
+            Some(OutputFormat::Pfm) => Framebuffer::load_pfm(path),
+            Some(OutputFormat::Ldr(_)) => Err(FramebufferError::NotHdr),
+            None => Err(FramebufferError::UnknownFormat)
+        }
+    }
+
+    fn load_exr(path: &Path) -> Result<Framebuffer, FramebufferError> {
+        let image = exr::prelude::read_first_rgba_layer_from_file(
+            path,
+            |resolution, _| Framebuffer::new(resolution.width() as u32, resolution.height() as u32),
+            |buffer: &mut Framebuffer, position, (r, g, b, _a): (f32, f32, f32, f32)| {
+                let index = position.y() * buffer.width as usize + position.x();
+                buffer.pixels[index] = Vec3A::new(r, g, b);
+            }
+        ).map_err(FramebufferError::Exr)?;
+        Ok(image.layer_data.channel_data.pixels)
+    }
+
+    fn load_hdr(path: &Path) -> Result<Framebuffer, FramebufferError> {
+        let decoder = HdrDecoder::new(BufReader::new(File::open(path)?)).map_err(FramebufferError::Image)?;
+        let metadata = decoder.metadata();
+        let pixels = decoder.read_image_hdr().map_err(FramebufferError::Image)?
+            .iter()
+            .map(|p| Vec3A::new(p[0], p[1], p[2]))
+            .collect();
+        Ok(Framebuffer::from_pixels(metadata.width, metadata.height, pixels))
+    }
+
+    /// Accepts both colour (`PF`) and greyscale (`Pf`) maps in either byte order.
+    fn load_pfm(path: &Path) -> Result<Framebuffer, FramebufferError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let malformed = || FramebufferError::Malformed(format!("{} is not a valid PFM file", path.display()));
+
+        // The header is three whitespace separated tokens after the magic
+        let mut header = Vec::new();
+        while header.len() < 4 {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(malformed());
+            }
+            header.extend(line.split_whitespace().map(String::from));
+        }
+
+        let channels = match header[0].as_str() {
+            "PF" => 3,
+            "Pf" => 1,
+            _ => return Err(malformed())
+        };
+        let width: u32 = header[1].parse().map_err(|_| malformed())?;
+        let height: u32 = header[2].parse().map_err(|_| malformed())?;
+        let scale: f32 = header[3].parse().map_err(|_| malformed())?;
+
+        let mut data = vec![0_u8; width as usize * height as usize * channels * 4];
+        reader.read_exact(&mut data).map_err(|_| malformed())?;
+
+        let values: Vec<f32> = data.chunks_exact(4)
+            .map(|b| {
+                let bytes = [b[0], b[1], b[2], b[3]];
+                if scale < 0.0 { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) }
+            })
+            .collect();
+
+        let mut buffer = Framebuffer::new(width, height);
+        for y in 0..height as usize {
+            // Rows are stored bottom to top
+            let row = (height as usize - 1 - y) * width as usize;
+            for x in 0..width as usize {
+                let i = (row + x) * channels;
+                buffer.pixels[y * width as usize + x] = if channels == 3 {
+                    Vec3A::new(values[i], values[i + 1], values[i + 2])
+                } else {
+                    Vec3A::splat(values[i])
+                };
+            }
+        }
+        Ok(buffer)
+    }
+
+    fn save_exr(&self, path: &Path) -> Result<(), FramebufferError> {
+        exr::prelude::write_rgb_file(path, self.width as usize, self.height as usize, |x, y| {
+            let pixel = self.get(x as u32, y as u32);
+            (pixel.x, pixel.y, pixel.z)
+        }).map_err(FramebufferError::Exr)
+    }
+
+    fn save_hdr(&self, path: &Path) -> Result<(), FramebufferError> {
+        let pixels: Vec<Rgb<f32>> = self.pixels.iter()
+            // RGBE can't store negative values
+            .map(|p| Rgb([p.x.max(0.0), p.y.max(0.0), p.z.max(0.0)]))
+            .collect();
+        let writer = BufWriter::new(File::create(path)?);
+        HdrEncoder::new(writer).encode(&pixels, self.width as usize, self.height as usize).map_err(FramebufferError::Image)
+    }
+
+    /// PFM stores the rows bottom to top; a negative scale marks little endian.
+    fn save_pfm(&self, path: &Path) -> Result<(), FramebufferError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let pixel = self.get(x, y);
+                for value in [pixel.x, pixel.y, pixel.z].iter() {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("raytracer-rs-{}-{}", std::process::id(), name))
+    }
+
+    /// Every pixel different, so flipped rows or swapped channels show.
+    fn gradient() -> Framebuffer {
+        let (width, height) = (5, 3);
+        let pixels = (0..width * height)
+            .map(|i| Vec3A::new(i as f32 * 0.25, 1.0 / (i + 1) as f32, 100.0 + i as f32))
+            .collect();
+        Framebuffer::from_pixels(width, height, pixels)
+    }
+
+    fn round_trip(name: &str) -> (Framebuffer, Framebuffer) {
+        let path = temp_path(name);
+        let image = gradient();
+        image.save(&path, &ToneMapSettings::default()).unwrap();
+        let loaded = Framebuffer::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        (image, loaded.unwrap())
+    }
+
+    #[test]
+    fn exr_round_trips_exactly() {
+        let (image, loaded) = round_trip("round-trip.exr");
+        assert_eq!((loaded.width, loaded.height), (image.width, image.height));
+        assert_eq!(loaded.pixels, image.pixels);
+    }
+
+    #[test]
+    fn hdr_round_trips_within_rgbe_precision() {
+        let (image, loaded) = round_trip("round-trip.hdr");
+        assert_eq!((loaded.width, loaded.height), (image.width, image.height));
+        for (expected, found) in image.pixels.iter().zip(loaded.pixels.iter()) {
+            // The channels share an exponent, so the error follows the largest
+            let tolerance = expected.max_element() / 128.0;
+            assert!((*expected - *found).abs().max_element() <= tolerance, "{:?} against {:?}", found, expected);
+        }
+    }
+
+    #[test]
+    fn pfm_round_trips_exactly() {
+        let (image, loaded) = round_trip("round-trip.pfm");
+        assert_eq!((loaded.width, loaded.height), (image.width, image.height));
+        assert_eq!(loaded.pixels, image.pixels);
+    }
+
+    #[test]
+    fn pfm_is_little_endian_and_bottom_up() {
+        let path = temp_path("layout.pfm");
+        let image = gradient();
+        image.save(&path, &ToneMapSettings::default()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"PF\n5 3\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], &header[..]);
+        let first = |channel: usize| {
+            let at = header.len() + 4 * channel;
+            f32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        // The first pixel in the file is the bottom left one
+        let bottom_left = image.get(0, image.height - 1);
+        assert_eq!(Vec3A::new(first(0), first(1), first(2)), bottom_left);
+    }
+
+    #[test]
+    fn loads_big_endian_and_greyscale_pfm() {
+        // A positive scale marks big endian values
+        let path = temp_path("big-endian.pfm");
+        let mut bytes = b"Pf\n2 2\n1.0\n".to_vec();
+        for value in [1.0f32, 2.0, 3.0, 4.0].iter() {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        std::fs::write(&path, &bytes).unwrap();
+        let loaded = Framebuffer::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.pixels, vec![Vec3A::splat(3.0), Vec3A::splat(4.0), Vec3A::splat(1.0), Vec3A::splat(2.0)]);
+    }
+}
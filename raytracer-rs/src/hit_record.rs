@@ -10,11 +10,22 @@ use std::sync::Arc;
 #[derive(Clone)]
 pub struct HitRecord {
     pub point: Vec3A,
+    /// The shading normal, facing the ray
     pub normal: Vec3A,
+    /// The normal of the actual surface, facing the ray. Differs from
+    /// `normal` where normals are interpolated or perturbed.
+    pub geometric_normal: Vec3A,
+    /// How the point moves with the texture coordinates u and v (∂p/∂u and
+    /// ∂p/∂v). They lie in the surface but aren't normalised or orthogonal.
+    pub tangent: Vec3A,
+    pub bitangent: Vec3A,
     pub t: f32,
     pub tex_coords: (f32, f32),
     pub material: Arc<dyn Material>,
-    pub front_face: bool
+    pub front_face: bool,
+    /// Which object of the scene was hit, see `Scene::add_shape`. Zero when
+    /// the shape isn't part of a scene.
+    pub object_id: u32
 }
 
 impl HitRecord {
@@ -22,13 +33,18 @@ impl HitRecord {
         HitRecord{
             point: Vec3A::ZERO,
             normal: Vec3A::ZERO,
+            geometric_normal: Vec3A::ZERO,
+            tangent: Vec3A::ZERO,
+            bitangent: Vec3A::ZERO,
             t: f32::INFINITY,
             tex_coords: (0.0, 0.0),
             material: Arc::new(NoMaterial{}),
-            front_face: false
+            front_face: false,
+            object_id: 0
         }
     }
 
+    /// Sets both normals from the outward one of the surface.
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3A) {
         self.front_face = ray.direction.dot(*outward_normal) < 0.0;
         self.normal = {
@@ -39,5 +55,13 @@ impl HitRecord {
                 -*outward_normal
             }
         };
+        self.geometric_normal = self.normal;
+    }
+
+    /// Whether `direction` is on one side of the surface for the shading
+    /// normal and on the other for the geometric one. Light going that way
+    /// would leak through the surface.
+    pub fn crosses_surface(&self, direction: Vec3A) -> bool {
+        direction.dot(self.normal) * direction.dot(self.geometric_normal) < 0.0
     }
 }
\ No newline at end of file
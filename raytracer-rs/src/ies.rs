@@ -0,0 +1,280 @@
+//! Reader for IESNA LM-63 photometric files, which describe how the
+//! intensity of a luminaire varies with direction.
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum IesError {
+    Io(io::Error),
+    Malformed(String)
+}
+
+impl fmt::Display for IesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IesError::Io(e) => write!(f, "{}", e),
+            IesError::Malformed(message) => write!(f, "malformed IES file: {}", message)
+        }
+    }
+}
+
+impl From<io::Error> for IesError {
+    fn from(e: io::Error) -> Self {
+        IesError::Io(e)
+    }
+}
+
+/// Candela values on a grid of vertical angles, measured from the axis of
+/// the light (straight down for most luminaires), and horizontal angles
+/// around it. Scaled so the brightest direction is 1, the light's own
+/// intensity sets the brightness.
+#[derive(Clone, Debug)]
+pub struct IesProfile {
+    /// Radians, increasing
+    vertical: Vec<f32>,
+    /// Radians, increasing
+    horizontal: Vec<f32>,
+    /// One row of `vertical.len()` values for every horizontal angle
+    candela: Vec<f32>
+}
+
+/// A count read from the file, which has to be a whole number that fits a
+/// `usize`. Nothing is allocated up front from it, so a file can't make us
+/// reserve more than it holds.
+fn to_count(value: f32, what: &str) -> Result<usize, IesError> {
+    if value >= 0.0 && value.fract() == 0.0 && value < usize::MAX as f32 {
+        Ok(value as usize)
+    } else {
+        Err(IesError::Malformed(format!("{} is not a valid {} count", value, what)))
+    }
+}
+
+/// The index of the interval of `angles` holding `angle` and how far along
+/// it `angle` lies, or `None` outside the table.
+fn locate(angles: &[f32], angle: f32) -> Option<(usize, f32)> {
+    if angles.len() == 1 {
+        return if angle == angles[0] { Some((0, 0.0)) } else { None };
+    }
+    if angle < angles[0] || angle > angles[angles.len() - 1] {
+        return None;
+    }
+    let index = (angles.partition_point(|a| *a <= angle).max(1) - 1).min(angles.len() - 2);
+    let width = angles[index + 1] - angles[index];
+    let t = if width > 0.0 { (angle - angles[index]) / width } else { 0.0 };
+    Some((index, t))
+}
+
+impl IesProfile {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, IesError> {
+        let source = std::fs::read_to_string(path)?;
+        IesProfile::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, IesError> {
+        let malformed = |message: &str| IesError::Malformed(message.to_string());
+
+        // Keyword lines come first, the numbers follow the TILT line
+        let mut lines = source.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim()[5..].trim().to_string(),
+                Some(_) => continue,
+                None => return Err(malformed("no TILT line"))
+            }
+        };
+        let rest: Vec<&str> = lines.collect();
+        let mut numbers = rest.iter()
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<f32>().map_err(|_| IesError::Malformed(format!("'{}' is not a number", token))));
+        let mut next = move || numbers.next().unwrap_or_else(|| Err(malformed("the file ends early")));
+
+        if tilt == "INCLUDE" {
+            // Lamp to luminaire geometry, then pairs of angles and factors
+            next()?;
+            let pairs = to_count(next()?, "tilt angle")?;
+            for _ in 0..pairs {
+                next()?;
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens = next()?;
+        let multiplier = next()?;
+        let vertical_count = to_count(next()?, "vertical angle")?;
+        let horizontal_count = to_count(next()?, "horizontal angle")?;
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err(malformed("the angle tables are empty"));
+        }
+        // Photometric type, units, width, length, height, ballast factor,
+        // a reserved value and the input watts
+        for _ in 0..8 {
+            next()?;
+        }
+
+        let candela_count = vertical_count.checked_mul(horizontal_count)
+            .ok_or_else(|| malformed("the angle tables are too large"))?;
+
+        let mut vertical = Vec::new();
+        for _ in 0..vertical_count {
+            vertical.push(next()?.to_radians());
+        }
+        let mut horizontal = Vec::new();
+        for _ in 0..horizontal_count {
+            horizontal.push(next()?.to_radians());
+        }
+        if vertical.windows(2).any(|w| w[1] < w[0]) || horizontal.windows(2).any(|w| w[1] < w[0]) {
+            return Err(malformed("the angles must increase"));
+        }
+
+        let mut candela = Vec::new();
+        for _ in 0..candela_count {
+            candela.push((next()? * multiplier).max(0.0));
+        }
+        let largest = candela.iter().copied().fold(0.0, f32::max);
+        if largest <= 0.0 {
+            return Err(malformed("the luminaire gives no light"));
+        }
+        for value in candela.iter_mut() {
+            *value /= largest;
+        }
+
+        Ok(IesProfile {
+            vertical,
+            horizontal,
+            candela
+        })
+    }
+
+    /// Relative intensity at `vertical` radians from the axis and
+    /// `horizontal` radians around it, interpolated between the measured
+    /// angles. Directions outside the measured range give no light.
+    pub fn value(&self, vertical: f32, horizontal: f32) -> f32 {
+        // The last horizontal angle tells which symmetry the file relies on
+        let last = self.horizontal[self.horizontal.len() - 1].to_degrees().round();
+        let mut horizontal = horizontal.rem_euclid(2.0 * PI);
+        if last <= 0.0 {
+            horizontal = 0.0;
+        } else if last <= 90.0 {
+            horizontal = horizontal.min(2.0 * PI - horizontal);
+            horizontal = horizontal.min(PI - horizontal);
+        } else if last <= 180.0 {
+            horizontal = horizontal.min(2.0 * PI - horizontal);
+        }
+
+        let (v, tv) = match locate(&self.vertical, vertical) {
+            Some(found) => found,
+            None => return 0.0
+        };
+        let (h, th) = match locate(&self.horizontal, horizontal) {
+            Some(found) => found,
+            // Full circle tables may stop short of 360 degrees
+            None => (self.horizontal.len() - 1, 0.0)
+        };
+
+        let count = self.vertical.len();
+        let sample = |hi: usize, vi: usize| {
+            let hi = hi.min(self.horizontal.len() - 1);
+            let vi = vi.min(count - 1);
+            self.candela[hi * count + vi]
+        };
+        let row = |hi: usize| sample(hi, v) * (1.0 - tv) + sample(hi, v + 1) * tv;
+        row(h) * (1.0 - th) + row(h + 1) * th
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Quadrant symmetric: horizontal angles from 0 to 90 degrees, with a
+    /// tilt table in the file.
+    const QUADRANT: &str = "IESNA:LM-63-2002
+[TEST] quadrant symmetric
+[MANUFAC] none
+TILT=INCLUDE
+1
+3
+0 45 90
+1.0 0.9 0.8
+1 1000 2.0 3 3 1 1 0.5 0.5 0.0
+1.0 1.0 100
+0 45 90
+0 45 90
+100 50 0
+80 40 0
+60 30 0
+";
+
+    /// Rotationally symmetric: a single horizontal angle.
+    const ROTATIONAL: &str = "IESNA:LM-63-2002
+TILT=NONE
+1 -1 1.0 2 1 1 2 0 0 0
+1.0 1.0 60
+0 90
+0
+400, 100
+";
+
+    fn assert_value(profile: &IesProfile, vertical: f32, horizontal: f32, expected: f32) {
+        let value = profile.value(vertical.to_radians(), horizontal.to_radians());
+        assert!((value - expected).abs() < 1e-5, "{} at ({}, {}) instead of {}", value, vertical, horizontal, expected);
+    }
+
+    fn parse_error(source: &str) -> String {
+        match IesProfile::parse(source) {
+            Err(IesError::Malformed(message)) => message,
+            other => panic!("expected a malformed file, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reads_quadrant_symmetric_tables() {
+        let profile = IesProfile::parse(QUADRANT).unwrap();
+
+        // Scaled so the brightest value is one, interpolated in between
+        assert_value(&profile, 0.0, 0.0, 1.0);
+        assert_value(&profile, 45.0, 0.0, 0.5);
+        assert_value(&profile, 22.5, 0.0, 0.75);
+        assert_value(&profile, 0.0, 45.0, 0.8);
+        assert_value(&profile, 0.0, 67.5, 0.7);
+        assert_value(&profile, 45.0, 90.0, 0.3);
+
+        // The other quadrants mirror the first
+        assert_value(&profile, 0.0, 135.0, 0.8);
+        assert_value(&profile, 0.0, 180.0, 1.0);
+        assert_value(&profile, 45.0, 270.0, 0.3);
+        assert_value(&profile, 0.0, -45.0, 0.8);
+
+        // Nothing beyond the measured vertical angles
+        assert_value(&profile, 120.0, 0.0, 0.0);
+    }
+
+    #[test]
+    fn reads_rotationally_symmetric_tables() {
+        let profile = IesProfile::parse(ROTATIONAL).unwrap();
+        for horizontal in [0.0, 30.0, 200.0, -90.0].iter() {
+            assert_value(&profile, 0.0, *horizontal, 1.0);
+            assert_value(&profile, 45.0, *horizontal, 0.625);
+            assert_value(&profile, 90.0, *horizontal, 0.25);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_counts() {
+        let with_counts = |counts: &str| ROTATIONAL.replace("1.0 2 1 1", &format!("1.0 {} 1", counts));
+
+        // Huge tables the file doesn't hold must not be allocated
+        assert_eq!(parse_error(&with_counts("1000000000 1000000000")), "the file ends early");
+        assert_eq!(parse_error(&with_counts("1e10 1e10")), "the angle tables are too large");
+        assert_eq!(parse_error(&with_counts("1e30 1")), "1000000000000000000000000000000 is not a valid vertical angle count");
+        assert_eq!(parse_error(&with_counts("2.5 1")), "2.5 is not a valid vertical angle count");
+        assert_eq!(parse_error(&with_counts("2 -1")), "-1 is not a valid horizontal angle count");
+        assert_eq!(parse_error(&with_counts("0 1")), "the angle tables are empty");
+        assert_eq!(parse_error(&QUADRANT.replace("1\n3\n", "1\n1e12\n")), "the file ends early");
+    }
+}
@@ -0,0 +1,250 @@
+extern crate glam;
+
+use glam::*;
+
+use crate::shapes::Hittable;
+use crate::sampler::Sampler;
+use crate::environment::Environment;
+use crate::ies::IesProfile;
+use crate::vec3_helpers;
+
+use std::sync::Arc;
+
+/// The light a punctual light sends towards a point.
+pub struct LightIncidence {
+    /// Unit vector from the point towards the light
+    pub direction: Vec3A,
+    /// Distance to the light, infinite for directional lights
+    pub distance: f32,
+    /// Irradiance on a surface facing the light, before occlusion
+    pub irradiance: Vec3A
+}
+
+/// A light no ray can hit, only found by shadow rays from the surfaces it
+/// lights.
+pub trait PunctualLight: Send + Sync {
+    fn illuminate(&self, point: Vec3A) -> LightIncidence;
+}
+
+/// An `IesProfile` turned so its vertical angles are measured from `axis`.
+#[derive(Clone)]
+pub struct LightProfile {
+    pub profile: Arc<IesProfile>,
+    axis: Vec3A,
+    tangent: Vec3A,
+    bitangent: Vec3A
+}
+
+impl LightProfile {
+    pub fn new(profile: Arc<IesProfile>, axis: Vec3A) -> Self {
+        let axis = axis.normalize();
+        let (tangent, bitangent) = vec3_helpers::orthonormal_basis(axis);
+        LightProfile {
+            profile,
+            axis,
+            tangent,
+            bitangent
+        }
+    }
+
+    /// Relative intensity towards the unit vector `direction`.
+    fn value(&self, direction: Vec3A) -> f32 {
+        let vertical = direction.dot(self.axis).clamp(-1.0, 1.0).acos();
+        let horizontal = f32::atan2(direction.dot(self.bitangent), direction.dot(self.tangent));
+        self.profile.value(vertical, horizontal)
+    }
+}
+
+/// Light leaving a single point, the same in every direction unless it has
+/// a profile.
+pub struct PointLight {
+    pub position: Vec3A,
+    /// Radiant intensity, colour times brightness
+    pub intensity: Vec3A,
+    pub profile: Option<LightProfile>
+}
+
+impl PointLight {
+    pub fn new(position: Vec3A, intensity: Vec3A) -> Self {
+        PointLight {
+            position,
+            intensity,
+            profile: None
+        }
+    }
+
+    pub fn with_profile(mut self, profile: LightProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+}
+
+impl PunctualLight for PointLight {
+    fn illuminate(&self, point: Vec3A) -> LightIncidence {
+        let to_light = self.position - point;
+        let distance_squared = to_light.length_squared();
+        let distance = distance_squared.sqrt();
+        let direction = to_light / distance;
+        let scale = self.profile.as_ref().map_or(1.0, |profile| profile.value(-direction));
+        LightIncidence {
+            direction,
+            distance,
+            irradiance: self.intensity * scale / distance_squared
+        }
+    }
+}
+
+/// A point light limited to a cone, fading out smoothly between the inner
+/// and the outer angle. A profile is measured from the cone's axis.
+pub struct SpotLight {
+    pub position: Vec3A,
+    /// Unit vector along the axis of the cone
+    pub direction: Vec3A,
+    pub intensity: Vec3A,
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    pub profile: Option<LightProfile>
+}
+
+impl SpotLight {
+    /// The angles are in radians from the axis.
+    pub fn new(position: Vec3A, direction: Vec3A, intensity: Vec3A, inner_angle: f32, outer_angle: f32) -> Self {
+        SpotLight {
+            position,
+            direction: direction.normalize(),
+            intensity,
+            cos_inner: inner_angle.min(outer_angle).cos(),
+            cos_outer: outer_angle.cos(),
+            profile: None
+        }
+    }
+
+    pub fn with_profile(mut self, profile: Arc<IesProfile>) -> Self {
+        self.profile = Some(LightProfile::new(profile, self.direction));
+        self
+    }
+
+    fn falloff(&self, cos_theta: f32) -> f32 {
+        if cos_theta >= self.cos_inner {
+            return 1.0;
+        }
+        if cos_theta <= self.cos_outer {
+            return 0.0;
+        }
+        let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl PunctualLight for SpotLight {
+    fn illuminate(&self, point: Vec3A) -> LightIncidence {
+        let to_light = self.position - point;
+        let distance_squared = to_light.length_squared();
+        let distance = distance_squared.sqrt();
+        let direction = to_light / distance;
+        let mut scale = self.falloff(-direction.dot(self.direction));
+        if let Some(profile) = &self.profile {
+            scale *= profile.value(-direction);
+        }
+        LightIncidence {
+            direction,
+            distance,
+            irradiance: self.intensity * scale / distance_squared
+        }
+    }
+}
+
+/// Parallel light from infinitely far away, like a sun without a disk.
+pub struct DirectionalLight {
+    /// Unit vector the light travels along
+    pub direction: Vec3A,
+    pub irradiance: Vec3A
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3A, irradiance: Vec3A) -> Self {
+        DirectionalLight {
+            direction: direction.normalize(),
+            irradiance
+        }
+    }
+}
+
+impl PunctualLight for DirectionalLight {
+    fn illuminate(&self, _point: Vec3A) -> LightIncidence {
+        LightIncidence {
+            direction: -self.direction,
+            distance: f32::INFINITY,
+            irradiance: self.irradiance
+        }
+    }
+}
+
+/// The emissive shapes the integrator samples directly, and the environment
+/// if it can be sampled. Each light is picked with equal probability. The
+/// punctual lights are separate: every one of them is evaluated at every
+/// shading point, with or without light sampling.
+pub struct LightList {
+    pub lights: Vec<Arc<dyn Hittable>>,
+    pub environment: Option<Arc<dyn Environment>>,
+    pub punctual: Vec<Arc<dyn PunctualLight>>
+}
+
+impl LightList {
+    pub fn new(lights: Vec<Arc<dyn Hittable>>) -> Self {
+        LightList {
+            lights,
+            environment: None,
+            punctual: Vec::new()
+        }
+    }
+
+    /// Also samples `environment`, unless it isn't a light.
+    pub fn with_environment(lights: Vec<Arc<dyn Hittable>>, environment: Arc<dyn Environment>) -> Self {
+        LightList {
+            lights,
+            environment: if environment.is_light() { Some(environment) } else { None },
+            punctual: Vec::new()
+        }
+    }
+
+    pub fn with_punctual_lights(mut self, punctual: Vec<Arc<dyn PunctualLight>>) -> Self {
+        self.punctual = punctual;
+        self
+    }
+
+    pub fn empty() -> Self {
+        LightList::new(Vec::new())
+    }
+
+    fn len(&self) -> usize {
+        self.lights.len() + self.environment.is_some() as usize
+    }
+
+    /// Whether there is nothing to pick a direction from; says nothing about
+    /// the punctual lights.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Solid angle density with which `random_direction` picks `direction`.
+    pub fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+
+        let mut sum: f32 = self.lights.iter().map(|light| light.pdf_value(origin, direction, sampler)).sum();
+        if let Some(environment) = &self.environment {
+            sum += environment.pdf(direction);
+        }
+        sum / self.len() as f32
+    }
+
+    pub fn random_direction(&self, origin: Vec3A, sampler: &mut dyn Sampler) -> Vec3A {
+        let index = ((sampler.get_1d() * self.len() as f32) as usize).min(self.len() - 1);
+        match self.lights.get(index) {
+            Some(light) => light.random_direction(origin, sampler.get_2d()),
+            None => self.environment.as_ref().unwrap().sample_direction(sampler.get_2d())
+        }
+    }
+}
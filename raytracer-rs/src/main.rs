@@ -14,22 +14,53 @@ mod aabb;
 mod bvh_node;
 mod texture;
 mod perlin;
-
-use std::time::Instant;
+mod cli;
+mod scene_file;
+mod mesh;
+mod flat_bvh;
+mod bench;
+mod lights;
+mod framebuffer;
+mod tonemap;
+mod render;
+mod checkpoint;
+mod rng;
+mod sampler;
+mod aov;
+mod denoise;
+mod environment;
+mod ies;
+mod microfacet;
+mod spectrum;
+
+use std::time::{Duration, Instant};
 use std::sync::Arc;
+use std::path::{Path, PathBuf};
 
-use image::*;
 use glam::*;
-use rayon::prelude::*;
 
-use ray::Ray;
 use scene::Scene;
 use shapes::*;
-use camera::Camera;
+use camera::CameraSettings;
 use rand::prelude::*;
 use materials::*;
-use bvh_node::BVHNode;
+use bvh_node::BVHBuildOptions;
+use flat_bvh::FlatBVH;
+use lights::LightList;
+use framebuffer::{Framebuffer, OutputFormat};
+use tonemap::ToneMapSettings;
+use render::{Accumulator, AdaptiveSettings, Renderer, ProgressiveSettings, Snapshot};
+use checkpoint::RenderConfig;
+use aov::{AovType, Aovs};
+use denoise::{DenoiseGuides, DenoiseSettings};
+use environment::{ConstantEnvironment, Environment, EnvironmentSettings, SkySettings};
+use rng::{Pcg32, SCENE_STREAM};
 use texture::*;
+use cli::{ImageQuality, SceneType};
+use scene_file::ImageSettings;
+
+/// Camera rays per pixel the AOVs are averaged over, at most --spp
+const AOV_SAMPLES: u32 = 16;
 
 
 // fn write_color(color: &Vec3A, samples_per_pixel: f32) -> Rgb {
@@ -41,50 +72,11 @@ use texture::*;
 //      (256.0 * f32::clamp(r,0.0, 0.999)) as u8, (256.0 * f32::clamp(g, 0.0, 0.999)) as u8, (256.0 * f32::clamp(b, 0.0, 0.999)) as u8);
 // }
 
-fn ray_color<T: Hittable + Send>(ray: &Ray, background: Vec3A, world: &T, depth: i32) -> Vec3A {
-    if depth <= 0 {
-        return Vec3A::ZERO;
-    }
-
-    let world_result = world.intersect(ray, 0.005, f32::INFINITY);
-    if let Option::Some(record) = world_result {
-        let mut scattered = Ray{ origin: Vec3A::ZERO, direction: Vec3A::ZERO, time: ray.time };
-        let mut attentuation = Vec3A::ONE;
-        let emitted = record.material.emitted(record.tex_coords, record.point);
-
-        if record.material.scatter(ray, &record, &mut attentuation, &mut scattered) {
-            return emitted + attentuation * ray_color(&scattered, background, world, depth - 1);
-        } else {
-            return emitted;
-        }
-    } else {
-        return background;
-    }
-}
-
-fn simple_ray_color(ray: &Ray, background: Vec3A, world: &dyn Hittable, _depth: i32) -> Vec3A {
-
-    let world_result = world.intersect(ray, 0.005, f32::INFINITY);
-    if let Option::Some(record) = world_result {
-        let mut scattered = Ray{ origin: Vec3A::ZERO, direction: Vec3A::ZERO, time: ray.time };
-        let mut attentuation = Vec3A::ONE;
-        let emitted = record.material.emitted(record.tex_coords, record.point);
-
-        if record.material.scatter(ray, &record, &mut attentuation, &mut scattered) {
-            return attentuation;
-        } else {
-            return emitted;
-        }
-    } else {
-        return background;
-    }
-}
-
 fn degree_to_rad(deg: f32) -> f32 {
     deg * std::f32::consts::PI / 180.0
 }
 
-pub fn generate_random_world() -> Scene {
+pub fn generate_random_world(rng: &mut Pcg32) -> Scene {
     let mut s = Scene::new();
 
     // Ground
@@ -94,8 +86,6 @@ pub fn generate_random_world() -> Scene {
     ));
     s.add_shape(Sphere::new(Vec3A::new(0.0, -1000.0, 0.0), 1000.0, material));
     
-    let mut rng = rand::thread_rng();
-
     let point = Vec3A::new(4.0, 0.2, 0.0);
 
     for a in -11..11 {
@@ -107,9 +97,9 @@ pub fn generate_random_world() -> Scene {
             if (center - point).length() > 0.9 {
                 if choose_mat < 0.8 {
                     let center2 = center + Vec3A::new(0.0, rng.gen_range(0.0..0.5), 0.0);
-                    s.add_shape(MovingSphere::new(center, center2, 0.2, 0.0, 1.0, LambertianMat::from_color(vec3_helpers::random() * vec3_helpers::random())));
+                    s.add_shape(MovingSphere::new(center, center2, 0.2, 0.0, 1.0, LambertianMat::from_color(vec3_helpers::random(rng) * vec3_helpers::random(rng))));
                 } else if choose_mat < 0.95 {
-                    s.add_shape(Sphere::new(center, 0.2, MetalMat::new(vec3_helpers::random_range(0.5,1.0), rng.gen_range(0.5..1.0))));
+                    s.add_shape(Sphere::new(center, 0.2, MetalMat::new(vec3_helpers::random_range(0.5, 1.0, rng), rng.gen_range(0.5..1.0))));
                 } else {
                     s.add_shape(Sphere::new(center, 0.2, DielectricMat::new(1.5)));
                 }
@@ -121,7 +111,7 @@ pub fn generate_random_world() -> Scene {
     s.add_shape(Sphere::new(Vec3A::new(-4.0, 1.0, 0.0), 1.0, LambertianMat::from_color(Vec3A::new(0.4, 0.2, 0.1))));
     s.add_shape(Sphere::new(Vec3A::new( 4.0, 1.0, 0.0), 1.0, MetalMat::new(Vec3A::new(0.7, 0.6, 0.5), 0.0)));
 
-    return s;
+    s
 }
 
 fn two_spheres() -> Scene {
@@ -135,18 +125,18 @@ fn two_spheres() -> Scene {
     s.add_shape(Sphere::new(Vec3A::new(0.0,  10.0, 0.0), 10.0, LambertianMat::from_shared_texture(checkered.clone())));
     s.add_shape(Sphere::new(Vec3A::new(0.0, -10.0, 0.0), 10.0, LambertianMat::from_shared_texture(checkered.clone())));
 
-    return s;
+    s
 }
 
-fn two_perlin_spheres() -> Scene {
+fn two_perlin_spheres(rng: &mut Pcg32) -> Scene {
     let mut s = Scene::new();
 
-    let noise_texture = Arc::new(NoiseTexture::new(4.0));
+    let noise_texture = Arc::new(NoiseTexture::new(4.0, rng));
 
     s.add_shape(Sphere::new(Vec3A::new(0.0, -1000.0, 0.0), 1000.0, LambertianMat::from_shared_texture(noise_texture.clone())));
     s.add_shape(Sphere::new(Vec3A::new(0.0, 2.0, 0.0), 2.0, LambertianMat::from_shared_texture(noise_texture.clone())));
 
-    return s;
+    s
 }
 
 fn earth() -> Scene {
@@ -157,21 +147,21 @@ fn earth() -> Scene {
     
     s.add_shape(Sphere::new(Vec3A::ZERO, 2.0, surface));
 
-    return s;
+    s
 }
 
-fn simple_light() -> Scene {
+fn simple_light(rng: &mut Pcg32) -> Scene {
     let mut s = Scene::new();
 
-    let noise_texture = Arc::new(NoiseTexture::new(4.0));
+    let noise_texture = Arc::new(NoiseTexture::new(4.0, rng));
 
     s.add_shape(Sphere::new(Vec3A::new(0.0, -1000.0, 0.0), 1000.0, LambertianMat::from_shared_texture(noise_texture.clone())));
     s.add_shape(Sphere::new(Vec3A::new(0.0, 2.0, 0.0), 2.0, LambertianMat::from_shared_texture(noise_texture.clone())));
 
-    s.add_shape(XYRect::new(Vec2::new(3.0, 1.0), Vec2::new(5.0, 3.0), -2.0, DiffuseLight::from_color(Vec3A::new(4.0, 4.0, 4.0))));
-    s.add_shape(Sphere::new(Vec3A::new(0.0, 7.0, 0.0), 2.0, DiffuseLight::from_color(Vec3A::new(4.0, 4.0, 4.0))));
+    s.add_light(XYRect::new(Vec2::new(3.0, 1.0), Vec2::new(5.0, 3.0), -2.0, DiffuseLight::from_color(Vec3A::new(4.0, 4.0, 4.0))));
+    s.add_light(Sphere::new(Vec3A::new(0.0, 7.0, 0.0), 2.0, DiffuseLight::from_color(Vec3A::new(4.0, 4.0, 4.0))));
 
-    return s;
+    s
 }
 
 fn cornell_box() -> Scene {
@@ -184,7 +174,7 @@ fn cornell_box() -> Scene {
 
     s.add_shape(YZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 555.0, LambertianMat::from_color(green)));
     s.add_shape(YZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 0.0, LambertianMat::from_color(red)));
-    s.add_shape(XZRect::new(Vec2::new(213.0, 227.0), Vec2::new(343.0, 332.0), 554.0, DiffuseLight::from_color(light)));
+    s.add_light(XZRect::new(Vec2::new(213.0, 227.0), Vec2::new(343.0, 332.0), 554.0, DiffuseLight::from_color(light)));
     s.add_shape(XZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 555.0, LambertianMat::from_color(white)));
     s.add_shape(XZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 0.0, LambertianMat::from_color(white)));
     s.add_shape(XYRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 555.0, LambertianMat::from_color(white)));
@@ -201,7 +191,7 @@ fn cornell_box() -> Scene {
     let final_transform = translation * rotation;
     s.add_shape(TransformedObject::new(b1, final_transform));
 
-    return s;
+    s
 }
 
 fn cornell_smoke() -> Scene {
@@ -214,7 +204,7 @@ fn cornell_smoke() -> Scene {
 
     s.add_shape(YZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 555.0, LambertianMat::from_color(green)));
     s.add_shape(YZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 0.0, LambertianMat::from_color(red)));
-    s.add_shape(XZRect::new(Vec2::new(113.0, 127.0), Vec2::new(443.0, 432.0), 554.0, DiffuseLight::from_color(light)));
+    s.add_light(XZRect::new(Vec2::new(113.0, 127.0), Vec2::new(443.0, 432.0), 554.0, DiffuseLight::from_color(light)));
     s.add_shape(XZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 555.0, LambertianMat::from_color(white)));
     s.add_shape(XZRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 0.0, LambertianMat::from_color(white)));
     s.add_shape(XYRect::new(Vec2::new(0.0, 0.0), Vec2::new(555.0, 555.0), 555.0, LambertianMat::from_color(white)));
@@ -225,19 +215,19 @@ fn cornell_smoke() -> Scene {
     let final_transform = translation * rotation;
     let box2 = TransformedObject::new(b2, final_transform);
     //s.add_shape(box2);
-    s.add_shape(ConstantMedium::from_color(box2, 0.5, Vec3A::ONE));
+    s.add_shape(ConstantMedium::from_color(box2, 0.01, Vec3A::ONE));
 
     let b1 = Box2::new(165.0, 330.0, 165.0, LambertianMat::from_color(white));
     let rotation = Mat4::from_rotation_y(degree_to_rad(15.0));
     let translation = Mat4::from_translation(Vec3::new(265.0, 0.0, 305.0));
     let final_transform = translation * rotation;
     let box1 = TransformedObject::new(b1, final_transform);
-    s.add_shape(ConstantMedium::from_color(box1, 0.5, Vec3A::ZERO));
+    s.add_shape(ConstantMedium::from_color(box1, 0.01, Vec3A::ZERO));
     
-    return s;
+    s
 }
 
-fn final_scene() -> Scene {
+fn final_scene(rng: &mut Pcg32) -> Scene {
     let mut s = Scene::new();
 
     let mut boxes = Scene::new();
@@ -246,8 +236,6 @@ fn final_scene() -> Scene {
 
     let boxes_per_side = 20;
 
-    let mut rng = rand::thread_rng();
-
     for i in 0..boxes_per_side {
         for j in 0..boxes_per_side {
             let w = 100.0;
@@ -262,11 +250,11 @@ fn final_scene() -> Scene {
         }
     }
 
-    s.add_shape(BVHNode::from_scene(&boxes, 0.0, 1.0));
+    s.add_shape(FlatBVH::from_scene(&boxes, 0.0, 1.0));
 
     let light_color = Vec3A::new(7.0, 7.0, 7.0);
     let light = DiffuseLight::from_color(light_color);
-    s.add_shape(XZRect::new(Vec2::new(123.0,147.0), Vec2::new(423.0,412.0), 554.0, light));
+    s.add_light(XZRect::new(Vec2::new(123.0,147.0), Vec2::new(423.0,412.0), 554.0, light));
 
     let center1 = Vec3A::new(400.0, 400.0, 200.0);
     let center2 = center1 + Vec3A::new(30.0, 0.0, 0.0);
@@ -281,79 +269,179 @@ fn final_scene() -> Scene {
     let boundary = Sphere::new(Vec3A::new(360.0,150.0,145.0), 70.0, DielectricMat::new(1.5));
     s.add_shape(ConstantMedium::from_color(boundary, 0.2, Vec3A::new(0.2, 0.4, 0.9)));
     let boundary = Sphere::new(Vec3A::new(0.0, 0.0, 0.0), 5000.0, DielectricMat::new(1.5));
-    s.add_shape(ConstantMedium::from_color(boundary, 0.005, Vec3A::new(1.0,1.0,1.0)));
+    s.add_shape(ConstantMedium::from_color(boundary, 0.0001, Vec3A::new(1.0,1.0,1.0)));
 
     let emat = LambertianMat::from_texture(ImageTexture::new(String::from("earthmap.jpg")));
     s.add_shape(Sphere::new(Vec3A::new(400.0,200.0,400.0), 100.0, emat));
-    let pertext = NoiseTexture::new(0.1);
+    let pertext = NoiseTexture::new(0.1, rng);
     s.add_shape(Sphere::new(Vec3A::new(220.0,280.0,300.0), 80.0, LambertianMat::from_texture(pertext)));
 
     let mut boxes2 = Scene::new();
 
     for _ in 0..1000 {
         let white = LambertianMat::from_color(Vec3A::new(0.73, 0.73, 0.73));
-        boxes2.add_shape(Sphere::new(vec3_helpers::random_range(0.0,165.0), 10.0, white));
+        boxes2.add_shape(Sphere::new(vec3_helpers::random_range(0.0, 165.0, rng), 10.0, white));
     }
 
     let rotation = Mat4::from_rotation_y(degree_to_rad(15.0));
     let translation = Mat4::from_translation(Vec3::new(-100.0, 270.0, 395.0));
     let final_transform = translation * rotation;
 
-    s.add_shape(TransformedObject::new(BVHNode::from_scene(&boxes2, 0.0, 1.0), final_transform));
-
-    return s;
-}
+    s.add_shape(TransformedObject::new(FlatBVH::from_scene(&boxes2, 0.0, 1.0), final_transform));
 
-fn float_to_u8_color(f: f32) -> u8 {
-    (256.0 * f32::clamp(f, 0.0, 0.999)) as u8
+    s
 }
 
-fn vec3_to_rgb(color: Vec3A) -> (u8,u8,u8) {
-    (float_to_u8_color(color.x), float_to_u8_color(color.y), float_to_u8_color(color.z))
-}
-
-#[allow(dead_code)]
-enum ImageQuality {
-    Low,
-    High,
-    Cornell,
-    Final
+/// `seed` drives the random placement and noise textures of the scene.
+fn builtin_scene(scene: SceneType, seed: u64) -> (Scene, CameraSettings, Vec3A) {
+    let rng = &mut Pcg32::new(seed, SCENE_STREAM);
+    match scene {
+        SceneType::Random => (
+            generate_random_world(rng),
+            CameraSettings::new(Vec3A::new(13.0, 2.0, 3.0), Vec3A::new(0.0, 0.0, 0.0), degree_to_rad(20.0), 0.1),
+            Vec3A::new(0.70, 0.80, 1.00)
+        ),
+        SceneType::TwoSpheres => (
+            two_spheres(),
+            CameraSettings::new(Vec3A::new(13.0, 2.0, 3.0), Vec3A::new(0.0, 0.0, 0.0), degree_to_rad(20.0), 0.0),
+            Vec3A::new(0.7, 0.8, 1.0)
+        ),
+        SceneType::PerlinSpheres => (
+            two_perlin_spheres(rng),
+            CameraSettings::new(Vec3A::new(13.0, 2.0, 3.0), Vec3A::new(0.0, 0.0, 0.0), degree_to_rad(20.0), 0.0),
+            Vec3A::new(0.70, 0.80, 1.00)
+        ),
+        SceneType::Earth => (
+            earth(),
+            CameraSettings::new(Vec3A::new(13.0, 2.0, 3.0), Vec3A::new(0.0, 0.0, 0.0), degree_to_rad(20.0), 0.0),
+            Vec3A::new(0.70, 0.80, 1.00)
+        ),
+        SceneType::SimpleLight => (
+            simple_light(rng),
+            CameraSettings::new(Vec3A::new(26.0, 3.0, 6.0), Vec3A::new(0.0, 2.0, 0.0), degree_to_rad(20.0), 0.0),
+            Vec3A::ZERO
+        ),
+        SceneType::CornellBox => (
+            cornell_box(),
+            CameraSettings::new(Vec3A::new(278.0, 278.0, -800.0), Vec3A::new(278.0, 278.0, 0.0), degree_to_rad(40.0), 0.0),
+            Vec3A::ZERO
+        ),
+        SceneType::CornellSmoke => (
+            cornell_smoke(),
+            CameraSettings::new(Vec3A::new(278.0, 278.0, -800.0), Vec3A::new(278.0, 278.0, 0.0), degree_to_rad(40.0), 0.0),
+            Vec3A::ZERO
+        ),
+        SceneType::FinalScene => (
+            final_scene(rng),
+            CameraSettings::new(Vec3A::new(478.0, 278.0, -600.0), Vec3A::new(278.0, 278.0, 0.0), degree_to_rad(40.0), 0.0),
+            Vec3A::ZERO
+        )
+    }
 }
 
-#[allow(dead_code)]
-enum SceneType {
-    Random,
-    TwoSpheres,
-    PerlinSpheres,
-    Earth,
-    SimpleLight,
-    CornellBox,
-    CornellSmoke,
-    FinalScene
+/// Reads the AOV `aov` saved next to the image `input`, if there is one.
+fn load_guide(input: &str, aov: AovType, width: u32, height: u32) -> Option<Framebuffer> {
+    let path = aov::aov_path(input, aov);
+    if !path.exists() {
+        return None;
+    }
+    match Framebuffer::load(&path) {
+        Ok(guide) if guide.width == width && guide.height == height => {
+            println!("Denoising with the {} in '{}'", aov.name(), path.display());
+            Some(guide)
+        },
+        Ok(guide) => {
+            eprintln!("error: '{}' is {}x{}, the image is {}x{}", path.display(), guide.width, guide.height, width, height);
+            std::process::exit(1);
+        },
+        Err(error) => {
+            eprintln!("error: could not read '{}': {}", path.display(), error);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn main() {
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli::Command::Render(options)) => *options,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            return;
+        },
+        Err(error) => {
+            eprintln!("error: {}\n\nRun with --help for usage.", error);
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(input) = &options.input {
+        let mut framebuffer = match Framebuffer::load(input) {
+            Ok(framebuffer) => framebuffer,
+            Err(error) => {
+                eprintln!("error: could not read '{}': {}", input, error);
+                std::process::exit(1);
+            }
+        };
+        if options.denoise == Some(true) {
+            let (width, height) = (framebuffer.width, framebuffer.height);
+            let albedo = load_guide(input, AovType::Albedo, width, height);
+            let emission = load_guide(input, AovType::Emission, width, height);
+            let normal = load_guide(input, AovType::Normal, width, height);
+            let variance = load_guide(input, AovType::Variance, width, height)
+                .map(|v| v.pixels.iter().map(|p| p.x).collect::<Vec<f32>>());
+            let guides = DenoiseGuides {
+                albedo: albedo.as_ref(),
+                emission: emission.as_ref(),
+                normal: normal.as_ref(),
+                variance: variance.as_deref()
+            };
+            framebuffer = denoise::denoise(&framebuffer, &guides, &DenoiseSettings::default());
+        }
+        if let Err(error) = framebuffer.save(&options.output, &options.tone_map) {
+            eprintln!("error: could not write '{}': {}", options.output, error);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    // TODO: get dimensions from CLI
-    // Image
-    let aspect_ratio: f32;
-    let image_width: u32;
-    let samples_per_pixel: u32;
-    let max_depth: i32;
-
-    let world: Scene;
-    let fov: f32;
-    let aperture: f32;
-    let focus_distance: f32;
-    let origin: Vec3A;
-    let target: Vec3A;
+    let bvh_options = BVHBuildOptions {
+        max_leaf_size: options.bvh_leaf_size.unwrap_or(BVHBuildOptions::default().max_leaf_size),
+        ..BVHBuildOptions::default()
+    };
+
+    if options.benchmark_bvh && options.scene.is_none() && options.scene_file.is_none() {
+        // Every built-in scene with its default camera
+        let aspect_ratio = options.aspect_ratio.unwrap_or(1.0);
+        let image_width = options.image_width.unwrap_or(400);
+        let image_height = ((image_width as f32 / aspect_ratio) as u32).max(2);
+        for scene in SceneType::all() {
+            let (world, camera_settings, _) = builtin_scene(scene, options.seed);
+            bench::compare_bvhs(scene.name(), &world, &camera_settings.build(aspect_ratio), image_width, image_height, bvh_options);
+        }
+        return;
+    }
 
-    let background: Vec3A;
+    let (world, mut camera_settings, mut background, mut environment_settings, mut sky_settings, file_image) = match &options.scene_file {
+        Some(path) => match scene_file::load_scene(path, options.seed) {
+            Ok(loaded) => (loaded.world, loaded.camera, loaded.background, loaded.environment, loaded.sky, loaded.image),
+            Err(error) => {
+                eprintln!("error: {}", error);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            let (world, camera_settings, background) = builtin_scene(options.scene.unwrap_or(SceneType::FinalScene), options.seed);
+            (world, camera_settings, background, None, None, ImageSettings::default())
+        }
+    };
 
-    let quality = ImageQuality::Final;
-    let scene = SceneType::FinalScene;
+    // Image: quality preset, then the scene file, then the command line
+    let mut aspect_ratio: f32;
+    let mut image_width: u32;
+    let mut samples_per_pixel: u32;
+    let mut max_depth: i32;
 
-    match quality {
+    match options.quality {
         ImageQuality::Low => {
             aspect_ratio = 16.0 / 9.0;
             image_width = 400;
@@ -380,119 +468,265 @@ fn main() {
         }
     }
 
+    aspect_ratio = options.aspect_ratio.or(file_image.aspect_ratio).unwrap_or(aspect_ratio);
+    image_width = options.image_width.or(file_image.width).unwrap_or(image_width);
+    samples_per_pixel = options.samples_per_pixel.or(file_image.samples_per_pixel).unwrap_or(samples_per_pixel);
+    max_depth = options.max_depth.or(file_image.max_depth).unwrap_or(max_depth);
+    let denoise = options.denoise.or(file_image.denoise).unwrap_or(false);
+    let spectral = options.spectral.or(file_image.spectral).unwrap_or(false);
+
     let image_height = (image_width as f32 / aspect_ratio) as u32;
 
-    match scene {
-        SceneType::Random => {
-            world = generate_random_world();
-            origin = Vec3A::new(13.0, 2.0, 3.0);
-            target = Vec3A::new(0.0, 0.0, 0.0);
-            fov = degree_to_rad(20.0);
-            aperture = 0.1;
-            background = Vec3A::new(0.70, 0.80, 1.00);
+    camera_settings.origin = options.origin.unwrap_or(camera_settings.origin);
+    camera_settings.target = options.target.unwrap_or(camera_settings.target);
+    camera_settings.vfov = options.fov.map(degree_to_rad).unwrap_or(camera_settings.vfov);
+    camera_settings.aperture = options.aperture.unwrap_or(camera_settings.aperture);
+    camera_settings.focus_distance = options.focus_distance.unwrap_or(camera_settings.focus_distance);
+    background = options.background.unwrap_or(background);
+
+    // A sky from the command line replaces the scene's environment map and
+    // the other way round
+    if let Some(model) = options.sky {
+        environment_settings = None;
+        sky_settings = Some(SkySettings { model, ..sky_settings.unwrap_or_else(|| SkySettings::new(model)) });
+    }
+    if options.environment.is_some() {
+        sky_settings = None;
+    }
+    match &mut sky_settings {
+        Some(sky) => {
+            if let Some((elevation, azimuth)) = options.sun {
+                sky.sun_elevation = degree_to_rad(elevation);
+                sky.sun_azimuth = degree_to_rad(azimuth);
+            }
+            sky.turbidity = options.turbidity.unwrap_or(sky.turbidity);
+            sky.sun_radius = options.sun_radius.map(degree_to_rad).unwrap_or(sky.sun_radius);
         },
-        SceneType::TwoSpheres => {
-            world = two_spheres();
-            origin = Vec3A::new(13.0, 2.0, 3.0);
-            target = Vec3A::new(0.0, 0.0, 0.0);
-            fov = degree_to_rad(20.0);
-            aperture = 0.0;
-            background = Vec3A::new(0.7, 0.8, 1.0);
+        None if options.sun.is_some() || options.turbidity.is_some() || options.sun_radius.is_some() => {
+            eprintln!("error: --sun, --turbidity and --sun-radius need a sky");
+            std::process::exit(2);
         },
-        SceneType::PerlinSpheres => {
-            world = two_perlin_spheres();
-            origin = Vec3A::new(13.0, 2.0, 3.0);
-            target = Vec3A::new(0.0, 0.0, 0.0);
-            fov = degree_to_rad(20.0);
-            aperture = 0.0;
-            background = Vec3A::new(0.70, 0.80, 1.00);
+        None => {}
+    }
+
+    if let Some(path) = &options.environment {
+        let settings = environment_settings.get_or_insert_with(|| EnvironmentSettings::new(PathBuf::new()));
+        settings.path = PathBuf::from(path);
+    }
+    match &mut environment_settings {
+        Some(settings) => {
+            settings.rotation = options.environment_rotation.map(degree_to_rad).unwrap_or(settings.rotation);
+            settings.intensity = options.environment_intensity.unwrap_or(settings.intensity);
         },
-        SceneType::Earth => {
-            world = earth();
-            origin = Vec3A::new(13.0, 2.0, 3.0);
-            target = Vec3A::new(0.0, 0.0, 0.0);
-            fov = degree_to_rad(20.0);
-            aperture = 0.0;
-            background = Vec3A::new(0.70, 0.80, 1.00);
+        None if options.environment_rotation.is_some() || options.environment_intensity.is_some() => {
+            eprintln!("error: --environment-rotation and --environment-intensity need an environment map");
+            std::process::exit(2);
         },
-        SceneType::SimpleLight => {
-            world = simple_light();
-            origin = Vec3A::new(26.0, 3.0, 6.0);
-            target = Vec3A::new(0.0, 2.0, 0.0);
-            fov = degree_to_rad(20.0);
-            aperture = 0.0;
-            background = Vec3A::ZERO;
+        None => {}
+    }
+
+    if let Err(error) = cli::validate_view(image_width, image_height, camera_settings.origin, camera_settings.target, camera_settings.up) {
+        eprintln!("error: {}", error);
+        std::process::exit(2);
+    }
+
+    let camera = camera_settings.build(aspect_ratio);
+
+    if options.benchmark_bvh {
+        let name = match (&options.scene_file, options.scene) {
+            (Some(path), _) => path.as_str(),
+            (None, Some(scene)) => scene.name(),
+            (None, None) => unreachable!()
+        };
+        bench::compare_bvhs(name, &world, &camera, image_width, image_height, bvh_options);
+        return;
+    }
+
+    let build_start = Instant::now();
+    let bvh = FlatBVH::from_scene_with_options(&world, 0.0, 1.0, bvh_options);
+    println!("BVH built in {} ms: {}", build_start.elapsed().as_millis(), bvh.stats());
+
+    let environment: Arc<dyn Environment> = match (&environment_settings, &sky_settings) {
+        (Some(settings), _) => match settings.load() {
+            Ok(map) => Arc::new(map),
+            Err(error) => {
+                eprintln!("error: could not read the environment map '{}': {}", settings.path.display(), error);
+                std::process::exit(1);
+            }
         },
-        SceneType::CornellBox => {
-            world = cornell_box();
-            origin = Vec3A::new(278.0, 278.0, -800.0);
-            target = Vec3A::new(278.0, 278.0, 0.0);
-            fov = degree_to_rad(40.0);
-            aperture = 0.0;
-            background = Vec3A::ZERO;
-            //background = Vec3A::new(0.7, 0.8, 1.0);
+        (None, Some(sky)) => sky.build(),
+        (None, None) => Arc::new(ConstantEnvironment::new(background))
+    };
+
+    // Punctual lights can only be reached by light sampling, so they are
+    // kept without it too
+    let lights = if options.light_sampling {
+        LightList::with_environment(world.lights.clone(), environment.clone())
+    } else {
+        LightList::empty()
+    }.with_punctual_lights(world.punctual_lights.clone());
+
+    let renderer = Renderer {
+        world: &bvh,
+        lights: &lights,
+        camera: &camera,
+        environment: environment.as_ref(),
+        max_depth,
+        sampler: options.sampler,
+        seed: options.seed,
+        samples_per_pixel,
+        spectral
+    };
+    let hash_file = |path: &Path| checkpoint::hash_file(path).unwrap_or_else(|error| {
+        eprintln!("error: could not read '{}' for the checkpoint: {}", path.display(), error);
+        std::process::exit(1);
+    });
+    let checkpoint_config = options.checkpoint.as_ref().map(|_| {
+        let (scene, scene_hash) = match &options.scene_file {
+            Some(path) => (path.clone(), hash_file(Path::new(path))),
+            None => (options.scene.unwrap_or(SceneType::FinalScene).name().to_string(), String::new())
+        };
+        let config = RenderConfig::new(scene, scene_hash, image_width, image_height, max_depth, background,
+            options.light_sampling, options.seed, options.sampler, &camera_settings, aspect_ratio).with_spectral(spectral);
+        match (&environment_settings, &sky_settings) {
+            (Some(settings), _) => config.with_environment(settings, hash_file(&settings.path)),
+            (None, Some(sky)) => config.with_sky(sky),
+            (None, None) => config
+        }
+    });
+
+    let mut accumulator = match (&options.checkpoint, &checkpoint_config) {
+        (Some(path), Some(config)) if options.resume => match checkpoint::load(path, config) {
+            Ok(accumulator) => {
+                println!("Resuming from '{}' with {} samples", path, accumulator.total_samples());
+                accumulator
+            },
+            Err(error) => {
+                eprintln!("error: cannot resume from '{}': {}", path, error);
+                std::process::exit(1);
+            }
         },
-        SceneType::CornellSmoke => {
-            world = cornell_smoke();
-            origin = Vec3A::new(278.0, 278.0, -800.0);
-            target = Vec3A::new(278.0, 278.0, 0.0);
-            fov = degree_to_rad(40.0);
-            aperture = 0.0;
-            background = Vec3A::ZERO;
-            //background = Vec3A::new(0.7, 0.8, 1.0);
+        (Some(path), _) if Path::new(path).exists() => {
+            eprintln!("error: the checkpoint '{}' already exists, pass --resume to continue it or remove it", path);
+            std::process::exit(1);
         },
-        SceneType::FinalScene => {
-            world = final_scene();
-            origin = Vec3A::new(478.0, 278.0, -600.0);
-            target = Vec3A::new(278.0, 278.0, 0.0);
-            fov = degree_to_rad(40.0);
-            aperture = 0.0;
-            background = Vec3A::ZERO;
-        }
-    }
+        _ => Accumulator::new(image_width, image_height)
+    };
 
-    let vup = Vec3A::Y;
-    focus_distance = 10.0;
-    let camera = Camera::new(origin, target, vup, fov, aspect_ratio, aperture, focus_distance, 0.0, 1.0); 
+    // The denoiser needs the albedo and normals for the previews already
+    let mut aovs = Aovs::new(image_width, image_height);
+    if denoise || !options.aovs.is_empty() {
+        renderer.render_aovs(&mut aovs, samples_per_pixel.min(AOV_SAMPLES), &world.material_ids());
+    }
+    let albedo = aovs.to_framebuffer(AovType::Albedo);
+    let emission = aovs.to_framebuffer(AovType::Emission);
+    let normal = aovs.to_framebuffer(AovType::Normal);
+
+    let save = |accumulator: &Accumulator| {
+        let mut framebuffer = accumulator.to_framebuffer();
+        if denoise {
+            let variance = accumulator.variances();
+            let guides = DenoiseGuides {
+                albedo: Some(&albedo),
+                emission: Some(&emission),
+                normal: Some(&normal),
+                variance: Some(&variance)
+            };
+            framebuffer = denoise::denoise(&framebuffer, &guides, &DenoiseSettings::default());
+        }
+        if let Err(error) = framebuffer.save(&options.output, &options.tone_map) {
+            eprintln!("error: could not write '{}': {}", options.output, error);
+            std::process::exit(1);
+        }
+    };
 
-    let bvh = BVHNode::from_scene(&world, 0.0, 1.0);
+    let save_checkpoint = |accumulator: &Accumulator| {
+        if let (Some(path), Some(config)) = (&options.checkpoint, &checkpoint_config) {
+            if let Err(error) = checkpoint::save(path, config, accumulator) {
+                eprintln!("error: could not write the checkpoint '{}': {}", path, error);
+                std::process::exit(1);
+            }
+        }
+    };
 
-    let inv_samples = 1.0 / samples_per_pixel as f32;
+    let passes = options.progressive || options.adaptive || options.checkpoint.is_some();
+    if passes {
+        if let Err(error) = render::install_interrupt_handler() {
+            eprintln!("warning: Ctrl-C will not save the render: {}", error);
+        }
+    }
 
     let now = Instant::now();
-    let par: Vec<(u8,u8,u8)> = (0..image_width * image_height)
-        .map(|i| (i % image_width, i / image_width))
-        .collect::<Vec<(u32, u32)>>()
-        .par_iter()
-        .map(|(i, j)| {
-            let mut rng = rand::thread_rng();
-            let color = (0..samples_per_pixel)
-                .map(|_| {
-                    let u = (*i as f32 + rng.gen::<f32>()) / (image_width - 1) as f32;
-                    let v = ((image_height - 1 - *j) as f32 + rng.gen::<f32>()) / (image_height - 1) as f32;
-
-                    let r = camera.get_ray(u, v);
-
-                    ray_color(&r, background, &bvh, max_depth)
+    let finished = if passes {
+        let settings = ProgressiveSettings {
+            pass_samples: options.pass_samples.unwrap_or(4),
+            preview_interval: if options.progressive {
+                Some(options.preview_interval.map(Duration::from_secs_f32).unwrap_or(Duration::ZERO))
+            } else {
+                None
+            },
+            checkpoint_interval: options.checkpoint.as_ref()
+                .map(|_| Duration::from_secs_f32(options.checkpoint_interval.unwrap_or(300.0))),
+            adaptive: if options.adaptive {
+                Some(AdaptiveSettings {
+                    min_samples: options.min_samples.unwrap_or(16),
+                    max_samples: options.max_samples.unwrap_or(samples_per_pixel.saturating_mul(4)),
+                    threshold: options.adaptive_threshold.unwrap_or(0.01)
                 })
-                .reduce(|c, src| c + src)
-                .unwrap();
-
-            vec3_to_rgb(color * inv_samples)
-        }).collect();
+            } else {
+                None
+            }
+        };
+        renderer.render_progressive(&mut accumulator, samples_per_pixel, settings, |accumulator, snapshot| {
+            match snapshot {
+                Snapshot::Preview => save(accumulator),
+                Snapshot::Checkpoint => save_checkpoint(accumulator)
+            }
+        })
+    } else {
+        renderer.render_pass(&mut accumulator, samples_per_pixel);
+        true
+    };
 
     println!("Time elapsed: {}", now.elapsed().as_millis());
 
-    let mut out_data = Vec::new();
-    out_data.reserve(par.len() * 3);
+    save_checkpoint(&accumulator);
+    save(&accumulator);
 
-    for pixel in par.iter() {
-        out_data.push(pixel.0);
-        out_data.push(pixel.1);
-        out_data.push(pixel.2);
+    if let Some(path) = &options.sample_count_output {
+        let mut counts = accumulator.sample_counts();
+        let most = accumulator.samples.iter().copied().max().unwrap_or(0).max(1) as f32;
+        if !OutputFormat::from_path(path).is_some_and(|format| format.is_hdr()) {
+            counts.pixels.iter_mut().for_each(|p| *p /= most);
+        }
+        let linear = ToneMapSettings { srgb: false, ..ToneMapSettings::default() };
+        if let Err(error) = counts.save(path, &linear) {
+            eprintln!("error: could not write '{}': {}", path, error);
+            std::process::exit(1);
+        }
     }
 
-    let output: RgbImage = ImageBuffer::from_raw(image_width, image_height, out_data).unwrap();
+    if finished && !options.aovs.is_empty() {
+        aovs.variance = accumulator.variances();
+        let hdr = OutputFormat::from_path(&options.output).is_some_and(|format| format.is_hdr());
+        for aov in options.aovs.iter() {
+            let path = aov::aov_path(&options.output, *aov);
+            let (image, tone_map) = if hdr {
+                (aovs.to_framebuffer(*aov), options.tone_map)
+            } else {
+                (aovs.to_display_framebuffer(*aov), ToneMapSettings { srgb: aov.is_color(), ..ToneMapSettings::default() })
+            };
+            if let Err(error) = image.save(&path, &tone_map) {
+                eprintln!("error: could not write '{}': {}", path.display(), error);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    output.save("./test.png").unwrap();
+    if !finished {
+        if let Some(path) = &options.checkpoint {
+            println!("Interrupted, saved the checkpoint to '{}'", path);
+        }
+        std::process::exit(130);
+    }
 }
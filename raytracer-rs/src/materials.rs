@@ -1,20 +1,115 @@
 extern crate glam;
 
-use crate::ray::Ray;
 use crate::hit_record::HitRecord;
 use crate::texture::*;
 
 use crate::vec3_helpers;
+use crate::sampler::Sampler;
+use crate::microfacet::{self, ShadingFrame, GGX};
+use crate::spectrum::Dispersion;
+use crate::tonemap::luminance;
 
+use std::f32::consts::PI;
 use std::sync::Arc;
 
 use glam::*;
 
-pub trait Material {
-    fn scatter(&self, ray: &Ray, record: &HitRecord, attentuation: &mut Vec3A, scattered: &mut Ray) -> bool;
+/// The kinds of scattering a material can produce, combined with `|`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LobeFlags(u8);
+
+impl LobeFlags {
+    pub const NONE: LobeFlags = LobeFlags(0);
+    pub const REFLECTION: LobeFlags = LobeFlags(1);
+    pub const TRANSMISSION: LobeFlags = LobeFlags(2);
+    pub const DIFFUSE: LobeFlags = LobeFlags(4);
+    pub const GLOSSY: LobeFlags = LobeFlags(8);
+    /// Lobes that can only be sampled: `eval` and `pdf` are zero for them
+    pub const SPECULAR: LobeFlags = LobeFlags(16);
+
+    pub fn contains(self, other: LobeFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: LobeFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn is_specular(self) -> bool {
+        self.contains(LobeFlags::SPECULAR)
+    }
+
+    /// Whether `eval` and `pdf` can be non-zero, i.e. light sampling is useful
+    pub fn has_non_specular(self) -> bool {
+        self.intersects(LobeFlags::DIFFUSE | LobeFlags::GLOSSY)
+    }
+}
+
+impl std::ops::BitOr for LobeFlags {
+    type Output = LobeFlags;
+
+    fn bitor(self, other: LobeFlags) -> LobeFlags {
+        LobeFlags(self.0 | other.0)
+    }
+}
+
+/// A direction picked by `Material::sample`.
+#[derive(Copy, Clone, Debug)]
+pub struct BSDFSample {
+    /// Unit direction towards the incoming light
+    pub wi: Vec3A,
+    /// `eval(wo, wi) / pdf`, the factor the light arriving along `wi` is scaled by
+    pub weight: Vec3A,
+    /// Solid angle density of `wi`, meaningless for specular lobes
+    pub pdf: f32,
+    /// The lobe `wi` was sampled from
+    pub lobe: LobeFlags
+}
+
+/// Directions are unit vectors pointing away from the hit point: `wo` towards
+/// where the ray came from and `wi` towards the incoming light.
+pub trait Material: Send + Sync {
+    /// Picks an incoming direction, or `None` if the ray is absorbed.
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample>;
+
+    /// `sample` for light of a single `wavelength` in nanometres, used in
+    /// spectral mode. Only materials that bend each wavelength differently
+    /// need to override it.
+    fn sample_wavelength(&self, wo: Vec3A, record: &HitRecord, _wavelength: f32, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        self.sample(wo, record, sampler)
+    }
+
+    /// Whether `sample_wavelength` sends different wavelengths different ways.
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    /// The BSDF f(wo, wi) times |cos| of `wi` with the normal (phase
+    /// functions have no cosine term). Zero for specular lobes.
+    fn eval(&self, _wo: Vec3A, _wi: Vec3A, _record: &HitRecord) -> Vec3A {
+        Vec3A::ZERO
+    }
+
+    /// Solid angle density with which `sample` picks `wi`. Zero for specular lobes.
+    fn pdf(&self, _wo: Vec3A, _wi: Vec3A, _record: &HitRecord) -> f32 {
+        0.0
+    }
+
+    /// Every lobe `sample` can return.
+    fn lobes(&self) -> LobeFlags;
+
     fn emitted(&self, _tex_coords: (f32, f32), _point: Vec3A) -> Vec3A {
         Vec3A::ZERO
     }
+
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    /// The colour of the surface for the albedo image, in [0, 1].
+    fn albedo(&self, _record: &HitRecord) -> Vec3A {
+        Vec3A::ZERO
+    }
 }
 
 #[derive(Clone)]
@@ -31,7 +126,7 @@ impl LambertianMat {
 
     pub fn from_shared_texture(albedo: Arc<dyn Texture>) -> Self {
         LambertianMat {
-            albedo: albedo
+            albedo
         }
     }
 
@@ -43,20 +138,40 @@ impl LambertianMat {
 }
 
 impl Material for LambertianMat {
-    fn scatter(&self, _ray: &Ray, record: &HitRecord, attentuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let mut scatter = record.normal + vec3_helpers::random_unit_vector();
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let wi = vec3_helpers::sample_cosine_direction(record.normal, sampler.get_2d());
+        let pdf = self.pdf(wo, wi, record);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        // Cosine sampling cancels everything but the albedo
+        Some(BSDFSample {
+            wi,
+            weight: self.albedo.value(record.tex_coords, record.point),
+            pdf,
+            lobe: LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+        })
+    }
 
-        if vec3_helpers::is_near_zero(scatter) {
-            scatter = record.normal;
+    fn eval(&self, _wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        let cosine = record.normal.dot(wi);
+        if cosine <= 0.0 {
+            return Vec3A::ZERO;
         }
+        self.albedo.value(record.tex_coords, record.point) * cosine / std::f32::consts::PI
+    }
 
-        *scattered = Ray{
-            origin: record.point,
-            direction: scatter,
-            time: _ray.time
-        };
-        *attentuation = self.albedo.value(record.tex_coords, record.point);
-        return true;
+    fn pdf(&self, _wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        f32::max(record.normal.dot(wi), 0.0) / std::f32::consts::PI
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Vec3A {
+        self.albedo.value(record.tex_coords, record.point)
     }
 }
 
@@ -69,7 +184,7 @@ pub struct MetalMat {
 impl MetalMat {
     pub fn new(albedo: Vec3A, fuzz: f32) -> Self {
         MetalMat{
-            albedo: albedo,
+            albedo,
             fuzz: {
                 if fuzz < 1.0 {
                     fuzz
@@ -79,71 +194,1215 @@ impl MetalMat {
             }
         }
     }
+
+    /// Density of `normalize(reflected + fuzz * p)` for `p` uniform in the
+    /// unit ball. The points `reflected + fuzz * p` fill a ball of radius
+    /// `fuzz`; a direction gets the part of it that lies along `wi`, where
+    /// the solid angle spreads with the squared distance from the origin.
+    fn fuzz_pdf(&self, reflected: Vec3A, wi: Vec3A) -> f32 {
+        // The distances s along `wi` with |s * wi - reflected| <= fuzz
+        let cos_theta = wi.dot(reflected);
+        let sin2_theta = wi.cross(reflected).length_squared();
+        let discriminant = self.fuzz * self.fuzz - sin2_theta;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let half_length = discriminant.sqrt();
+        let far = cos_theta + half_length;
+        if far <= 0.0 {
+            return 0.0;
+        }
+        let near = f32::max(cos_theta - half_length, 0.0);
+
+        // The integral of s^2 from near to far, over the volume of the ball
+        (far - near) * (far * far + far * near + near * near) / (4.0 * PI * self.fuzz.powi(3))
+    }
 }
 
+// Without fuzz the reflection is a perfect mirror, otherwise every sample
+// carries the albedo so `eval` is the albedo times the sampling density
 impl Material for MetalMat {
-    fn scatter(&self, ray: &Ray, record: &HitRecord, attentuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        let reflected = vec3_helpers::reflect(ray.direction.normalize(), record.normal);
-        *scattered = Ray{
-            origin: record.point,
-            direction: reflected + self.fuzz * vec3_helpers::random_in_unit_sphere(),
-            time: ray.time
-        };
-        *attentuation = self.albedo;
-        return scattered.direction.dot(record.normal) > 0.0;
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let reflected = vec3_helpers::reflect(-wo, record.normal);
+        let wi = (reflected + self.fuzz * vec3_helpers::sample_ball(sampler.get_2d(), sampler.get_1d())).normalize();
+
+        if wi.dot(record.normal) <= 0.0 {
+            return None;
+        }
+
+        if self.fuzz <= 0.0 {
+            return Some(BSDFSample {
+                wi,
+                weight: self.albedo,
+                pdf: 0.0,
+                lobe: LobeFlags::SPECULAR | LobeFlags::REFLECTION
+            });
+        }
+
+        let pdf = self.fuzz_pdf(reflected, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        Some(BSDFSample {
+            wi,
+            weight: self.albedo,
+            pdf,
+            lobe: LobeFlags::GLOSSY | LobeFlags::REFLECTION
+        })
+    }
+
+    fn eval(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        self.albedo * self.pdf(wo, wi, record)
+    }
+
+    fn pdf(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        if self.fuzz <= 0.0 || wi.dot(record.normal) <= 0.0 {
+            return 0.0;
+        }
+        self.fuzz_pdf(vec3_helpers::reflect(-wo, record.normal), wi)
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        if self.fuzz <= 0.0 {
+            return LobeFlags::SPECULAR | LobeFlags::REFLECTION;
+        }
+        LobeFlags::GLOSSY | LobeFlags::REFLECTION
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Vec3A {
+        self.albedo
+    }
+}
+
+/// Measured metals for `ConductorMat`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConductorPreset {
+    Gold,
+    Copper,
+    Aluminium,
+    Silver,
+    Iron
+}
+
+impl ConductorPreset {
+    pub const NAMES: [(&'static str, ConductorPreset); 5] = [
+        ("gold", ConductorPreset::Gold),
+        ("copper", ConductorPreset::Copper),
+        ("aluminium", ConductorPreset::Aluminium),
+        ("silver", ConductorPreset::Silver),
+        ("iron", ConductorPreset::Iron),
+    ];
+
+    pub fn from_name(name: &str) -> Option<ConductorPreset> {
+        ConductorPreset::NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, p)| *p)
+    }
+
+    pub fn name(&self) -> &'static str {
+        ConductorPreset::NAMES.iter().find(|(_, p)| p == self).map(|(n, _)| *n).unwrap()
+    }
+
+    /// The complex index of refraction (eta, k) at roughly 650, 550 and
+    /// 450 nm for the red, green and blue channels.
+    pub fn ior(&self) -> (Vec3A, Vec3A) {
+        match self {
+            ConductorPreset::Gold => (Vec3A::new(0.143, 0.374, 1.442), Vec3A::new(3.983, 2.385, 1.603)),
+            ConductorPreset::Copper => (Vec3A::new(0.200, 0.924, 1.102), Vec3A::new(3.912, 2.452, 2.142)),
+            ConductorPreset::Aluminium => (Vec3A::new(1.657, 0.880, 0.521), Vec3A::new(9.224, 6.270, 4.837)),
+            ConductorPreset::Silver => (Vec3A::new(0.155, 0.117, 0.138), Vec3A::new(4.828, 3.122, 2.147)),
+            ConductorPreset::Iron => (Vec3A::new(2.911, 2.950, 2.585), Vec3A::new(3.089, 2.932, 2.767))
+        }
+    }
+}
+
+/// A metal: GGX microfacets reflecting with the Fresnel factor of a complex
+/// index of refraction, sampled by their visible normals. Without roughness
+/// it is a perfect mirror. Light that would bounce between microfacets is
+/// lost, so very rough metals are a little dark.
+#[derive(Copy, Clone)]
+pub struct ConductorMat {
+    eta: Vec3A,
+    k: Vec3A,
+    distribution: GGX
+}
+
+impl ConductorMat {
+    /// `roughness` is perceptual, see `microfacet::anisotropic_alphas`.
+    pub fn new(eta: Vec3A, k: Vec3A, roughness: f32) -> Self {
+        ConductorMat::new_anisotropic(eta, k, roughness, 0.0)
+    }
+
+    /// An `anisotropy` of 1 stretches the highlight along the tangent.
+    pub fn new_anisotropic(eta: Vec3A, k: Vec3A, roughness: f32, anisotropy: f32) -> Self {
+        let (alpha_x, alpha_y) = microfacet::anisotropic_alphas(roughness, anisotropy);
+        ConductorMat {
+            eta,
+            k,
+            distribution: GGX::new(alpha_x, alpha_y)
+        }
+    }
+
+    pub fn from_preset(preset: ConductorPreset, roughness: f32, anisotropy: f32) -> Self {
+        let (eta, k) = preset.ior();
+        ConductorMat::new_anisotropic(eta, k, roughness, anisotropy)
+    }
+}
+
+impl Material for ConductorMat {
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z <= 0.0 {
+            return None;
+        }
+
+        if self.distribution.is_smooth() {
+            return Some(BSDFSample {
+                wi: vec3_helpers::reflect(-wo, record.normal),
+                weight: microfacet::fresnel_conductor(wo_local.z, self.eta, self.k),
+                pdf: 0.0,
+                lobe: LobeFlags::SPECULAR | LobeFlags::REFLECTION
+            });
+        }
+
+        let wm = self.distribution.sample_visible_normal(wo_local, sampler.get_2d());
+        let wi_local = vec3_helpers::reflect(-wo_local, wm);
+        if wi_local.z <= 0.0 {
+            return None;
+        }
+
+        // Everything but the Fresnel factor and the shadowing from `wi`
+        // cancels with the density
+        let fresnel = microfacet::fresnel_conductor(wo_local.dot(wm), self.eta, self.k);
+        let weight = fresnel * self.distribution.g(wo_local, wi_local) / self.distribution.g1(wo_local);
+        Some(BSDFSample {
+            wi: frame.to_world(wi_local),
+            weight,
+            pdf: self.distribution.visible_d(wo_local, wm) / (4.0 * wo_local.dot(wm)),
+            lobe: LobeFlags::GLOSSY | LobeFlags::REFLECTION
+        })
+    }
+
+    fn eval(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        if self.distribution.is_smooth() {
+            return Vec3A::ZERO;
+        }
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let wo_local = frame.to_local(wo);
+        let wi_local = frame.to_local(wi);
+        if wo_local.z <= 0.0 || wi_local.z <= 0.0 {
+            return Vec3A::ZERO;
+        }
+        let wm = (wo_local + wi_local).normalize();
+
+        // D G F / (4 cos_o cos_i), times cos_i
+        let fresnel = microfacet::fresnel_conductor(wo_local.dot(wm), self.eta, self.k);
+        fresnel * self.distribution.d(wm) * self.distribution.g(wo_local, wi_local) / (4.0 * wo_local.z)
+    }
+
+    fn pdf(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        if self.distribution.is_smooth() {
+            return 0.0;
+        }
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let wo_local = frame.to_local(wo);
+        let wi_local = frame.to_local(wi);
+        if wo_local.z <= 0.0 || wi_local.z <= 0.0 {
+            return 0.0;
+        }
+        let wm = (wo_local + wi_local).normalize();
+        self.distribution.visible_d(wo_local, wm) / (4.0 * wo_local.dot(wm))
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        if self.distribution.is_smooth() {
+            LobeFlags::SPECULAR | LobeFlags::REFLECTION
+        } else {
+            LobeFlags::GLOSSY | LobeFlags::REFLECTION
+        }
+    }
+
+    /// The reflectance straight on.
+    fn albedo(&self, _record: &HitRecord) -> Vec3A {
+        microfacet::fresnel_conductor(1.0, self.eta, self.k).min(Vec3A::ONE)
     }
 }
 
 #[derive(Copy, Clone)]
 pub struct DielectricMat {
-    index_refraction: f32
+    index_refraction: f32,
+    /// Only used in spectral mode, `index_refraction` stands in for it otherwise
+    dispersion: Option<Dispersion>
 }
 
 impl DielectricMat {
     pub fn new(index: f32) -> Self {
         Self {
-            index_refraction: index
+            index_refraction: index,
+            dispersion: None
         }
     }
 
+    pub fn with_dispersion(mut self, dispersion: Dispersion) -> Self {
+        self.dispersion = Some(dispersion);
+        self
+    }
+
     fn reflectance(&self, cosine: f32, ref_index: f32) -> f32 {
         let mut r0 = (1.0 - ref_index) / (1.0 + ref_index);
         r0 = r0 * r0;
 
-        return r0 + (1.0 - r0) * f32::powf(1.0 - cosine, 5.0); 
+        r0 + (1.0 - r0) * f32::powf(1.0 - cosine, 5.0)
     }
-}
 
-impl Material for DielectricMat {
-    fn scatter(&self, ray: &Ray, record: &HitRecord, attentuation: &mut Vec3A, scattered: &mut Ray) -> bool {
-        *attentuation = Vec3A::ONE;
-        let refraction_ratio: f32;
-        
-        if record.front_face { 
-            refraction_ratio = 1.0 / self.index_refraction;
+    /// `sample` with `index_refraction` in place of the material's own.
+    fn sample_index(&self, wo: Vec3A, record: &HitRecord, index_refraction: f32, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let refraction_ratio = if record.front_face { 
+            1.0 / index_refraction
         } else { 
-            refraction_ratio = self.index_refraction;
+            index_refraction
         };
 
-        let unit_direction = ray.direction.normalize();
-        let cos_theta = f32::min(record.normal.dot(-unit_direction), 1.0);
+        let unit_direction = -wo;
+        let cos_theta = f32::min(record.normal.dot(wo), 1.0);
         let sin_theta = f32::sqrt(1.0 - cos_theta * cos_theta);
 
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let direction: Vec3A;
+        let (wi, lobe) = if cannot_refract || self.reflectance(cos_theta, refraction_ratio) > sampler.get_1d() {
+            (vec3_helpers::reflect(unit_direction, record.normal), LobeFlags::REFLECTION)
+        } else {
+            (vec3_helpers::refract(unit_direction, record.normal, refraction_ratio), LobeFlags::TRANSMISSION)
+        };
+
+        Some(BSDFSample {
+            wi: wi.normalize(),
+            weight: Vec3A::ONE,
+            pdf: 0.0,
+            lobe: LobeFlags::SPECULAR | lobe
+        })
+    }
+}
+
+impl Material for DielectricMat {
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        self.sample_index(wo, record, self.index_refraction, sampler)
+    }
+
+    fn sample_wavelength(&self, wo: Vec3A, record: &HitRecord, wavelength: f32, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let index_refraction = match &self.dispersion {
+            Some(dispersion) => dispersion.ior(wavelength),
+            None => self.index_refraction
+        };
+        self.sample_index(wo, record, index_refraction, sampler)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some()
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        LobeFlags::SPECULAR | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Vec3A {
+        Vec3A::ONE
+    }
+}
+
+/// Unpolarised reflectance of a dielectric interface for light arriving at
+/// `cos_theta` from the normal, `eta` being the index of refraction on the
+/// far side over the one on the near side.
+fn fresnel_dielectric(cos_theta: f32, eta: f32) -> f32 {
+    let cos_i = cos_theta.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = f32::sqrt(1.0 - sin2_t);
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// The microfacet normal that turns `wo` into `wi` at a dielectric
+/// interface, facing `wo`, or `None` if no microfacet does. Local frame.
+fn dielectric_half_vector(wo: Vec3A, wi: Vec3A, eta: f32) -> Option<Vec3A> {
+    let reflect = wi.z > 0.0;
+    let wm = if reflect { wo + wi } else { wo + wi * eta };
+    if wm.length_squared() == 0.0 {
+        return None;
+    }
+    let wm = wm.normalize();
+    let wm = if wm.z < 0.0 { -wm } else { wm };
+    // Microfacets seen from behind don't scatter
+    if wo.dot(wm) <= 0.0 || (if reflect { wi.dot(wm) <= 0.0 } else { wi.dot(wm) >= 0.0 }) {
+        return None;
+    }
+    Some(wm)
+}
+
+/// A rough dielectric interface with the relative index `eta`: the BSDF
+/// times |cos wi| and the density `sample_rough_dielectric` picks `wi` with,
+/// in the local frame.
+fn rough_dielectric(distribution: &GGX, wo: Vec3A, wi: Vec3A, eta: f32) -> (f32, f32) {
+    if wo.z <= 0.0 || wi.z == 0.0 {
+        return (0.0, 0.0);
+    }
+    let wm = match dielectric_half_vector(wo, wi, eta) {
+        Some(wm) => wm,
+        None => return (0.0, 0.0)
+    };
+    let fresnel = fresnel_dielectric(wo.dot(wm), eta);
+    let d = distribution.d(wm);
+    let g = distribution.g(wo, wi);
+    let visible = distribution.visible_d(wo, wm);
+
+    if wi.z > 0.0 {
+        let f = d * g * fresnel / (4.0 * wo.z);
+        let pdf = visible / (4.0 * wo.dot(wm)) * fresnel;
+        return (f, pdf);
+    }
+
+    let denominator = wi.dot(wm) + wo.dot(wm) / eta;
+    let denominator = denominator * denominator;
+    let f = (1.0 - fresnel) * d * g * f32::abs(wi.dot(wm) * wo.dot(wm) / (wo.z * denominator));
+    let pdf = visible * f32::abs(wi.dot(wm)) / denominator * (1.0 - fresnel);
+    (f, pdf)
+}
+
+/// Reflects or refracts `wo` off a visible microfacet, picking by the
+/// Fresnel term. `None` if the direction ends up on the wrong side.
+fn sample_rough_dielectric(distribution: &GGX, wo: Vec3A, eta: f32, sampler: &mut dyn Sampler) -> Option<(Vec3A, LobeFlags)> {
+    let wm = distribution.sample_visible_normal(wo, sampler.get_2d());
+    let fresnel = fresnel_dielectric(wo.dot(wm), eta);
+    let (wi, lobe) = if fresnel > sampler.get_1d() {
+        (vec3_helpers::reflect(-wo, wm), LobeFlags::REFLECTION)
+    } else {
+        (vec3_helpers::refract(-wo, wm, 1.0 / eta).normalize(), LobeFlags::TRANSMISSION)
+    };
+    // Reflections below the surface and refractions above it are lost
+    if (wi.z > 0.0) != (lobe == LobeFlags::REFLECTION) {
+        return None;
+    }
+    Some((wi, lobe))
+}
+
+/// Glass with an optionally rough GGX surface (Walter et al. 2007) that
+/// absorbs light inside following Beer-Lambert. The absorption is applied
+/// when a ray leaves the object over the distance since it entered, so the
+/// object must be closed with outward facing sides and nothing inside it.
+/// Like `DielectricMat` it leaves out the eta² scaling of radiance crossing
+/// the surface, which cancels for objects lit from outside.
+#[derive(Copy, Clone)]
+pub struct GlassMat {
+    index_refraction: f32,
+    distribution: GGX,
+    /// Fraction of light absorbed per unit distance, per channel
+    absorption: Vec3A
+}
+
+impl GlassMat {
+    /// `roughness` is perceptual, see `microfacet::anisotropic_alphas`.
+    pub fn new(index: f32, roughness: f32, absorption: Vec3A) -> Self {
+        let (alpha_x, alpha_y) = microfacet::anisotropic_alphas(roughness, 0.0);
+        GlassMat {
+            index_refraction: index,
+            distribution: GGX::new(alpha_x, alpha_y),
+            absorption
+        }
+    }
+
+    /// The absorption coefficient that leaves `color` of the light after
+    /// `distance` inside.
+    pub fn absorption_for(color: Vec3A, distance: f32) -> Vec3A {
+        let color = color.max(Vec3A::splat(1e-4)).min(Vec3A::ONE);
+        Vec3A::new(-color.x.ln(), -color.y.ln(), -color.z.ln()) / distance
+    }
+
+    /// Index of refraction behind the surface over the one in front of it.
+    fn eta(&self, record: &HitRecord) -> f32 {
+        if record.front_face { self.index_refraction } else { 1.0 / self.index_refraction }
+    }
+
+    /// What is left of the light that travelled through the glass to reach
+    /// `record`. Camera rays and the directions sampled here have unit
+    /// length, so `t` is the distance travelled.
+    fn transmittance(&self, record: &HitRecord) -> Vec3A {
+        if record.front_face || self.absorption == Vec3A::ZERO {
+            return Vec3A::ONE;
+        }
+        let optical_depth = self.absorption * record.t;
+        Vec3A::new((-optical_depth.x).exp(), (-optical_depth.y).exp(), (-optical_depth.z).exp())
+    }
+}
+
+impl Material for GlassMat {
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z <= 0.0 {
+            return None;
+        }
+        let eta = self.eta(record);
+        let transmittance = self.transmittance(record);
+
+        if self.distribution.is_smooth() {
+            let fresnel = fresnel_dielectric(wo_local.z, eta);
+            let (wi, lobe) = if fresnel > sampler.get_1d() {
+                (vec3_helpers::reflect(-wo, record.normal), LobeFlags::REFLECTION)
+            } else {
+                (vec3_helpers::refract(-wo, record.normal, 1.0 / eta), LobeFlags::TRANSMISSION)
+            };
+            return Some(BSDFSample {
+                wi: wi.normalize(),
+                weight: transmittance,
+                pdf: 0.0,
+                lobe: LobeFlags::SPECULAR | lobe
+            });
+        }
+
+        let (wi_local, lobe) = sample_rough_dielectric(&self.distribution, wo_local, eta, sampler)?;
+        let (f, pdf) = rough_dielectric(&self.distribution, wo_local, wi_local, eta);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BSDFSample {
+            wi: frame.to_world(wi_local),
+            weight: transmittance * f / pdf,
+            pdf,
+            lobe: LobeFlags::GLOSSY | lobe
+        })
+    }
+
+    fn eval(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        if self.distribution.is_smooth() {
+            return Vec3A::ZERO;
+        }
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let (f, _) = rough_dielectric(&self.distribution, frame.to_local(wo), frame.to_local(wi), self.eta(record));
+        self.transmittance(record) * f
+    }
+
+    fn pdf(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        if self.distribution.is_smooth() {
+            return 0.0;
+        }
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let (_, pdf) = rough_dielectric(&self.distribution, frame.to_local(wo), frame.to_local(wi), self.eta(record));
+        pdf
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        let surface = if self.distribution.is_smooth() { LobeFlags::SPECULAR } else { LobeFlags::GLOSSY };
+        surface | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Vec3A {
+        Vec3A::ONE
+    }
+}
+
+/// `1 - cos_theta` to the fifth, how much Schlick's approximation moves
+/// from its value at normal incidence towards one.
+fn schlick_weight(cos_theta: f32) -> f32 {
+    let m = (1.0 - cos_theta).clamp(0.0, 1.0);
+    let m2 = m * m;
+    m2 * m2 * m
+}
+
+fn schlick_fresnel(f0: Vec3A, cos_theta: f32) -> Vec3A {
+    f0 + (Vec3A::ONE - f0) * schlick_weight(cos_theta)
+}
+
+fn constant_texture(value: f32) -> Arc<dyn Texture> {
+    Arc::new(SolidColor{ color: Vec3A::splat(value) })
+}
+
+/// The textures of a `PrincipledMat` looked up at one point.
+struct PrincipledParameters {
+    base_color: Vec3A,
+    metallic: f32,
+    roughness: f32,
+    /// Reflectance of the dielectric at normal incidence
+    specular_f0: f32,
+    sheen: Vec3A,
+    clearcoat: f32,
+    transmission: f32,
+    distribution: GGX,
+    clearcoat_distribution: GGX,
+    eta: f32
+}
+
+/// A layered material in the style of the Disney principled BSDF (Burley
+/// 2012, 2015) that covers most real surfaces with a handful of parameters
+/// in [0, 1], each of which can come from a texture:
+/// - `base_color`: the diffuse colour, or the reflectance of metals
+/// - `metallic`: blends from a dielectric to a conductor
+/// - `roughness`: of the specular and the transmission
+/// - `specular`: the dielectric's reflectance, 0.5 for an index of 1.5
+/// - `sheen` and `sheen_tint`: a soft grazing highlight for cloth
+/// - `clearcoat` and `clearcoat_roughness`: a clear varnish on top
+/// - `transmission`: how much of the dielectric is rough glass instead of
+///   diffuse, with the index of refraction `ior`
+///
+/// Scalar parameters read the first channel of their texture. Directions
+/// are sampled from one lobe, picked by its estimated contribution, and
+/// weighted with the density of all of them.
+pub struct PrincipledMat {
+    base_color: Arc<dyn Texture>,
+    metallic: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>,
+    specular: Arc<dyn Texture>,
+    sheen: Arc<dyn Texture>,
+    sheen_tint: Arc<dyn Texture>,
+    clearcoat: Arc<dyn Texture>,
+    clearcoat_roughness: Arc<dyn Texture>,
+    transmission: Arc<dyn Texture>,
+    index_refraction: f32
+}
+
+impl PrincipledMat {
+    /// A plastic-like dielectric: not metallic, a roughness and specular
+    /// of 0.5, no sheen, clear coat or transmission.
+    pub fn from_shared_texture(base_color: Arc<dyn Texture>) -> Self {
+        PrincipledMat {
+            base_color,
+            metallic: constant_texture(0.0),
+            roughness: constant_texture(0.5),
+            specular: constant_texture(0.5),
+            sheen: constant_texture(0.0),
+            sheen_tint: constant_texture(0.5),
+            clearcoat: constant_texture(0.0),
+            clearcoat_roughness: constant_texture(0.1),
+            transmission: constant_texture(0.0),
+            index_refraction: 1.5
+        }
+    }
+
+    pub fn from_color(base_color: Vec3A) -> Self {
+        PrincipledMat::from_shared_texture(Arc::new(SolidColor{ color: base_color }))
+    }
+
+    pub fn with_metallic(mut self, metallic: Arc<dyn Texture>) -> Self {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: Arc<dyn Texture>) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    pub fn with_specular(mut self, specular: Arc<dyn Texture>) -> Self {
+        self.specular = specular;
+        self
+    }
+
+    pub fn with_sheen(mut self, sheen: Arc<dyn Texture>, tint: Arc<dyn Texture>) -> Self {
+        self.sheen = sheen;
+        self.sheen_tint = tint;
+        self
+    }
+
+    pub fn with_clearcoat(mut self, clearcoat: Arc<dyn Texture>, roughness: Arc<dyn Texture>) -> Self {
+        self.clearcoat = clearcoat;
+        self.clearcoat_roughness = roughness;
+        self
+    }
+
+    pub fn with_transmission(mut self, transmission: Arc<dyn Texture>, index: f32) -> Self {
+        self.transmission = transmission;
+        self.index_refraction = index;
+        self
+    }
+
+    fn parameters(&self, record: &HitRecord) -> PrincipledParameters {
+        let scalar = |texture: &Arc<dyn Texture>| texture.value(record.tex_coords, record.point).x.clamp(0.0, 1.0);
+        let base_color = self.base_color.value(record.tex_coords, record.point).max(Vec3A::ZERO);
+
+        // The sheen takes on the hue of the base colour as it is tinted
+        let base_luminance = luminance(base_color);
+        let tint = if base_luminance > 0.0 { base_color / base_luminance } else { Vec3A::ONE };
+        let sheen = Vec3A::ONE.lerp(tint, scalar(&self.sheen_tint)) * scalar(&self.sheen);
+
+        let roughness = scalar(&self.roughness);
+        let (alpha_x, alpha_y) = microfacet::anisotropic_alphas(roughness, 0.0);
+        let (coat_alpha_x, coat_alpha_y) = microfacet::anisotropic_alphas(scalar(&self.clearcoat_roughness), 0.0);
+        PrincipledParameters {
+            base_color,
+            metallic: scalar(&self.metallic),
+            roughness,
+            specular_f0: 0.08 * scalar(&self.specular),
+            sheen,
+            clearcoat: scalar(&self.clearcoat),
+            transmission: scalar(&self.transmission),
+            distribution: GGX::new(alpha_x, alpha_y),
+            clearcoat_distribution: GGX::new(coat_alpha_x, coat_alpha_y),
+            eta: if record.front_face { self.index_refraction } else { 1.0 / self.index_refraction }
+        }
+    }
+
+    /// How often `sample` picks the diffuse, specular, transmission and
+    /// clear coat lobes, roughly in proportion to what they reflect.
+    fn lobe_probabilities(parameters: &PrincipledParameters, wo: Vec3A) -> [f32; 4] {
+        let p = parameters;
+        let dielectric = (1.0 - p.metallic) * (1.0 - p.transmission);
+        let fresnel = schlick_weight(wo.z);
+        let coat = p.clearcoat * (0.04 + 0.96 * fresnel);
+        let base = 1.0 - coat;
+
+        let specular_color = dielectric * (p.specular_f0 + (1.0 - p.specular_f0) * fresnel)
+            + p.metallic * luminance(schlick_fresnel(p.base_color, wo.z));
+        let weights = [
+            base * dielectric * luminance(p.base_color + p.sheen),
+            base * specular_color,
+            base * (1.0 - p.metallic) * p.transmission,
+            coat
+        ];
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            return [0.0; 4];
+        }
+        weights.map(|w| w / total)
+    }
+
+    /// The BSDF times |cos wi| and the density `sample` picks `wi` with, in
+    /// the local frame.
+    fn eval_local(parameters: &PrincipledParameters, wo: Vec3A, wi: Vec3A) -> (Vec3A, f32) {
+        let p = parameters;
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return (Vec3A::ZERO, 0.0);
+        }
+        let probabilities = PrincipledMat::lobe_probabilities(p, wo);
+        let dielectric = (1.0 - p.metallic) * (1.0 - p.transmission);
+        // Light that the clear coat reflects doesn't reach the layers below
+        let base = 1.0 - p.clearcoat * (0.04 + 0.96 * schlick_weight(wo.z));
+
+        let mut f = Vec3A::ZERO;
+        let mut pdf = 0.0;
+
+        if wi.z > 0.0 {
+            let wm = (wo + wi).normalize();
+            let cos_d = wi.dot(wm);
+
+            // Burley's diffuse with its grazing retro-reflection, less what
+            // the specular reflects on the way in and out, and the sheen
+            let fd90 = 0.5 + 2.0 * p.roughness * cos_d * cos_d;
+            let fd = (1.0 + (fd90 - 1.0) * schlick_weight(wi.z)) * (1.0 + (fd90 - 1.0) * schlick_weight(wo.z));
+            let entering = 1.0 - p.specular_f0 - (1.0 - p.specular_f0) * schlick_weight(wi.z);
+            let leaving = 1.0 - p.specular_f0 - (1.0 - p.specular_f0) * schlick_weight(wo.z);
+            let diffuse = p.base_color * fd * entering * leaving / PI + p.sheen * schlick_weight(cos_d);
+            f += base * dielectric * diffuse * wi.z;
+            pdf += probabilities[0] * wi.z / PI;
+
+            if wo.dot(wm) > 0.0 {
+                let cos_o = wo.dot(wm);
+                let fresnel = dielectric * schlick_fresnel(Vec3A::splat(p.specular_f0), cos_o)
+                    + p.metallic * schlick_fresnel(p.base_color, cos_o);
+                let specular = p.distribution.d(wm) * p.distribution.g(wo, wi) / (4.0 * wo.z);
+                f += base * fresnel * specular;
+                pdf += probabilities[1] * p.distribution.visible_d(wo, wm) / (4.0 * cos_o);
+
+                let coat = p.clearcoat_distribution.d(wm) * p.clearcoat_distribution.g(wo, wi) / (4.0 * wo.z);
+                f += Vec3A::splat(p.clearcoat * (0.04 + 0.96 * schlick_weight(cos_o)) * coat);
+                pdf += probabilities[3] * p.clearcoat_distribution.visible_d(wo, wm) / (4.0 * cos_o);
+            }
+        }
+
+        if probabilities[2] > 0.0 {
+            let (transmitted, transmission_pdf) = rough_dielectric(&p.distribution, wo, wi, p.eta);
+            // Light going through is tinted by the base colour
+            let tint = if wi.z < 0.0 { p.base_color } else { Vec3A::ONE };
+            f += base * (1.0 - p.metallic) * p.transmission * tint * transmitted;
+            pdf += probabilities[2] * transmission_pdf;
+        }
+
+        (f, pdf)
+    }
+}
+
+impl Material for PrincipledMat {
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z <= 0.0 {
+            return None;
+        }
+        let parameters = self.parameters(record);
+        let probabilities = PrincipledMat::lobe_probabilities(&parameters, wo_local);
+
+        let u = sampler.get_1d();
+        let (wi_local, lobe) = if u < probabilities[0] {
+            (vec3_helpers::sample_cosine_direction(Vec3A::Z, sampler.get_2d()), LobeFlags::DIFFUSE | LobeFlags::REFLECTION)
+        } else if u < probabilities[0] + probabilities[1] {
+            let wm = parameters.distribution.sample_visible_normal(wo_local, sampler.get_2d());
+            (vec3_helpers::reflect(-wo_local, wm), LobeFlags::GLOSSY | LobeFlags::REFLECTION)
+        } else if u < probabilities[0] + probabilities[1] + probabilities[2] {
+            let (wi, lobe) = sample_rough_dielectric(&parameters.distribution, wo_local, parameters.eta, sampler)?;
+            (wi, LobeFlags::GLOSSY | lobe)
+        } else {
+            let wm = parameters.clearcoat_distribution.sample_visible_normal(wo_local, sampler.get_2d());
+            (vec3_helpers::reflect(-wo_local, wm), LobeFlags::GLOSSY | LobeFlags::REFLECTION)
+        };
+        if lobe.contains(LobeFlags::REFLECTION) && wi_local.z <= 0.0 {
+            return None;
+        }
+
+        // Weighted against every lobe that could have picked the direction
+        let (f, pdf) = PrincipledMat::eval_local(&parameters, wo_local, wi_local);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BSDFSample {
+            wi: frame.to_world(wi_local),
+            weight: f / pdf,
+            pdf,
+            lobe
+        })
+    }
+
+    fn eval(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let (f, _) = PrincipledMat::eval_local(&self.parameters(record), frame.to_local(wo), frame.to_local(wi));
+        f
+    }
+
+    fn pdf(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let (_, pdf) = PrincipledMat::eval_local(&self.parameters(record), frame.to_local(wo), frame.to_local(wi));
+        pdf
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::GLOSSY | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Vec3A {
+        self.base_color.value(record.tex_coords, record.point).clamp(Vec3A::ZERO, Vec3A::ONE)
+    }
+}
+
+/// Chooses between two materials with a mask in [0, 1], read from the first
+/// channel of a texture: 0 is all `first`, 1 all `second` and values in
+/// between blend them. Each sample comes from one of the two, picked with
+/// the probability of its share, and is weighted with the blend of both.
+pub struct MixMaterial {
+    first: Arc<dyn Material>,
+    second: Arc<dyn Material>,
+    mask: Arc<dyn Texture>
+}
+
+impl MixMaterial {
+    pub fn new(first: Arc<dyn Material>, second: Arc<dyn Material>, mask: Arc<dyn Texture>) -> Self {
+        MixMaterial {
+            first,
+            second,
+            mask
+        }
+    }
+
+    /// The share of `second` at a point.
+    fn mask(&self, tex_coords: (f32, f32), point: Vec3A) -> f32 {
+        self.mask.value(tex_coords, point).x.clamp(0.0, 1.0)
+    }
+
+    /// Weights a sample of one of the materials with both. A specular sample
+    /// only comes from the material that picked it, so its weight stands.
+    fn blend_sample(&self, wo: Vec3A, record: &HitRecord, mask: f32, sample: BSDFSample) -> Option<BSDFSample> {
+        if sample.lobe.is_specular() {
+            return Some(sample);
+        }
+        let f = (1.0 - mask) * self.first.eval(wo, sample.wi, record) + mask * self.second.eval(wo, sample.wi, record);
+        let pdf = (1.0 - mask) * self.first.pdf(wo, sample.wi, record) + mask * self.second.pdf(wo, sample.wi, record);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BSDFSample {
+            wi: sample.wi,
+            weight: f / pdf,
+            pdf,
+            lobe: sample.lobe
+        })
+    }
 
-        if cannot_refract || self.reflectance(cos_theta, refraction_ratio) > rand::random() {
-            direction = vec3_helpers::reflect(unit_direction, record.normal);
+    fn choose(&self, record: &HitRecord, sampler: &mut dyn Sampler) -> (&dyn Material, f32) {
+        let mask = self.mask(record.tex_coords, record.point);
+        if sampler.get_1d() < mask {
+            (self.second.as_ref(), mask)
         } else {
-            direction = vec3_helpers::refract(unit_direction, record.normal, refraction_ratio);
+            (self.first.as_ref(), mask)
+        }
+    }
+}
+
+impl Material for MixMaterial {
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let (material, mask) = self.choose(record, sampler);
+        let sample = material.sample(wo, record, sampler)?;
+        self.blend_sample(wo, record, mask, sample)
+    }
+
+    fn sample_wavelength(&self, wo: Vec3A, record: &HitRecord, wavelength: f32, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let (material, mask) = self.choose(record, sampler);
+        let sample = material.sample_wavelength(wo, record, wavelength, sampler)?;
+        self.blend_sample(wo, record, mask, sample)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.first.is_dispersive() || self.second.is_dispersive()
+    }
+
+    fn eval(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        let mask = self.mask(record.tex_coords, record.point);
+        (1.0 - mask) * self.first.eval(wo, wi, record) + mask * self.second.eval(wo, wi, record)
+    }
+
+    fn pdf(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        let mask = self.mask(record.tex_coords, record.point);
+        (1.0 - mask) * self.first.pdf(wo, wi, record) + mask * self.second.pdf(wo, wi, record)
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        self.first.lobes() | self.second.lobes()
+    }
+
+    fn emitted(&self, tex_coords: (f32, f32), point: Vec3A) -> Vec3A {
+        let mask = self.mask(tex_coords, point);
+        (1.0 - mask) * self.first.emitted(tex_coords, point) + mask * self.second.emitted(tex_coords, point)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.first.is_emissive() || self.second.is_emissive()
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Vec3A {
+        let mask = self.mask(record.tex_coords, record.point);
+        (1.0 - mask) * self.first.albedo(record) + mask * self.second.albedo(record)
+    }
+}
+
+/// A clear dielectric layer over another material, like varnish or lacquer.
+/// The coat reflects what its Fresnel factor says, smooth or rough, and the
+/// base gets the light that made it through the coat on the way in and on
+/// the way out, less what the coat's colour absorbs. Light bouncing between
+/// the base and the underside of the coat is ignored.
+pub struct CoatedMat {
+    base: Arc<dyn Material>,
+    index_refraction: f32,
+    distribution: GGX,
+    /// What is left of the light after going through the coat and back
+    /// straight on
+    color: Vec3A
+}
+
+impl CoatedMat {
+    /// `roughness` is perceptual, see `microfacet::anisotropic_alphas`.
+    pub fn new(base: Arc<dyn Material>, index: f32, roughness: f32) -> Self {
+        let (alpha_x, alpha_y) = microfacet::anisotropic_alphas(roughness, 0.0);
+        CoatedMat {
+            base,
+            index_refraction: index,
+            distribution: GGX::new(alpha_x, alpha_y),
+            color: Vec3A::ONE
+        }
+    }
+
+    pub fn with_color(mut self, color: Vec3A) -> Self {
+        self.color = color.clamp(Vec3A::ZERO, Vec3A::ONE);
+        self
+    }
+
+    /// Cosine of a direction with the normal once it has refracted into the coat.
+    fn refracted_cos(&self, cos_theta: f32) -> f32 {
+        let sin2_t = (1.0 - cos_theta * cos_theta) / (self.index_refraction * self.index_refraction);
+        f32::sqrt(f32::max(1.0 - sin2_t, 0.0))
+    }
+
+    /// How much of the light the base sends from `wi` towards `wo` leaves
+    /// the coat, `cos_i` and `cos_o` are local and positive.
+    fn base_factor(&self, cos_o: f32, cos_i: f32) -> Vec3A {
+        let fresnel = (1.0 - fresnel_dielectric(cos_o, self.index_refraction)) * (1.0 - fresnel_dielectric(cos_i, self.index_refraction));
+        if self.color == Vec3A::ONE {
+            return Vec3A::splat(fresnel);
+        }
+        // `color` is for a path straight down and back up
+        let path = 0.5 * (1.0 / self.refracted_cos(cos_o) + 1.0 / self.refracted_cos(cos_i));
+        let tint = Vec3A::new(self.color.x.powf(path), self.color.y.powf(path), self.color.z.powf(path));
+        fresnel * tint
+    }
+
+    /// Probability of sampling the coat instead of the base.
+    fn coat_probability(&self, cos_o: f32, record: &HitRecord) -> f32 {
+        let fresnel = fresnel_dielectric(cos_o, self.index_refraction);
+        let base = (1.0 - fresnel) * luminance(self.base.albedo(record) * self.color);
+        (fresnel / (fresnel + base).max(1e-4)).clamp(0.1, 0.9)
+    }
+
+    /// The rough coat's reflection times cos wi and its density, in the
+    /// local frame.
+    fn eval_coat(&self, wo: Vec3A, wi: Vec3A) -> (f32, f32) {
+        if self.distribution.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let wm = (wo + wi).normalize();
+        let fresnel = fresnel_dielectric(wo.dot(wm), self.index_refraction);
+        let f = fresnel * self.distribution.d(wm) * self.distribution.g(wo, wi) / (4.0 * wo.z);
+        let pdf = self.distribution.visible_d(wo, wm) / (4.0 * wo.dot(wm));
+        (f, pdf)
+    }
+
+    /// `eval` and `pdf` together, given the coat probability.
+    fn eval_both(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord, coat_probability: f32) -> (Vec3A, f32) {
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let wo_local = frame.to_local(wo);
+        let wi_local = frame.to_local(wi);
+        let (coat, coat_pdf) = self.eval_coat(wo_local, wi_local);
+        let base = self.base_factor(wo_local.z, wi_local.z.abs()) * self.base.eval(wo, wi, record);
+        let pdf = coat_probability * coat_pdf + (1.0 - coat_probability) * self.base.pdf(wo, wi, record);
+        (Vec3A::splat(coat) + base, pdf)
+    }
+
+    fn sample_with<F>(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler, sample_base: F) -> Option<BSDFSample>
+        where F: FnOnce(&mut dyn Sampler) -> Option<BSDFSample> {
+        let frame = ShadingFrame::from_tangent(record.normal, record.tangent);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z <= 0.0 {
+            return None;
+        }
+        let coat_probability = self.coat_probability(wo_local.z, record);
+
+        if sampler.get_1d() < coat_probability {
+            if self.distribution.is_smooth() {
+                return Some(BSDFSample {
+                    wi: vec3_helpers::reflect(-wo, record.normal),
+                    weight: Vec3A::splat(fresnel_dielectric(wo_local.z, self.index_refraction) / coat_probability),
+                    pdf: 0.0,
+                    lobe: LobeFlags::SPECULAR | LobeFlags::REFLECTION
+                });
+            }
+            let wm = self.distribution.sample_visible_normal(wo_local, sampler.get_2d());
+            let wi_local = vec3_helpers::reflect(-wo_local, wm);
+            if wi_local.z <= 0.0 {
+                return None;
+            }
+            let wi = frame.to_world(wi_local);
+            let (f, pdf) = self.eval_both(wo, wi, record, coat_probability);
+            if pdf <= 0.0 {
+                return None;
+            }
+            return Some(BSDFSample {
+                wi,
+                weight: f / pdf,
+                pdf,
+                lobe: LobeFlags::GLOSSY | LobeFlags::REFLECTION
+            });
+        }
+
+        let sample = sample_base(sampler)?;
+        if sample.lobe.is_specular() {
+            let cos_i = frame.to_local(sample.wi).z.abs();
+            return Some(BSDFSample {
+                wi: sample.wi,
+                weight: sample.weight * self.base_factor(wo_local.z, cos_i) / (1.0 - coat_probability),
+                pdf: 0.0,
+                lobe: sample.lobe
+            });
+        }
+        let (f, pdf) = self.eval_both(wo, sample.wi, record, coat_probability);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BSDFSample {
+            wi: sample.wi,
+            weight: f / pdf,
+            pdf,
+            lobe: sample.lobe
+        })
+    }
+}
+
+impl Material for CoatedMat {
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        // Seen from inside there is no coat in the way
+        if !record.front_face {
+            return self.base.sample(wo, record, sampler);
+        }
+        let base = &self.base;
+        self.sample_with(wo, record, sampler, |sampler| base.sample(wo, record, sampler))
+    }
+
+    fn sample_wavelength(&self, wo: Vec3A, record: &HitRecord, wavelength: f32, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        if !record.front_face {
+            return self.base.sample_wavelength(wo, record, wavelength, sampler);
+        }
+        let base = &self.base;
+        self.sample_with(wo, record, sampler, |sampler| base.sample_wavelength(wo, record, wavelength, sampler))
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+
+    fn eval(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        if !record.front_face {
+            return self.base.eval(wo, wi, record);
+        }
+        let coat_probability = self.coat_probability(record.normal.dot(wo), record);
+        self.eval_both(wo, wi, record, coat_probability).0
+    }
+
+    fn pdf(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        if !record.front_face {
+            return self.base.pdf(wo, wi, record);
+        }
+        let coat_probability = self.coat_probability(record.normal.dot(wo), record);
+        self.eval_both(wo, wi, record, coat_probability).1
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        let coat = if self.distribution.is_smooth() { LobeFlags::SPECULAR } else { LobeFlags::GLOSSY };
+        self.base.lobes() | coat | LobeFlags::REFLECTION
+    }
+
+    fn emitted(&self, tex_coords: (f32, f32), point: Vec3A) -> Vec3A {
+        self.base.emitted(tex_coords, point)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.base.is_emissive()
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Vec3A {
+        self.base.albedo(record) * self.color
+    }
+}
+
+/// Where the shading normal of a `PerturbedMat` comes from.
+pub enum NormalPerturbation {
+    /// A tangent space normal map: RGB in [0, 1] is XYZ in [-1, 1] with Z
+    /// along the normal and Y along increasing v. `strength` scales the tilt.
+    NormalMap { texture: Arc<dyn Texture>, strength: f32 },
+    /// Bumps `scale` times the first channel of `height` high
+    Bump { height: Arc<dyn Texture>, scale: f32 }
+}
+
+/// Another material shaded with a normal from a normal map or a bump map,
+/// for detail that isn't in the geometry. Directions the new normal puts on
+/// the other side of the surface than the real one does are dropped, so no
+/// light leaks through it.
+pub struct PerturbedMat {
+    base: Arc<dyn Material>,
+    perturbation: NormalPerturbation
+}
+
+impl PerturbedMat {
+    pub fn from_normal_map(base: Arc<dyn Material>, texture: Arc<dyn Texture>, strength: f32) -> Self {
+        PerturbedMat {
+            base,
+            perturbation: NormalPerturbation::NormalMap { texture, strength }
+        }
+    }
+
+    pub fn from_bump_map(base: Arc<dyn Material>, height: Arc<dyn Texture>, scale: f32) -> Self {
+        PerturbedMat {
+            base,
+            perturbation: NormalPerturbation::Bump { height, scale }
         }
+    }
 
-        *scattered = Ray{ 
-            origin: record.point, 
-            direction: direction,
-            time: ray.time
+    fn perturbed_normal(&self, record: &HitRecord) -> Vec3A {
+        let normal = record.normal;
+        let perturbed = match &self.perturbation {
+            NormalPerturbation::NormalMap { texture, strength } => {
+                let color = texture.value(record.tex_coords, record.point);
+                let local = Vec3A::new((2.0 * color.x - 1.0) * strength, (2.0 * color.y - 1.0) * strength, f32::max(2.0 * color.z - 1.0, 1e-3));
+                let frame = ShadingFrame::from_tangent(normal, record.tangent);
+                // The frame's bitangent may point along decreasing v
+                let handedness = if frame.bitangent.dot(record.bitangent) < 0.0 { -1.0 } else { 1.0 };
+                frame.tangent * local.x + frame.bitangent * handedness * local.y + frame.normal * local.z
+            },
+            NormalPerturbation::Bump { height, scale } => {
+                // Finite differences of the displaced surface along u and v
+                let delta = 5e-4;
+                let (u, v) = record.tex_coords;
+                let height_at = |coords: (f32, f32), point: Vec3A| height.value(coords, point).x * scale;
+                let base_height = height_at(record.tex_coords, record.point);
+                let du = (height_at((u + delta, v), record.point + delta * record.tangent) - base_height) / delta;
+                let dv = (height_at((u, v + delta), record.point + delta * record.bitangent) - base_height) / delta;
+                let bumped = (record.tangent + du * normal).cross(record.bitangent + dv * normal);
+                if bumped.dot(normal) < 0.0 { -bumped } else { bumped }
+            }
         };
-        return true;
+        let perturbed = perturbed.normalize();
+        if perturbed.is_finite() { perturbed } else { normal }
+    }
+
+    /// `record` with the perturbed normal, unless it faces away from `wo` or
+    /// points into the actual surface.
+    fn shading_record(&self, wo: Vec3A, record: &HitRecord) -> HitRecord {
+        let mut shading = record.clone();
+        let normal = self.perturbed_normal(record);
+        if normal.dot(wo) > 0.0 && normal.dot(record.geometric_normal) > 0.0 {
+            shading.normal = normal;
+        }
+        shading
+    }
+
+    fn keep_above(shading: &HitRecord, sample: BSDFSample) -> Option<BSDFSample> {
+        if shading.crosses_surface(sample.wi) {
+            return None;
+        }
+        Some(sample)
+    }
+}
+
+impl Material for PerturbedMat {
+    fn sample(&self, wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let shading = self.shading_record(wo, record);
+        let sample = self.base.sample(wo, &shading, sampler)?;
+        PerturbedMat::keep_above(&shading, sample)
+    }
+
+    fn sample_wavelength(&self, wo: Vec3A, record: &HitRecord, wavelength: f32, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        let shading = self.shading_record(wo, record);
+        let sample = self.base.sample_wavelength(wo, &shading, wavelength, sampler)?;
+        PerturbedMat::keep_above(&shading, sample)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.base.is_dispersive()
+    }
+
+    fn eval(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> Vec3A {
+        let shading = self.shading_record(wo, record);
+        if shading.crosses_surface(wi) {
+            return Vec3A::ZERO;
+        }
+        self.base.eval(wo, wi, &shading)
+    }
+
+    fn pdf(&self, wo: Vec3A, wi: Vec3A, record: &HitRecord) -> f32 {
+        let shading = self.shading_record(wo, record);
+        if shading.crosses_surface(wi) {
+            return 0.0;
+        }
+        self.base.pdf(wo, wi, &shading)
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        self.base.lobes()
+    }
+
+    fn emitted(&self, tex_coords: (f32, f32), point: Vec3A) -> Vec3A {
+        self.base.emitted(tex_coords, point)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.base.is_emissive()
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Vec3A {
+        self.base.albedo(record)
     }
 }
 
@@ -152,9 +1411,12 @@ pub struct NoMaterial {
 }
 
 impl Material for NoMaterial {
-    fn scatter(&self, _ray: &Ray, _record: &HitRecord, _attentuation: &mut Vec3A, _scattered: &mut Ray) -> bool {
-        println!("No Material");
-        return false;
+    fn sample(&self, _wo: Vec3A, _record: &HitRecord, _sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        None
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        LobeFlags::NONE
     }
 }
 
@@ -168,19 +1430,38 @@ impl DiffuseLight {
             emit: Arc::new(texture)
         }
     }
+    pub fn from_shared_texture(texture: Arc<dyn Texture>) -> Self {
+        DiffuseLight {
+            emit: texture
+        }
+    }
+
     pub fn from_color(color: Vec3A) -> Self {
-        DiffuseLight::from_texture(SolidColor{color:color})
+        DiffuseLight::from_texture(SolidColor{color})
     }
 }
 
 impl Material for DiffuseLight {
-    fn scatter(&self, _ray: &Ray, _record: &HitRecord, _attentuation: &mut Vec3A, _scattered: &mut Ray) -> bool {
-        return false;
+    fn sample(&self, _wo: Vec3A, _record: &HitRecord, _sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        None
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        LobeFlags::NONE
     }
 
     fn emitted(&self, tex_coords: (f32, f32), point: Vec3A) -> Vec3A {
         self.emit.value(tex_coords, point)
     }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    /// Lights have no reflectance, their colour stands in for it
+    fn albedo(&self, record: &HitRecord) -> Vec3A {
+        self.emit.value(record.tex_coords, record.point).min(Vec3A::ONE)
+    }
 }
 
 pub struct IsotropicMat {
@@ -194,6 +1475,12 @@ impl IsotropicMat {
         }
     }
 
+    pub fn from_shared_texture(texture: Arc<dyn Texture>) -> Self {
+        Self {
+            albedo: texture
+        }
+    }
+
     pub fn from_color(texture: Vec3A) -> Self {
         Self {
             albedo: Arc::new(SolidColor{color: texture})
@@ -201,15 +1488,277 @@ impl IsotropicMat {
     }
 }
 
+/// Scatters equally in every direction; the phase function takes the place
+/// of the BSDF and has no cosine term.
 impl Material for IsotropicMat {
-    fn scatter(&self, _ray: &Ray, _record: &HitRecord, _attentuation: &mut Vec3A, _scattered: &mut Ray) -> bool {
-        *_scattered = Ray{
-            direction: vec3_helpers::random_in_unit_sphere().normalize(),
-            origin: _record.point,
-            time: _ray.time
+    fn sample(&self, _wo: Vec3A, record: &HitRecord, sampler: &mut dyn Sampler) -> Option<BSDFSample> {
+        Some(BSDFSample {
+            wi: vec3_helpers::sample_sphere(sampler.get_2d()),
+            weight: self.albedo.value(record.tex_coords, record.point),
+            pdf: 1.0 / (4.0 * std::f32::consts::PI),
+            lobe: LobeFlags::DIFFUSE | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+        })
+    }
+
+    fn eval(&self, _wo: Vec3A, _wi: Vec3A, record: &HitRecord) -> Vec3A {
+        self.albedo.value(record.tex_coords, record.point) / (4.0 * std::f32::consts::PI)
+    }
+
+    fn pdf(&self, _wo: Vec3A, _wi: Vec3A, _record: &HitRecord) -> f32 {
+        1.0 / (4.0 * std::f32::consts::PI)
+    }
+
+    fn lobes(&self) -> LobeFlags {
+        LobeFlags::DIFFUSE | LobeFlags::REFLECTION | LobeFlags::TRANSMISSION
+    }
+
+    fn albedo(&self, record: &HitRecord) -> Vec3A {
+        self.albedo.value(record.tex_coords, record.point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Pcg32;
+    use crate::sampler::{IndependentSampler, SamplerType};
+
+    /// A hit at the origin of a surface facing +z, with u along +x and v
+    /// along +y.
+    fn record_for(material: Arc<dyn Material>, front_face: bool) -> HitRecord {
+        HitRecord {
+            point: Vec3A::ZERO,
+            normal: Vec3A::Z,
+            geometric_normal: Vec3A::Z,
+            tangent: Vec3A::X,
+            bitangent: Vec3A::Y,
+            t: 1.0,
+            tex_coords: (0.3, 0.6),
+            material,
+            front_face,
+            object_id: 0
+        }
+    }
+
+    /// A height rising along u and v.
+    struct Slope;
+
+    impl Texture for Slope {
+        fn value(&self, coords: (f32, f32), _point: Vec3A) -> Vec3A {
+            Vec3A::splat(coords.0 + 0.5 * coords.1)
+        }
+    }
+
+    /// Directions to look at a surface facing +z from, head on to grazing.
+    fn outgoing_directions() -> Vec<Vec3A> {
+        [1.0f32, 0.8, 0.5, 0.2, 0.05].iter().enumerate().map(|(i, cos_theta)| {
+            let sin_theta = f32::sqrt(1.0 - cos_theta * cos_theta);
+            let phi = 0.7 + 1.3 * i as f32;
+            Vec3A::new(sin_theta * phi.cos(), sin_theta * phi.sin(), *cos_theta)
+        }).collect()
+    }
+
+    /// Picks half the directions uniformly and the rest from cosine power
+    /// lobes of several widths around `axes`, so narrow lobes are found as
+    /// well as wide ones. Returns the direction and its density.
+    fn sample_probe(axes: &[Vec3A], u: Vec2, choice: f32) -> (Vec3A, f32) {
+        const EXPONENTS: [f32; 5] = [1.0, 8.0, 64.0, 512.0, 4096.0];
+        let lobe_count = axes.len() * EXPONENTS.len();
+        let wi = if choice < 0.5 {
+            vec3_helpers::sample_sphere(u)
+        } else {
+            let index = ((2.0 * choice - 1.0) * lobe_count as f32) as usize % lobe_count;
+            let exponent = EXPONENTS[index % EXPONENTS.len()];
+            let cos_theta = u.x.powf(1.0 / (exponent + 1.0));
+            let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+            let phi = 2.0 * PI * u.y;
+            ShadingFrame::from_normal(axes[index / EXPONENTS.len()])
+                .to_world(Vec3A::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
         };
-        *_attentuation = self.albedo.value(_record.tex_coords, _record.point);
 
-        return true;
+        let mut lobes = 0.0;
+        for axis in axes {
+            let cos_theta = wi.dot(*axis).max(0.0);
+            for exponent in EXPONENTS.iter() {
+                lobes += (exponent + 1.0) / (2.0 * PI) * cos_theta.powf(*exponent);
+            }
+        }
+        (wi, 0.5 / (4.0 * PI) + 0.5 * lobes / lobe_count as f32)
+    }
+
+    /// Sampled directions must be weighted by `eval / pdf` and have the
+    /// density `pdf` reports.
+    fn assert_sample_weights_match(material: &dyn Material, record: &HitRecord) {
+        let mut sampler = IndependentSampler::new(1);
+        for wo in outgoing_directions() {
+            for _ in 0..200 {
+                let sample = match material.sample(wo, record, &mut sampler) {
+                    Some(sample) if !sample.lobe.is_specular() => sample,
+                    _ => continue
+                };
+                let f = material.eval(wo, sample.wi, record);
+                let pdf = material.pdf(wo, sample.wi, record);
+                assert!(sample.pdf > 0.0 && sample.weight.is_finite(), "{:?} from {:?}", sample, wo);
+                assert!((pdf - sample.pdf).abs() <= 1e-3 * pdf, "pdf {} for {:?} from {:?}", pdf, sample, wo);
+                let expected = f / sample.pdf;
+                assert!((sample.weight - expected).abs().max_element() <= 1e-3 * expected.max_element().max(1.0),
+                    "eval / pdf {:?} for {:?} from {:?}", expected, sample, wo);
+            }
+        }
+    }
+
+    /// `pdf` over the whole sphere of directions must not exceed one,
+    /// specular lobes and absorbed samples make up the rest.
+    fn assert_pdf_integrates_to_at_most_one(material: &dyn Material, record: &HitRecord) {
+        let mut sampler = IndependentSampler::new(2);
+        let count = 20000;
+        for wo in outgoing_directions() {
+            // Probe around the mirror and refracted directions and where the
+            // material sends light, picked up front so the estimate stays
+            // unbiased whatever the lobes look like
+            let mut axes = vec![
+                vec3_helpers::reflect(-wo, record.normal),
+                vec3_helpers::refract(-wo, record.normal, 1.0 / 1.5).normalize(),
+                vec3_helpers::refract(-wo, record.normal, 1.5).normalize()
+            ];
+            for _ in 0..100 {
+                if let Some(sample) = material.sample(wo, record, &mut sampler) {
+                    if !sample.lobe.is_specular() && axes.len() < 8 {
+                        axes.push(sample.wi);
+                    }
+                }
+            }
+            // A low discrepancy sequence keeps the estimate's noise down
+            let mut probe = SamplerType::Halton.create(2, count);
+            let mut integral = 0.0;
+            for i in 0..count {
+                probe.start_sample(0, i);
+                let (wi, probe_pdf) = sample_probe(&axes, probe.get_2d(), probe.get_1d());
+                integral += material.pdf(wo, wi, record) / probe_pdf;
+            }
+            integral /= count as f32;
+            assert!(integral <= 1.02, "pdf integrates to {} from {:?}", integral, wo);
+        }
+    }
+
+    /// A white furnace: lit evenly from every direction a surface can't send
+    /// back more light than it gets.
+    fn assert_albedo_at_most_one(material: &dyn Material, record: &HitRecord) {
+        let mut sampler = IndependentSampler::new(3);
+        let count = 5000;
+        for wo in outgoing_directions() {
+            let mut albedo = Vec3A::ZERO;
+            for _ in 0..count {
+                if let Some(sample) = material.sample(wo, record, &mut sampler) {
+                    albedo += sample.weight;
+                }
+            }
+            albedo /= count as f32;
+            assert!(albedo.max_element() <= 1.02, "albedo {:?} from {:?}", albedo, wo);
+        }
+    }
+
+    fn assert_consistent(material: Arc<dyn Material>) {
+        for front_face in [true, false].iter() {
+            let record = record_for(material.clone(), *front_face);
+            assert_sample_weights_match(material.as_ref(), &record);
+            assert_pdf_integrates_to_at_most_one(material.as_ref(), &record);
+            assert_albedo_at_most_one(material.as_ref(), &record);
+        }
+    }
+
+    #[test]
+    fn metals_are_consistent() {
+        for fuzz in [0.05, 0.3, 1.0].iter() {
+            assert_consistent(Arc::new(MetalMat::new(Vec3A::new(0.9, 0.8, 0.5), *fuzz)));
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn conductors_are_consistent() {
+        for (roughness, anisotropy) in [(0.3, 0.0), (0.6, 0.8)].iter() {
+            assert_consistent(Arc::new(ConductorMat::from_preset(ConductorPreset::Silver, *roughness, *anisotropy)));
+            // A perfect mirror at every angle, anything lost is up to the microfacets
+            assert_consistent(Arc::new(ConductorMat::new_anisotropic(Vec3A::ONE, Vec3A::splat(1e4), *roughness, *anisotropy)));
+        }
+    }
+
+    #[test]
+    fn glass_is_consistent() {
+        for roughness in [0.0, 0.3, 0.7].iter() {
+            assert_consistent(Arc::new(GlassMat::new(1.5, *roughness, Vec3A::ZERO)));
+        }
+    }
+
+    #[test]
+    fn principled_is_consistent() {
+        let principled = || PrincipledMat::from_color(Vec3A::ONE).with_roughness(constant_texture(0.4));
+        assert_consistent(Arc::new(principled()));
+        assert_consistent(Arc::new(principled().with_metallic(constant_texture(0.5))));
+        assert_consistent(Arc::new(principled().with_sheen(constant_texture(1.0), constant_texture(0.5))));
+        assert_consistent(Arc::new(principled().with_clearcoat(constant_texture(1.0), constant_texture(0.2))));
+        assert_consistent(Arc::new(principled().with_transmission(constant_texture(0.5), 1.5)));
+    }
+
+    #[test]
+    fn mixed_materials_are_consistent() {
+        let white: Arc<dyn Material> = Arc::new(LambertianMat::from_color(Vec3A::ONE));
+        let mirror: Arc<dyn Material> = Arc::new(ConductorMat::new(Vec3A::ONE, Vec3A::splat(1e4), 0.3));
+        let glass: Arc<dyn Material> = Arc::new(GlassMat::new(1.5, 0.0, Vec3A::ZERO));
+        assert_consistent(Arc::new(MixMaterial::new(white.clone(), mirror.clone(), constant_texture(0.5))));
+        assert_consistent(Arc::new(MixMaterial::new(white.clone(), glass, constant_texture(0.3))));
+        assert_consistent(Arc::new(MixMaterial::new(mirror, white, constant_texture(1.0))));
+    }
+
+    #[test]
+    fn coated_materials_are_consistent() {
+        let white: Arc<dyn Material> = Arc::new(LambertianMat::from_color(Vec3A::ONE));
+        let mirror: Arc<dyn Material> = Arc::new(ConductorMat::new(Vec3A::ONE, Vec3A::splat(1e4), 0.5));
+        for roughness in [0.0, 0.3].iter() {
+            assert_consistent(Arc::new(CoatedMat::new(white.clone(), 1.5, *roughness)));
+            assert_consistent(Arc::new(CoatedMat::new(mirror.clone(), 1.5, *roughness).with_color(Vec3A::new(0.8, 0.9, 0.7))));
+        }
+    }
+
+    #[test]
+    fn perturbed_normals_stay_above_the_surface() {
+        let white: Arc<dyn Material> = Arc::new(LambertianMat::from_color(Vec3A::ONE));
+        let mut materials = Vec::new();
+        for color in [Vec3A::new(0.5, 0.5, 1.0), Vec3A::new(1.0, 0.0, 0.5), Vec3A::new(0.0, 1.0, 0.0), Vec3A::ZERO].iter() {
+            for strength in [1.0, 4.0].iter() {
+                let texture = Arc::new(SolidColor{ color: *color });
+                materials.push(Arc::new(PerturbedMat::from_normal_map(white.clone(), texture, *strength)));
+            }
+        }
+        let dents = Arc::new(NoiseTexture::new(8.0, &mut Pcg32::new(5, 0)));
+        for scale in [0.1, 10.0, -10.0].iter() {
+            materials.push(Arc::new(PerturbedMat::from_bump_map(white.clone(), dents.clone(), *scale)));
+        }
+
+        let mut sampler = IndependentSampler::new(4);
+        for material in materials.iter() {
+            for tilt in [0.0, 0.5].iter() {
+                // Interpolated normals may lean away from the geometric one
+                let mut record = record_for(material.clone(), true);
+                record.normal = Vec3A::new(*tilt, 0.0, 1.0).normalize();
+                for wo in outgoing_directions() {
+                    let normal = material.shading_record(wo, &record).normal;
+                    assert!(normal.dot(record.geometric_normal) > 0.0, "{:?} from {:?}", normal, wo);
+                    for _ in 0..100 {
+                        if let Some(sample) = material.sample(wo, &record, &mut sampler) {
+                            assert!(sample.wi.dot(record.geometric_normal) > 0.0, "{:?} from {:?}", sample, wo);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn perturbed_materials_are_consistent() {
+        let base: Arc<dyn Material> = Arc::new(ConductorMat::from_preset(ConductorPreset::Gold, 0.4, 0.0));
+        let tilted = Arc::new(SolidColor{ color: Vec3A::new(0.8, 0.4, 0.7) });
+        assert_consistent(Arc::new(PerturbedMat::from_normal_map(base.clone(), tilted, 1.0)));
+        assert_consistent(Arc::new(PerturbedMat::from_bump_map(base, Arc::new(Slope), 1.0)));
+    }
+}
@@ -0,0 +1,264 @@
+extern crate glam;
+extern crate image;
+
+use glam::*;
+
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hit_record::HitRecord;
+use crate::sampler::Sampler;
+use crate::shapes::{Hittable, Triangle};
+use crate::materials::*;
+use crate::texture::*;
+use crate::flat_bvh::FlatBVH;
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum MeshError {
+    Obj(tobj::LoadError),
+    Texture(String, image::ImageError),
+    Empty
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::Obj(e) => write!(f, "could not read OBJ file: {}", e),
+            MeshError::Texture(path, e) => write!(f, "could not load texture '{}': {}", path, e),
+            MeshError::Empty => write!(f, "the mesh has no triangles")
+        }
+    }
+}
+
+/// A triangle mesh with its own BVH, so it can be placed, transformed and
+/// used as a medium boundary like any other shape.
+pub struct Mesh {
+    bvh: FlatBVH,
+    triangle_count: usize
+}
+
+impl Mesh {
+    pub fn from_triangles(triangles: Vec<Triangle>) -> Result<Self, MeshError> {
+        if triangles.is_empty() {
+            return Err(MeshError::Empty);
+        }
+
+        let triangle_count = triangles.len();
+        let objects = triangles.into_iter()
+            .map(|t| Arc::new(t) as Arc<dyn Hittable>)
+            .collect();
+
+        Ok(Mesh {
+            bvh: FlatBVH::from_vector(objects, 0.0, 1.0),
+            triangle_count
+        })
+    }
+
+    /// Loads a Wavefront OBJ file and the MTL libraries it references.
+    /// Faces without a material, or every face if `override_material` is
+    /// given, use that material (falling back to a light grey Lambertian).
+    pub fn load_obj<P: AsRef<Path>>(path: P, override_material: Option<Arc<dyn Material>>) -> Result<Self, MeshError> {
+        let path = path.as_ref();
+        let (models, mtl_result) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS).map_err(MeshError::Obj)?;
+
+        let default_material: Arc<dyn Material> = match &override_material {
+            Some(material) => material.clone(),
+            None => Arc::new(LambertianMat::from_color(Vec3A::new(0.73, 0.73, 0.73)))
+        };
+
+        let materials = match (&override_material, mtl_result) {
+            (Some(_), _) => Vec::new(),
+            (None, Ok(mtl_materials)) => {
+                let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+                mtl_materials.iter()
+                    .map(|m| convert_material(m, base_dir))
+                    .collect::<Result<Vec<Arc<dyn Material>>, MeshError>>()?
+            },
+            (None, Err(e)) => {
+                eprintln!("warning: {}: could not read MTL file ({}), using the default material", path.display(), e);
+                Vec::new()
+            }
+        };
+
+        let mut triangles = Vec::new();
+
+        for model in models.iter() {
+            let mesh = &model.mesh;
+            let material = mesh.material_id
+                .and_then(|id| materials.get(id).cloned())
+                .unwrap_or_else(|| default_material.clone());
+
+            let position = |i: u32| {
+                let i = i as usize * 3;
+                Vec3A::new(mesh.positions[i], mesh.positions[i + 1], mesh.positions[i + 2])
+            };
+            let normal = |i: u32| {
+                let i = i as usize * 3;
+                Vec3A::new(mesh.normals[i], mesh.normals[i + 1], mesh.normals[i + 2])
+            };
+            let tex_coord = |i: u32| {
+                let i = i as usize * 2;
+                (mesh.texcoords[i], mesh.texcoords[i + 1])
+            };
+
+            for face in mesh.indices.chunks_exact(3) {
+                let vertices = [position(face[0]), position(face[1]), position(face[2])];
+
+                // Skip degenerate faces, they can never be hit
+                if (vertices[1] - vertices[0]).cross(vertices[2] - vertices[0]).length_squared() == 0.0 {
+                    continue;
+                }
+
+                let normals = if mesh.normals.is_empty() {
+                    None
+                } else {
+                    Some([normal(face[0]), normal(face[1]), normal(face[2])])
+                };
+
+                let tex_coords = if mesh.texcoords.is_empty() {
+                    None
+                } else {
+                    Some([tex_coord(face[0]), tex_coord(face[1]), tex_coord(face[2])])
+                };
+
+                triangles.push(Triangle::new_with_material(vertices, normals, tex_coords, material.clone()));
+            }
+        }
+
+        Mesh::from_triangles(triangles)
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangle_count
+    }
+}
+
+fn max_component(c: Option<[f32; 3]>) -> f32 {
+    c.map(|c| c[0].max(c[1]).max(c[2])).unwrap_or(0.0)
+}
+
+fn to_color(c: [f32; 3]) -> Vec3A {
+    Vec3A::new(c[0], c[1], c[2])
+}
+
+fn load_texture(texture_path: &str, base_dir: &Path) -> Result<Arc<dyn Texture>, MeshError> {
+    let full_path = base_dir.join(texture_path);
+    let texture = ImageTexture::load(&full_path)
+        .map_err(|e| MeshError::Texture(full_path.display().to_string(), e))?;
+    Ok(Arc::new(texture))
+}
+
+/// A parameter of the PBR extension to MTL, from its map (`map_Pr`) or its
+/// value (`Pr`). Maps are read from their first channel.
+fn pbr_parameter(mtl: &tobj::Material, key: &str, base_dir: &Path) -> Result<Option<Arc<dyn Texture>>, MeshError> {
+    if let Some(map) = mtl.unknown_param.get(&format!("map_{}", key)) {
+        // Options like `-bm 1` come before the file name
+        if let Some(texture_path) = map.split_whitespace().last() {
+            return Ok(Some(load_texture(texture_path, base_dir)?));
+        }
+    }
+
+    let value = mtl.unknown_param.get(key)
+        .and_then(|value| value.split_whitespace().next())
+        .and_then(|value| value.parse::<f32>().ok());
+    Ok(value.map(|value| Arc::new(SolidColor{ color: Vec3A::splat(value) }) as Arc<dyn Texture>))
+}
+
+const PBR_KEYS: [&str; 5] = ["Pr", "Pm", "Ps", "Pc", "Pcr"];
+
+fn has_pbr_parameters(mtl: &tobj::Material) -> bool {
+    PBR_KEYS.iter().any(|key| mtl.unknown_param.contains_key(*key) || mtl.unknown_param.contains_key(&format!("map_{}", key)))
+}
+
+/// Builds a `PrincipledMat` from the PBR extension to MTL: `Kd`/`map_Kd` is
+/// the base colour, `Pr`, `Pm`, `Ps`, `Pc` and `Pcr` (or their maps) the
+/// roughness, metallic, sheen, clearcoat and clearcoat roughness, and a
+/// dissolve below one the transmission with `Ni` as the index of refraction.
+fn convert_principled(mtl: &tobj::Material, base_dir: &Path) -> Result<PrincipledMat, MeshError> {
+    let mut material = match &mtl.diffuse_texture {
+        Some(texture_path) => PrincipledMat::from_shared_texture(load_texture(texture_path, base_dir)?),
+        None => PrincipledMat::from_color(mtl.diffuse.map(to_color).unwrap_or_else(|| Vec3A::new(0.8, 0.8, 0.8)))
+    };
+
+    if let Some(roughness) = pbr_parameter(mtl, "Pr", base_dir)? {
+        material = material.with_roughness(roughness);
+    }
+    if let Some(metallic) = pbr_parameter(mtl, "Pm", base_dir)? {
+        material = material.with_metallic(metallic);
+    }
+    if let Some(sheen) = pbr_parameter(mtl, "Ps", base_dir)? {
+        material = material.with_sheen(sheen, Arc::new(SolidColor{ color: Vec3A::splat(0.5) }));
+    }
+    if let Some(clearcoat) = pbr_parameter(mtl, "Pc", base_dir)? {
+        let roughness = pbr_parameter(mtl, "Pcr", base_dir)?
+            .unwrap_or_else(|| Arc::new(SolidColor{ color: Vec3A::splat(0.1) }));
+        material = material.with_clearcoat(clearcoat, roughness);
+    }
+
+    let dissolve = mtl.dissolve.unwrap_or(1.0).clamp(0.0, 1.0);
+    if dissolve < 1.0 {
+        let ior = mtl.optical_density.filter(|n| *n >= 1.0).unwrap_or(1.5);
+        material = material.with_transmission(Arc::new(SolidColor{ color: Vec3A::splat(1.0 - dissolve) }), ior);
+    }
+
+    Ok(material)
+}
+
+/// Maps the MTL parameters onto the closest of our materials:
+/// - an emissive colour (`Ke`) gives a `DiffuseLight`
+/// - any parameter of the PBR extension (`Pr`, `Pm`, `Ps`, `Pc`, `Pcr` or
+///   their maps) gives a `PrincipledMat`, see `convert_principled`
+/// - dissolve below one (`d`/`Tr`) or a refractive illumination model gives
+///   a `DielectricMat` using `Ni` as the index of refraction
+/// - a specular colour (`Ks`) brighter than the diffuse one gives a `MetalMat`
+///   with the fuzz derived from the shininess (`Ns`)
+/// - anything else is a `LambertianMat` using `map_Kd` or `Kd`
+fn convert_material(mtl: &tobj::Material, base_dir: &Path) -> Result<Arc<dyn Material>, MeshError> {
+    if max_component(mtl.emissive) > 0.0 {
+        return Ok(Arc::new(DiffuseLight::from_color(to_color(mtl.emissive.unwrap()))));
+    }
+
+    if has_pbr_parameters(mtl) {
+        return Ok(Arc::new(convert_principled(mtl, base_dir)?));
+    }
+
+    let dissolve = mtl.dissolve.unwrap_or(1.0);
+    let refractive_model = matches!(mtl.illumination_model, Some(4) | Some(6) | Some(7) | Some(9));
+    if dissolve < 1.0 || refractive_model {
+        let ior = mtl.optical_density.filter(|n| *n > 0.0).unwrap_or(1.5);
+        return Ok(Arc::new(DielectricMat::new(ior)));
+    }
+
+    let specular = max_component(mtl.specular);
+    let diffuse = max_component(mtl.diffuse);
+    if specular > 0.0 && specular >= diffuse {
+        // Phong exponent to a roughness-like fuzz, Ns = 0 is fully rough
+        let shininess = mtl.shininess.unwrap_or(0.0).max(0.0);
+        let fuzz = f32::sqrt(2.0 / (shininess + 2.0));
+        return Ok(Arc::new(MetalMat::new(to_color(mtl.specular.unwrap()), fuzz)));
+    }
+
+    if let Some(texture_path) = &mtl.diffuse_texture {
+        return Ok(Arc::new(LambertianMat::from_shared_texture(load_texture(texture_path, base_dir)?)));
+    }
+
+    let albedo = mtl.diffuse.map(to_color).unwrap_or_else(|| Vec3A::new(0.73, 0.73, 0.73));
+    Ok(Arc::new(LambertianMat::from_color(albedo)))
+}
+
+impl Hittable for Mesh {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        self.bvh.intersect(ray, t_min, t_max, sampler)
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.bvh.bounding_box(t0, t1)
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        self.bvh.visit_materials(visit)
+    }
+}
@@ -0,0 +1,180 @@
+//! The GGX (Trowbridge-Reitz) microfacet distribution with height-correlated
+//! Smith shadowing and visible normal sampling, and the Fresnel terms the
+//! microfacet materials share.
+//!
+//! Everything works in a local shading frame where the surface normal is +Z.
+
+extern crate glam;
+
+use glam::*;
+
+use crate::vec3_helpers;
+
+use std::f32::consts::PI;
+
+/// Below this alpha a surface is treated as perfectly smooth.
+pub const MIN_ALPHA: f32 = 1e-3;
+
+/// An orthonormal frame around the shading normal.
+#[derive(Copy, Clone, Debug)]
+pub struct ShadingFrame {
+    pub tangent: Vec3A,
+    pub bitangent: Vec3A,
+    pub normal: Vec3A
+}
+
+impl ShadingFrame {
+    /// A frame with an arbitrary but consistent tangent.
+    pub fn from_normal(normal: Vec3A) -> Self {
+        let (tangent, bitangent) = vec3_helpers::orthonormal_basis(normal);
+        ShadingFrame {
+            tangent,
+            bitangent,
+            normal
+        }
+    }
+
+    /// A frame whose tangent follows `tangent` projected onto the surface,
+    /// so anisotropic lobes line up with the texture coordinates.
+    pub fn from_tangent(normal: Vec3A, tangent: Vec3A) -> Self {
+        let tangent = tangent - normal * normal.dot(tangent);
+        if tangent.length_squared() < 1e-12 {
+            return ShadingFrame::from_normal(normal);
+        }
+        let tangent = tangent.normalize();
+        ShadingFrame {
+            tangent,
+            bitangent: normal.cross(tangent),
+            normal
+        }
+    }
+
+    pub fn to_local(self, v: Vec3A) -> Vec3A {
+        Vec3A::new(v.dot(self.tangent), v.dot(self.bitangent), v.dot(self.normal))
+    }
+
+    pub fn to_world(self, v: Vec3A) -> Vec3A {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
+}
+
+/// Alphas along the tangent and the bitangent for a perceptual `roughness`
+/// in [0, 1] (alpha = roughness²) and an `anisotropy` in [0, 1] that
+/// stretches the highlight along the tangent (Burley 2012).
+pub fn anisotropic_alphas(roughness: f32, anisotropy: f32) -> (f32, f32) {
+    let alpha = roughness * roughness;
+    let aspect = f32::sqrt(1.0 - 0.9 * anisotropy.clamp(0.0, 1.0));
+    (f32::max(MIN_ALPHA, alpha / aspect), f32::max(MIN_ALPHA, alpha * aspect))
+}
+
+#[derive(Copy, Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct GGX {
+    pub alpha_x: f32,
+    pub alpha_y: f32
+}
+
+impl GGX {
+    pub fn new(alpha_x: f32, alpha_y: f32) -> Self {
+        GGX {
+            alpha_x,
+            alpha_y
+        }
+    }
+
+    /// Too smooth to evaluate, sample it as a mirror instead.
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) <= MIN_ALPHA
+    }
+
+    /// Density of microfacet normals `wm`.
+    pub fn d(&self, wm: Vec3A) -> f32 {
+        if wm.z <= 0.0 {
+            return 0.0;
+        }
+        let x = wm.x / self.alpha_x;
+        let y = wm.y / self.alpha_y;
+        let e = x * x + y * y + wm.z * wm.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    /// Smith's auxiliary function, the hidden microfacet area per visible
+    /// area seen from `w`.
+    pub fn lambda(&self, w: Vec3A) -> f32 {
+        if w.z == 0.0 {
+            return f32::INFINITY;
+        }
+        let x = w.x * self.alpha_x;
+        let y = w.y * self.alpha_y;
+        let alpha_tan_squared = (x * x + y * y) / (w.z * w.z);
+        0.5 * (f32::sqrt(1.0 + alpha_tan_squared) - 1.0)
+    }
+
+    /// Fraction of the microfacets seen from `w` that aren't masked.
+    pub fn g1(&self, w: Vec3A) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction visible from both `wo` and `wi`.
+    pub fn g(&self, wo: Vec3A, wi: Vec3A) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Density of the normals visible from `wo`.
+    pub fn visible_d(&self, wo: Vec3A, wm: Vec3A) -> f32 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * f32::max(0.0, wo.dot(wm)) * self.d(wm) / wo.z
+    }
+
+    /// A microfacet normal visible from `wo` (with `wo.z > 0`), distributed
+    /// like `visible_d` (Heitz 2018).
+    pub fn sample_visible_normal(&self, wo: Vec3A, u: Vec2) -> Vec3A {
+        // Stretch to the hemisphere configuration of alpha 1
+        let vh = Vec3A::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).normalize();
+        let length_squared = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if length_squared > 0.0 {
+            Vec3A::new(-vh.y, vh.x, 0.0) / length_squared.sqrt()
+        } else {
+            Vec3A::X
+        };
+        let t2 = vh.cross(t1);
+
+        // A point on the disk, squashed to the part facing `vh`
+        let r = u.x.sqrt();
+        let phi = 2.0 * PI * u.y;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * f32::sqrt(1.0 - p1 * p1) + s * r * phi.sin();
+        let nh = p1 * t1 + p2 * t2 + f32::sqrt(f32::max(0.0, 1.0 - p1 * p1 - p2 * p2)) * vh;
+
+        Vec3A::new(self.alpha_x * nh.x, self.alpha_y * nh.y, f32::max(1e-6, nh.z)).normalize()
+    }
+}
+
+/// Unpolarised reflectance of a conductor with complex index of refraction
+/// `eta + i k`, per channel, for light arriving at `cos_theta` from the
+/// normal.
+pub fn fresnel_conductor(cos_theta: f32, eta: Vec3A, k: Vec3A) -> Vec3A {
+    let cos_theta = cos_theta.clamp(0.0, 1.0);
+    let channel = |eta: f32, k: f32| {
+        let cos2 = cos_theta * cos_theta;
+        let sin2 = 1.0 - cos2;
+        let eta2 = eta * eta;
+        let k2 = k * k;
+
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = f32::sqrt(f32::max(0.0, t0 * t0 + 4.0 * eta2 * k2));
+        let t1 = a2_plus_b2 + cos2;
+        let a = f32::sqrt(f32::max(0.0, 0.5 * (a2_plus_b2 + t0)));
+        let t2 = 2.0 * cos_theta * a;
+        let rs = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rp + rs)
+    };
+    Vec3A::new(channel(eta.x, k.x), channel(eta.y, k.y), channel(eta.z, k.z))
+}
@@ -2,6 +2,7 @@ extern crate rand;
 extern crate glam;
 
 use crate::vec3_helpers;
+use crate::rng::Pcg32;
 
 use glam::*;
 
@@ -17,32 +18,30 @@ pub struct Perlin {
 }
 
 impl Perlin {
-    pub fn new() -> Self {
-        let mut rand_vec = Vec::<Vec3A>::new();
-        rand_vec.reserve(POINT_COUNT);
+    pub fn new(rng: &mut Pcg32) -> Self {
+        let mut rand_vec = Vec::<Vec3A>::with_capacity(POINT_COUNT);
 
         for _ in 0..POINT_COUNT {
-            rand_vec.push(vec3_helpers::random_range(-1.0, 1.0));
+            rand_vec.push(vec3_helpers::random_range(-1.0, 1.0, rng));
         }
 
         Perlin {
-            rand_vec: rand_vec,
-            perm_x: Perlin::perlin_generate_perm(),
-            perm_y: Perlin::perlin_generate_perm(),
-            perm_z: Perlin::perlin_generate_perm()
+            rand_vec,
+            perm_x: Perlin::perlin_generate_perm(rng),
+            perm_y: Perlin::perlin_generate_perm(rng),
+            perm_z: Perlin::perlin_generate_perm(rng)
         }
     }
 
-    fn perlin_generate_perm() -> Vec<i32> {
+    fn perlin_generate_perm(rng: &mut Pcg32) -> Vec<i32> {
         let mut p: Vec<i32> = (0..(POINT_COUNT as i32)).collect();
 
-        let mut rng = rand::thread_rng();
+        p.shuffle(rng);
 
-        p.shuffle(&mut rng);
-
-        return p;
+        p
     }
 
+    #[allow(clippy::needless_range_loop)]
     pub fn noise(&self, point: Vec3A) -> f32 {
         let u = point.x - point.x.floor();
         let v = point.y - point.y.floor();
@@ -84,7 +83,7 @@ impl Perlin {
             }
         }
 
-        return acc;
+        acc
     }
 
     pub fn turb(&self, point: Vec3A, depth: i32) -> f32 {
@@ -98,6 +97,6 @@ impl Perlin {
             temp = 2.0 * temp;
         }
 
-        return acc.abs();
+        acc.abs()
     }
 }
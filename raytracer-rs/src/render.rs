@@ -0,0 +1,583 @@
+extern crate glam;
+
+use glam::*;
+use rayon::prelude::*;
+
+use crate::ray::Ray;
+use crate::camera::Camera;
+use crate::shapes::Hittable;
+use crate::hit_record::HitRecord;
+use crate::lights::LightList;
+use crate::framebuffer::Framebuffer;
+use crate::aov::Aovs;
+use crate::scene::MaterialIds;
+use crate::tonemap::luminance;
+use crate::sampler::{Sampler, SamplerType};
+use crate::environment::Environment;
+use crate::spectrum::SampledWavelengths;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Balances two sampling strategies for the same direction, `pdf` being the
+/// density of the strategy that produced the sample.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let a = pdf * pdf;
+    let b = other_pdf * other_pdf;
+    if a + b <= 0.0 {
+        return 0.0;
+    }
+    a / (a + b)
+}
+
+/// `rgb` the way paths carry it: unchanged, or in spectral mode its
+/// spectrum at the sampled wavelengths.
+fn carried(rgb: Vec3A, wavelengths: Option<&SampledWavelengths>) -> Vec3A {
+    match wavelengths {
+        Option::Some(wavelengths) => wavelengths.uplift(rgb),
+        Option::None => rgb
+    }
+}
+
+/// Radiance arriving along `ray`. `bsdf_pdf` is the density the previous
+/// bounce picked `ray` with; emission found this way is weighted against
+/// light sampling, except for camera rays and specular bounces (`None`).
+/// In spectral mode the radiance is that of `wavelengths`.
+#[allow(clippy::too_many_arguments)]
+fn ray_color<T: Hittable>(ray: &Ray, environment: &dyn Environment, world: &T, lights: &LightList, depth: i32, bsdf_pdf: Option<f32>,
+    mut wavelengths: Option<SampledWavelengths>, sampler: &mut dyn Sampler) -> Vec3A {
+    if depth <= 0 {
+        return Vec3A::ZERO;
+    }
+
+    let emission_weight = match bsdf_pdf {
+        Option::Some(pdf) => power_heuristic(pdf, lights.pdf_value(ray.origin, ray.direction, sampler)),
+        Option::None => 1.0
+    };
+
+    let world_result = world.intersect(ray, 0.005, f32::INFINITY, sampler);
+    if let Option::Some(record) = world_result {
+        let wo = -ray.direction.normalize();
+        let emitted = emission_weight * carried(record.material.emitted(record.tex_coords, record.point), wavelengths.as_ref());
+
+        let sample = match &wavelengths {
+            Option::Some(wavelengths) => record.material.sample_wavelength(wo, &record, wavelengths.hero(), sampler),
+            Option::None => record.material.sample(wo, &record, sampler)
+        };
+        let sample = match sample {
+            // Interpolated normals can send the path through the surface
+            Option::Some(sample) if !record.crosses_surface(sample.wi) => sample,
+            // The light reaching the surface directly doesn't depend on the
+            // BSDF sample
+            _ => return emitted + sample_lights(ray, wo, &record, environment, world, lights, wavelengths.as_ref(), sampler)
+        };
+
+        let mut weight = carried(sample.weight, wavelengths.as_ref());
+        if let Option::Some(wavelengths) = &mut wavelengths {
+            if record.material.is_dispersive() {
+                weight *= wavelengths.terminate_secondary();
+            }
+        }
+
+        let scattered = Ray{ origin: record.point, direction: sample.wi, time: ray.time };
+        if sample.lobe.is_specular() {
+            return emitted + weight * ray_color(&scattered, environment, world, lights, depth - 1, Option::None, wavelengths, sampler);
+        }
+
+        let direct = sample_lights(ray, wo, &record, environment, world, lights, wavelengths.as_ref(), sampler);
+        emitted + direct + weight * ray_color(&scattered, environment, world, lights, depth - 1, Option::Some(sample.pdf), wavelengths, sampler)
+    } else {
+        emission_weight * carried(environment.radiance(ray.direction.normalize()), wavelengths.as_ref())
+    }
+}
+
+/// Next-event estimation: the light of every punctual light, plus the light
+/// arriving at `record` from a direction sampled towards the other lights,
+/// weighted against BSDF sampling.
+#[allow(clippy::too_many_arguments)]
+fn sample_lights<T: Hittable>(ray: &Ray, wo: Vec3A, record: &HitRecord, environment: &dyn Environment, world: &T, lights: &LightList,
+    wavelengths: Option<&SampledWavelengths>, sampler: &mut dyn Sampler) -> Vec3A {
+    if !record.material.lobes().has_non_specular() {
+        return Vec3A::ZERO;
+    }
+
+    let mut punctual = Vec3A::ZERO;
+    for light in lights.punctual.iter() {
+        let incidence = light.illuminate(record.point);
+        if incidence.irradiance == Vec3A::ZERO || record.crosses_surface(incidence.direction) {
+            continue;
+        }
+        let f = record.material.eval(wo, incidence.direction, record);
+        if f == Vec3A::ZERO {
+            continue;
+        }
+        let shadow_ray = Ray{ origin: record.point, direction: incidence.direction, time: ray.time };
+        // Stop short of the light so nothing behind it casts a shadow
+        if world.intersect(&shadow_ray, 0.005, incidence.distance * (1.0 - 1e-4), sampler).is_none() {
+            punctual += carried(f, wavelengths) * carried(incidence.irradiance, wavelengths);
+        }
+    }
+
+    if lights.is_empty() {
+        return punctual;
+    }
+
+    // Normalised so `t_min` means the same distance for every light sample
+    let light_ray = Ray{ origin: record.point, direction: lights.random_direction(record.point, sampler).normalize(), time: ray.time };
+    let light_pdf = lights.pdf_value(light_ray.origin, light_ray.direction, sampler);
+    let bsdf_pdf = record.material.pdf(wo, light_ray.direction, record);
+    if light_pdf <= 0.0 || bsdf_pdf <= 0.0 || record.crosses_surface(light_ray.direction) {
+        return punctual;
+    }
+
+    // Whatever is hit first is what the light sample sees, this also takes
+    // care of occlusion
+    let incoming = match world.intersect(&light_ray, 0.005, f32::INFINITY, sampler) {
+        Option::Some(hit) => hit.material.emitted(hit.tex_coords, hit.point),
+        Option::None => environment.radiance(light_ray.direction)
+    };
+
+    let f = record.material.eval(wo, light_ray.direction, record);
+    punctual + carried(f, wavelengths) * carried(incoming, wavelengths) * power_heuristic(light_pdf, bsdf_pdf) / light_pdf
+}
+
+/// Running per-pixel radiance sums and sample counts, top row first.
+#[derive(Clone)]
+pub struct Accumulator {
+    pub width: u32,
+    pub height: u32,
+    pub sum: Vec<Vec3A>,
+    /// Sums of the squared luminance of every sample, for the variance
+    pub sum_squared: Vec<f32>,
+    pub samples: Vec<u32>
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Self {
+        Accumulator {
+            width,
+            height,
+            sum: vec![Vec3A::ZERO; (width * height) as usize],
+            sum_squared: vec![0.0; (width * height) as usize],
+            samples: vec![0; (width * height) as usize]
+        }
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.samples.iter().map(|s| *s as u64).sum()
+    }
+
+    /// The variance of the mean luminance of pixel `index`, the square of
+    /// its standard error. Infinite until the pixel has two samples.
+    pub fn variance(&self, index: usize) -> f32 {
+        let count = self.samples[index];
+        if count < 2 {
+            return f32::INFINITY;
+        }
+
+        let n = count as f32;
+        let mean = luminance(self.sum[index]) / n;
+        let sample_variance = f32::max(0.0, self.sum_squared[index] / n - mean * mean) * n / (n - 1.0);
+        sample_variance / n
+    }
+
+    /// `variance` of every pixel.
+    pub fn variances(&self) -> Vec<f32> {
+        (0..self.samples.len()).map(|i| self.variance(i)).collect()
+    }
+
+    /// The standard error of the mean luminance of pixel `index`, relative
+    /// to that mean. Infinite until the pixel has two samples.
+    pub fn relative_error(&self, index: usize) -> f32 {
+        let mean = luminance(self.sum[index]) / self.samples[index].max(1) as f32;
+        // The offset keeps black pixels from never converging
+        f32::sqrt(self.variance(index)) / (mean + 1e-3)
+    }
+
+    /// The number of samples of every pixel as a grey image.
+    pub fn sample_counts(&self) -> Framebuffer {
+        let pixels = self.samples.iter().map(|s| Vec3A::splat(*s as f32)).collect();
+        Framebuffer::from_pixels(self.width, self.height, pixels)
+    }
+
+    /// The mean radiance of every pixel.
+    pub fn to_framebuffer(&self) -> Framebuffer {
+        let pixels = self.sum.iter()
+            .zip(self.samples.iter())
+            .map(|(sum, samples)| *sum / (*samples).max(1) as f32)
+            .collect();
+        Framebuffer::from_pixels(self.width, self.height, pixels)
+    }
+}
+
+/// Everything needed to trace paths through one scene.
+pub struct Renderer<'a, T: Hittable> {
+    pub world: &'a T,
+    pub lights: &'a LightList,
+    pub camera: &'a Camera,
+    /// What rays that leave the scene see
+    pub environment: &'a dyn Environment,
+    pub max_depth: i32,
+    pub sampler: SamplerType,
+    /// Together with the pixel and sample index picks the random numbers of every sample
+    pub seed: u64,
+    /// The final sample count, which the stratified sampler splits into strata
+    pub samples_per_pixel: u32,
+    /// Trace wavelengths instead of RGB, for dispersion
+    pub spectral: bool
+}
+
+/// Renders in passes of `pass_samples` samples per pixel over the whole
+/// image. Previews and checkpoints are handed out at most once per interval,
+/// a zero interval means after every pass and `None` never.
+#[derive(Copy, Clone, Debug)]
+pub struct ProgressiveSettings {
+    pub pass_samples: u32,
+    pub preview_interval: Option<Duration>,
+    pub checkpoint_interval: Option<Duration>,
+    pub adaptive: Option<AdaptiveSettings>
+}
+
+/// Spends the sample budget where the image is noisy. Every pixel first
+/// gets `min_samples`, after that only pixels whose relative error is above
+/// `threshold` get more, the noisiest first, until they reach `max_samples`
+/// or the budget of `samples_per_pixel` per pixel on average runs out.
+#[derive(Copy, Clone, Debug)]
+pub struct AdaptiveSettings {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f32
+}
+
+/// The sample count every pixel should have after the next pass. Equal to
+/// the current counts once the render is done.
+fn next_targets(accumulator: &Accumulator, samples_per_pixel: u32, settings: &ProgressiveSettings) -> Vec<u32> {
+    let (min_samples, max_samples) = match settings.adaptive {
+        Some(adaptive) => (adaptive.min_samples.min(samples_per_pixel), adaptive.max_samples.max(samples_per_pixel)),
+        None => (samples_per_pixel, samples_per_pixel)
+    };
+
+    let lowest = accumulator.samples.iter().copied().min().unwrap_or(0);
+    if lowest < min_samples {
+        let target = (lowest + settings.pass_samples).min(min_samples);
+        return accumulator.samples.iter().map(|s| (*s).max(target)).collect();
+    }
+
+    let threshold = match settings.adaptive {
+        Some(adaptive) => adaptive.threshold,
+        None => return accumulator.samples.clone()
+    };
+
+    let budget = accumulator.samples.len() as u64 * samples_per_pixel as u64;
+    let mut remaining = budget.saturating_sub(accumulator.total_samples());
+
+    let mut noisy: Vec<(usize, f32)> = (0..accumulator.samples.len())
+        .filter(|i| accumulator.samples[*i] < max_samples)
+        .map(|i| (i, accumulator.relative_error(i)))
+        .filter(|(_, error)| *error > threshold)
+        .collect();
+    noisy.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut targets = accumulator.samples.clone();
+    for (i, _) in noisy {
+        let extra = settings.pass_samples.min(max_samples - targets[i]).min(remaining.min(u32::MAX as u64) as u32);
+        if extra == 0 {
+            break;
+        }
+        targets[i] += extra;
+        remaining -= extra as u64;
+    }
+    targets
+}
+
+/// Why `render_progressive` hands out the partial result.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Snapshot {
+    Preview,
+    Checkpoint
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C stop the render after the rows in flight instead of killing
+/// the process, so the caller can still save what has been accumulated.
+pub fn install_interrupt_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+fn is_due(last: Instant, interval: Option<Duration>) -> bool {
+    match interval {
+        Some(interval) => last.elapsed() >= interval,
+        None => false
+    }
+}
+
+impl<'a, T: Hittable> Renderer<'a, T> {
+    fn camera_ray(&self, x: u32, y: u32, width: u32, height: u32, sampler: &mut dyn Sampler) -> Ray {
+        let offset = sampler.get_2d();
+        let u = (x as f32 + offset.x) / (width - 1) as f32;
+        let v = ((height - 1 - y) as f32 + offset.y) / (height - 1) as f32;
+
+        self.camera.get_ray(u, v, sampler)
+    }
+
+    pub fn sample_pixel(&self, x: u32, y: u32, width: u32, height: u32, sampler: &mut dyn Sampler) -> Vec3A {
+        let r = self.camera_ray(x, y, width, height, sampler);
+
+        if self.spectral {
+            let wavelengths = SampledWavelengths::sample_visible(sampler.get_1d());
+            let radiance = ray_color(&r, self.environment, self.world, self.lights, self.max_depth, Option::None, Option::Some(wavelengths), sampler);
+            return wavelengths.to_rgb(radiance);
+        }
+        ray_color(&r, self.environment, self.world, self.lights, self.max_depth, Option::None, Option::None, sampler)
+    }
+
+    /// Fills `aovs` from the first hits of `samples` camera rays per pixel.
+    /// These are the same rays the first samples of the beauty image start
+    /// with, so the AOVs line up with it.
+    pub fn render_aovs(&self, aovs: &mut Aovs, samples: u32, material_ids: &MaterialIds) {
+        let width = aovs.width;
+        let height = aovs.height;
+        let row = width as usize;
+
+        aovs.albedo.par_chunks_mut(row)
+            .zip(aovs.emission.par_chunks_mut(row))
+            .zip(aovs.normal.par_chunks_mut(row))
+            .zip(aovs.depth.par_chunks_mut(row))
+            .zip(aovs.position.par_chunks_mut(row))
+            .zip(aovs.object_id.par_chunks_mut(row))
+            .zip(aovs.material_id.par_chunks_mut(row))
+            .enumerate()
+            .for_each(|(y, ((((((albedo_row, emission_row), normal_row), depth_row), position_row), object_row), material_row))| {
+                let mut sampler = self.sampler.create(self.seed, self.samples_per_pixel);
+                for x in 0..row {
+                    let pixel = y * row + x;
+                    let mut albedo = Vec3A::ZERO;
+                    let mut emission = Vec3A::ZERO;
+                    let mut normal = Vec3A::ZERO;
+                    let mut depth = 0.0;
+                    let mut position = Vec3A::ZERO;
+                    let mut hits = 0;
+
+                    for sample in 0..samples {
+                        sampler.start_sample(pixel as u64, sample);
+                        let ray = self.camera_ray(x as u32, y as u32, width, height, sampler.as_mut());
+                        match self.world.intersect(&ray, 0.005, f32::INFINITY, sampler.as_mut()) {
+                            Option::Some(record) => {
+                                if sample == 0 {
+                                    object_row[x] = record.object_id;
+                                    material_row[x] = material_ids.get(&record.material);
+                                }
+                                albedo += record.material.albedo(&record);
+                                emission += record.material.emitted(record.tex_coords, record.point);
+                                normal += record.normal;
+                                depth += self.camera.depth(record.point);
+                                position += record.point;
+                                hits += 1;
+                            },
+                            Option::None => {
+                                let background = self.environment.radiance(ray.direction.normalize());
+                                albedo += background.min(Vec3A::ONE);
+                                emission += background;
+                            }
+                        }
+                    }
+
+                    albedo_row[x] = albedo / samples.max(1) as f32;
+                    emission_row[x] = emission / samples.max(1) as f32;
+                    if hits > 0 {
+                        normal_row[x] = normal / hits as f32;
+                        depth_row[x] = depth / hits as f32;
+                        position_row[x] = position / hits as f32;
+                    }
+                }
+            });
+    }
+
+    /// Adds samples until every pixel of `accumulator` has `target` of them.
+    pub fn render_pass(&self, accumulator: &mut Accumulator, target: u32) {
+        let targets = vec![target; accumulator.samples.len()];
+        self.render_targets(accumulator, &targets);
+    }
+
+    /// Adds samples until every pixel has as many as its entry in `targets`.
+    /// Once interrupted the remaining rows are skipped, every pixel still
+    /// holds a valid sum for its own sample count. The sample values only
+    /// depend on the pixel and sample index, so the result doesn't depend on
+    /// the thread count or on how the samples were split into passes.
+    pub fn render_targets(&self, accumulator: &mut Accumulator, targets: &[u32]) {
+        let width = accumulator.width;
+        let height = accumulator.height;
+
+        accumulator.sum.par_chunks_mut(width as usize)
+            .zip(accumulator.sum_squared.par_chunks_mut(width as usize))
+            .zip(accumulator.samples.par_chunks_mut(width as usize))
+            .enumerate()
+            .for_each(|(y, ((sum_row, squared_row), sample_row))| {
+                if interrupted() {
+                    return;
+                }
+                let mut sampler = self.sampler.create(self.seed, self.samples_per_pixel);
+                for x in 0..width as usize {
+                    let pixel = y * width as usize + x;
+                    for sample in sample_row[x]..targets[pixel] {
+                        sampler.start_sample(pixel as u64, sample);
+                        let color = self.sample_pixel(x as u32, y as u32, width, height, sampler.as_mut());
+                        sum_row[x] += color;
+                        squared_row[x] += luminance(color) * luminance(color);
+                    }
+                    sample_row[x] = sample_row[x].max(targets[pixel]);
+                }
+            });
+    }
+
+    /// Renders until every pixel has `samples_per_pixel` samples, or until
+    /// the adaptive sampling in `settings` is done, continuing from whatever
+    /// `accumulator` already holds. Calls `snapshot` with the partial result
+    /// as configured in `settings`. Returns false if the render was
+    /// interrupted.
+    pub fn render_progressive<F: FnMut(&Accumulator, Snapshot)>(&self, accumulator: &mut Accumulator, samples_per_pixel: u32,
+        settings: ProgressiveSettings, mut snapshot: F) -> bool {
+        let pixel_count = accumulator.samples.len() as u64;
+        let mut progress = Progress::new(accumulator.total_samples(), pixel_count * samples_per_pixel as u64, pixel_count);
+        let mut last_preview = Instant::now();
+        let mut last_checkpoint = Instant::now();
+        let mut targets = next_targets(accumulator, samples_per_pixel, &settings);
+
+        while targets != accumulator.samples {
+            self.render_targets(accumulator, &targets);
+            progress.report(accumulator.total_samples());
+
+            if interrupted() {
+                progress.finish();
+                return false;
+            }
+            targets = next_targets(accumulator, samples_per_pixel, &settings);
+            if targets == accumulator.samples {
+                break;
+            }
+
+            if is_due(last_checkpoint, settings.checkpoint_interval) {
+                snapshot(accumulator, Snapshot::Checkpoint);
+                last_checkpoint = Instant::now();
+            }
+            if is_due(last_preview, settings.preview_interval) {
+                snapshot(accumulator, Snapshot::Preview);
+                last_preview = Instant::now();
+            }
+        }
+        progress.finish();
+        true
+    }
+}
+
+/// Prints how far a render has got, with an estimate of the time left. The
+/// counts are samples over the whole image; the estimate only uses the
+/// samples taken since `start` so resumed renders are not skewed.
+pub struct Progress {
+    start: Instant,
+    initial: u64,
+    total: u64,
+    pixel_count: u64
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+impl Progress {
+    pub fn new(initial: u64, total: u64, pixel_count: u64) -> Self {
+        Progress {
+            start: Instant::now(),
+            initial,
+            total,
+            pixel_count: pixel_count.max(1)
+        }
+    }
+
+    pub fn report(&mut self, done: u64) {
+        let elapsed = self.start.elapsed();
+        let fraction = done as f64 / self.total.max(1) as f64;
+        let remaining = self.total.saturating_sub(done) as f64;
+        let rendered = done.saturating_sub(self.initial) as f64;
+        let eta = if rendered > 0.0 {
+            Duration::from_secs_f64(elapsed.as_secs_f64() * remaining / rendered)
+        } else {
+            Duration::ZERO
+        };
+
+        eprint!("\r{}/{} spp ({:.1}%), elapsed {}, ETA {}   ",
+            done / self.pixel_count, self.total / self.pixel_count, 100.0 * fraction,
+            format_duration(elapsed), format_duration(eta));
+        std::io::stderr().flush().ok();
+    }
+
+    pub fn finish(&self) {
+        eprintln!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::CameraSettings;
+    use crate::environment::ConstantEnvironment;
+    use crate::flat_bvh::FlatBVH;
+
+    /// Renders a small cornell box at a fixed seed.
+    fn render_cornell_box(light_sampling: bool, samples_per_pixel: u32) -> Accumulator {
+        let scene = crate::cornell_box();
+        let bvh = FlatBVH::from_scene(&scene, 0.0, 1.0);
+        let camera = CameraSettings::new(Vec3A::new(278.0, 278.0, -800.0), Vec3A::new(278.0, 278.0, 0.0), 40f32.to_radians(), 0.0).build(1.0);
+        let environment = ConstantEnvironment::new(Vec3A::ZERO);
+        let lights = if light_sampling { LightList::new(scene.lights.clone()) } else { LightList::empty() };
+        let renderer = Renderer {
+            world: &bvh,
+            lights: &lights,
+            camera: &camera,
+            environment: &environment,
+            max_depth: 8,
+            sampler: SamplerType::Independent,
+            seed: 7,
+            samples_per_pixel,
+            spectral: false
+        };
+
+        let mut accumulator = Accumulator::new(16, 16);
+        renderer.render_pass(&mut accumulator, samples_per_pixel);
+        accumulator
+    }
+
+    fn mean_radiance(accumulator: &Accumulator) -> Vec3A {
+        accumulator.to_framebuffer().pixels.iter().fold(Vec3A::ZERO, |sum, pixel| sum + *pixel) / accumulator.sum.len() as f32
+    }
+
+    #[test]
+    fn light_sampling_converges_to_the_same_image() {
+        let with_light_sampling = mean_radiance(&render_cornell_box(true, 512));
+        let without_light_sampling = mean_radiance(&render_cornell_box(false, 4096));
+        // Both estimate the same integral, the tolerance covers their noise
+        let difference = (with_light_sampling - without_light_sampling).abs() / without_light_sampling;
+        assert!(difference.max_element() < 0.05, "{:?} against {:?}", with_light_sampling, without_light_sampling);
+    }
+
+    #[test]
+    fn renders_do_not_depend_on_the_thread_count() {
+        let render = |threads| rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| render_cornell_box(true, 16));
+        let single = render(1);
+        let multiple = render(4);
+        assert_eq!(single.sum, multiple.sum);
+        assert_eq!(single.sum_squared, multiple.sum_squared);
+        assert_eq!(single.samples, multiple.samples);
+    }
+}
@@ -0,0 +1,82 @@
+extern crate rand;
+
+use rand::{Error, RngCore, SeedableRng};
+
+/// Stream used for everything random while building a scene
+pub const SCENE_STREAM: u64 = 0x5ce9e;
+
+/// Permuted congruential generator (PCG-XSH-RR, 64-bit state, 32-bit
+/// output). Small and fast to create, so the renderer can start a fresh one
+/// for every sample and get the same numbers whichever thread takes it.
+#[derive(Clone, Debug)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64
+}
+
+const MULTIPLIER: u64 = 6364136223846793005;
+
+/// Scrambles `value` so nearby inputs give unrelated outputs (SplitMix64 finaliser).
+pub fn mix(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9e3779b97f4a7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+impl Pcg32 {
+    /// Generators with the same seed but different streams give independent sequences.
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Pcg32 {
+            state: 0,
+            increment: (stream << 1) | 1
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.step();
+        rng
+    }
+
+    /// The generator for sample `sample` of pixel `pixel`.
+    pub fn for_sample(seed: u64, pixel: u64, sample: u32) -> Self {
+        Pcg32::new(mix(seed ^ mix(sample as u64)), pixel)
+    }
+
+    fn step(&mut self) {
+        self.state = self.state.wrapping_mul(MULTIPLIER).wrapping_add(self.increment);
+    }
+}
+
+impl RngCore for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rotation = (old >> 59) as u32;
+        xorshifted.rotate_right(rotation)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for Pcg32 {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Pcg32::new(u64::from_le_bytes(seed), 0)
+    }
+}
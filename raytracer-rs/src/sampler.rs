@@ -0,0 +1,452 @@
+extern crate glam;
+
+use glam::*;
+use rand::prelude::*;
+
+use crate::rng::{self, Pcg32};
+
+/// Largest f32 below one, samples are kept in [0, 1)
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// Hands out the sample values of one pixel sample, one dimension after the
+/// other. The camera takes the first five (pixel position, lens, time), each
+/// bounce then takes what its material and the light sampling need.
+pub trait Sampler {
+    /// Starts sample `index` of pixel `pixel` (its index in the image) at
+    /// the first dimension.
+    fn start_sample(&mut self, pixel: u64, index: u32);
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> Vec2;
+}
+
+/// Which `Sampler` the renderer creates for every row.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplerType {
+    /// Uniform random numbers
+    Independent,
+    /// Jittered strata, shuffled independently for every dimension
+    Stratified,
+    /// Owen scrambled Halton sequence
+    Halton,
+    /// Owen scrambled Sobol (0,2) sequence, padded over pairs of dimensions
+    Sobol
+}
+
+impl SamplerType {
+    pub const NAMES: [(&'static str, SamplerType); 4] = [
+        ("independent", SamplerType::Independent),
+        ("stratified", SamplerType::Stratified),
+        ("halton", SamplerType::Halton),
+        ("sobol", SamplerType::Sobol),
+    ];
+
+    pub fn from_name(name: &str) -> Option<SamplerType> {
+        SamplerType::NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, s)| *s)
+    }
+
+    pub fn name(&self) -> &'static str {
+        SamplerType::NAMES.iter().find(|(_, s)| s == self).map(|(n, _)| *n).unwrap()
+    }
+
+    /// `samples_per_pixel` sets the number of strata of the stratified
+    /// sampler; the others don't need it.
+    pub fn create(&self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerType::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerType::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            SamplerType::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerType::Sobol => Box::new(SobolSampler::new(seed))
+        }
+    }
+}
+
+/// A hash of everything that identifies one dimension of one pixel.
+fn dimension_hash(seed: u64, pixel: u64, dimension: u32, extra: u64) -> u64 {
+    rng::mix(seed ^ rng::mix(pixel ^ rng::mix(((dimension as u64) << 32) ^ extra)))
+}
+
+fn to_unit_float(value: u32) -> f32 {
+    f32::min(value as f32 * (1.0 / 4294967296.0), ONE_MINUS_EPSILON)
+}
+
+/// Element `i` of a pseudo-random permutation of 0..`length` picked by
+/// `seed` (Kensler, "Correlated Multi-Jittered Sampling").
+fn permutation_element(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    // Added in u64: a sum wrapping around 2^32 would repeat some elements
+    ((i as u64 + seed as u64) % length as u64) as u32
+}
+
+/// White noise, every sample gets its own generator.
+pub struct IndependentSampler {
+    seed: u64,
+    rng: Pcg32
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        IndependentSampler {
+            seed,
+            rng: Pcg32::for_sample(seed, 0, 0)
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, pixel: u64, index: u32) {
+        self.rng = Pcg32::for_sample(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        Vec2::new(self.rng.gen(), self.rng.gen())
+    }
+}
+
+/// Splits every dimension into `samples_per_pixel` strata (a grid as close
+/// to square as possible for 2D) and puts one jittered sample in each. The
+/// order of the strata is shuffled per pixel and dimension so dimensions
+/// don't correlate. Pixels rendered past `samples_per_pixel`, for example
+/// when resuming with more samples, start a new set of strata.
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    grid: (u32, u32),
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+    rng: Pcg32
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> Self {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        let columns = ((samples_per_pixel as f32).sqrt() as u32).max(1);
+        let rows = samples_per_pixel.div_ceil(columns);
+        StratifiedSampler {
+            seed,
+            samples_per_pixel,
+            grid: (columns, rows),
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+            rng: Pcg32::for_sample(seed, 0, 0)
+        }
+    }
+
+    /// The shuffled stratum of the current sample among `count` strata.
+    fn next_stratum(&mut self, count: u32) -> u32 {
+        let set = (self.index / self.samples_per_pixel) as u64;
+        let hash = dimension_hash(self.seed, self.pixel, self.dimension, set) as u32;
+        self.dimension += 1;
+        permutation_element(self.index % self.samples_per_pixel, count, hash)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, pixel: u64, index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+        self.rng = Pcg32::for_sample(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let stratum = self.next_stratum(self.samples_per_pixel);
+        f32::min((stratum as f32 + self.rng.gen::<f32>()) / self.samples_per_pixel as f32, ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        let (columns, rows) = self.grid;
+        let stratum = self.next_stratum(columns * rows);
+        let x = (stratum % columns) as f32 + self.rng.gen::<f32>();
+        let y = (stratum / columns) as f32 + self.rng.gen::<f32>();
+        Vec2::new(f32::min(x / columns as f32, ONE_MINUS_EPSILON), f32::min(y / rows as f32, ONE_MINUS_EPSILON))
+    }
+}
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+    227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311
+];
+
+/// The radical inverse of `value` in `base` with every digit permuted by a
+/// hash of the digits below it, which Owen scrambles the sequence.
+fn owen_scrambled_radical_inverse(base: u32, mut value: u64, hash: u64) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_m = 1.0_f32;
+    let mut reversed_digits: u64 = 0;
+
+    // Stop once further digits no longer change an f32
+    while 1.0 - (base - 1) as f32 * inv_base_m < 1.0 {
+        let next = value / base as u64;
+        let digit = (value - next * base as u64) as u32;
+        let digit_hash = rng::mix(hash ^ reversed_digits) as u32;
+        let digit = permutation_element(digit, base, digit_hash);
+        reversed_digits = reversed_digits * base as u64 + digit as u64;
+        inv_base_m *= inv_base;
+        value = next;
+    }
+    f32::min(inv_base_m * reversed_digits as f32, ONE_MINUS_EPSILON)
+}
+
+/// Dimension `d` is the radical inverse in the `d`th prime of the sample
+/// index, scrambled differently for every pixel. Dimensions past the prime
+/// table fall back to random numbers.
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+    rng: Pcg32
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        HaltonSampler {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+            rng: Pcg32::for_sample(seed, 0, 0)
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, pixel: u64, index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+        self.rng = Pcg32::for_sample(self.seed, pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        if dimension as usize >= PRIMES.len() {
+            return self.rng.gen();
+        }
+        let hash = dimension_hash(self.seed, self.pixel, dimension, 0);
+        owen_scrambled_radical_inverse(PRIMES[dimension as usize], self.index as u64, hash)
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        let x = self.get_1d();
+        Vec2::new(x, self.get_1d())
+    }
+}
+
+/// The first two dimensions of the Sobol sequence.
+fn sobol_2d(index: u32) -> (u32, u32) {
+    let mut x = 0_u32;
+    let mut y = 0_u32;
+    let mut direction_x = 1_u32 << 31;
+    let mut direction_y = 1_u32 << 31;
+    let mut index = index;
+    while index != 0 {
+        if index & 1 != 0 {
+            x ^= direction_x;
+            y ^= direction_y;
+        }
+        index >>= 1;
+        direction_x >>= 1;
+        direction_y ^= direction_y >> 1;
+    }
+    (x, y)
+}
+
+/// Laine and Karras' hash, a permutation of `value` where each bit only
+/// depends on the bits below it.
+fn laine_karras_permutation(mut value: u32, seed: u32) -> u32 {
+    value = value.wrapping_add(seed);
+    value ^= value.wrapping_mul(0x6c50b47c);
+    value ^= value.wrapping_mul(0xb82f1e52);
+    value ^= value.wrapping_mul(0xc7afe638);
+    value ^= value.wrapping_mul(0x8d22f6e6);
+    value
+}
+
+/// Owen scrambling of a 32-bit fixed point value.
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    laine_karras_permutation(value.reverse_bits(), seed).reverse_bits()
+}
+
+/// Every pair of dimensions is its own 2D Sobol sequence with the sample
+/// order shuffled and the values Owen scrambled per pixel and dimension
+/// (Burley, "Practical Hash-based Owen Scrambling").
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    index: u32,
+    dimension: u32
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        SobolSampler {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0
+        }
+    }
+
+    fn next_sample(&mut self) -> (u32, u32) {
+        let hash = dimension_hash(self.seed, self.pixel, self.dimension, 0);
+        self.dimension += 1;
+        let index = nested_uniform_scramble(self.index, hash as u32);
+        let (x, y) = sobol_2d(index);
+        let second_hash = rng::mix(hash);
+        (nested_uniform_scramble(x, (hash >> 32) as u32), nested_uniform_scramble(y, second_hash as u32))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, pixel: u64, index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        to_unit_float(self.next_sample().0)
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        let (x, y) = self.next_sample();
+        Vec2::new(to_unit_float(x), to_unit_float(y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sorted cells of a `columns` x `rows` grid the points fall into.
+    fn cells(points: &[Vec2], columns: u32, rows: u32) -> Vec<u32> {
+        let mut cells: Vec<u32> = points.iter()
+            .map(|p| (p.y * rows as f32) as u32 * columns + (p.x * columns as f32) as u32)
+            .collect();
+        cells.sort_unstable();
+        cells
+    }
+
+    fn one_per_cell(points: &[Vec2], columns: u32, rows: u32) -> bool {
+        let cells = cells(points, columns, rows);
+        cells.windows(2).all(|pair| pair[0] != pair[1])
+    }
+
+    /// The samples of one pixel in the dimension pair after `skip` pairs.
+    fn pixel_samples(sampler: &mut dyn Sampler, count: u32, skip: u32) -> Vec<Vec2> {
+        (0..count).map(|i| {
+            sampler.start_sample(17, i);
+            for _ in 0..skip {
+                sampler.get_2d();
+            }
+            sampler.get_2d()
+        }).collect()
+    }
+
+    #[test]
+    fn permutation_element_is_a_bijection() {
+        for length in [1, 2, 3, 7, 16, 100, 1000].iter() {
+            for seed in [0, 1, 0x12345678, u32::MAX].iter() {
+                let mut elements: Vec<u32> = (0..*length).map(|i| permutation_element(i, *length, *seed)).collect();
+                elements.sort_unstable();
+                assert_eq!(elements, (0..*length).collect::<Vec<u32>>(), "length {}, seed {}", length, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn sobol_prefixes_are_stratified() {
+        let mut sampler = SobolSampler::new(3);
+        for k in 0..=8 {
+            let count = 1 << k;
+            for skip in [0, 3].iter() {
+                let points = pixel_samples(&mut sampler, count, *skip);
+                // Every split of the 2^k elementary intervals between x and y
+                for a in 0..=k {
+                    assert!(one_per_cell(&points, 1 << a, 1 << (k - a)), "{} samples, {}x{} cells", count, 1 << a, 1 << (k - a));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn halton_prefixes_are_stratified() {
+        let mut sampler = HaltonSampler::new(3);
+        // The first pair of dimensions has bases 2 and 3
+        let points = pixel_samples(&mut sampler, 64, 0);
+        assert!(one_per_cell(&points, 64, 1));
+        let points = pixel_samples(&mut sampler, 81, 0);
+        assert!(one_per_cell(&points, 1, 81));
+    }
+
+    #[test]
+    fn stratified_samples_fill_every_stratum() {
+        for samples_per_pixel in [1, 10, 16, 33].iter() {
+            let mut sampler = StratifiedSampler::new(5, *samples_per_pixel);
+            let (columns, rows) = sampler.grid;
+            for skip in [0, 2].iter() {
+                assert!(one_per_cell(&pixel_samples(&mut sampler, *samples_per_pixel, *skip), columns, rows), "{} samples", samples_per_pixel);
+            }
+            let values: Vec<Vec2> = (0..*samples_per_pixel).map(|i| {
+                sampler.start_sample(17, i);
+                Vec2::new(sampler.get_1d(), 0.0)
+            }).collect();
+            assert!(one_per_cell(&values, *samples_per_pixel, 1), "{} samples", samples_per_pixel);
+        }
+    }
+
+    #[test]
+    fn values_lie_in_the_unit_interval() {
+        assert!(to_unit_float(u32::MAX) < 1.0);
+        for sampler_type in SamplerType::NAMES.iter().map(|(_, s)| *s) {
+            let mut sampler = sampler_type.create(9, 64);
+            for i in 0..256 {
+                sampler.start_sample(i as u64 % 7, i);
+                for _ in 0..80 {
+                    let u = sampler.get_2d();
+                    let v = sampler.get_1d();
+                    assert!((0.0..1.0).contains(&u.x) && (0.0..1.0).contains(&u.y) && (0.0..1.0).contains(&v),
+                        "{:?} gave {:?} and {}", sampler_type, u, v);
+                }
+            }
+        }
+    }
+}
@@ -1,43 +1,116 @@
-use crate::shapes::Hittable;
+use crate::shapes::{Hittable, IdentifiedObject};
 use crate::ray::Ray;
 use crate::aabb::AABB;
 use crate::hit_record::HitRecord;
+use crate::sampler::Sampler;
+use crate::materials::Material;
+use crate::lights::PunctualLight;
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 pub struct Scene {
     pub shapes: Vec<Arc<dyn Hittable>>,
+    /// Emissive shapes that are also sampled directly, a subset of `shapes`
+    pub lights: Vec<Arc<dyn Hittable>>,
+    /// Point, spot and directional lights, which aren't shapes rays can hit
+    pub punctual_lights: Vec<Arc<dyn PunctualLight>>
+}
+
+/// The ids of the material id image. Materials are told apart by identity,
+/// so one material shared by several shapes has a single id.
+pub struct MaterialIds {
+    ids: HashMap<usize, u32>
+}
+
+/// The address of the material, which the scene keeps alive.
+fn material_key(material: &Arc<dyn Material>) -> usize {
+    Arc::as_ptr(material) as *const () as usize
+}
+
+impl MaterialIds {
+    /// The id of `material`, zero for materials that aren't in the scene.
+    pub fn get(&self, material: &Arc<dyn Material>) -> u32 {
+        self.ids.get(&material_key(material)).copied().unwrap_or(0)
+    }
 }
 
 impl Scene {
     pub fn new() -> Scene {
-        Scene{ shapes: Vec::new() }
+        Scene{ shapes: Vec::new(), lights: Vec::new(), punctual_lights: Vec::new() }
     }
 
+    /// The id the next shape added gets. Ids count up from one in the order
+    /// the shapes are added, so they stay the same between runs.
+    fn next_id(&self) -> u32 {
+        self.shapes.len() as u32 + 1
+    }
+
+    /// Adds a shape, every hit on it gets the shape's object id.
     pub fn add_shape<S: 'static + Hittable>(&mut self, shape: S) {
-        self.shapes.push(Arc::new(shape));
+        let id = self.next_id();
+        self.shapes.push(Arc::new(IdentifiedObject::new(shape, id)));
+    }
+
+    pub fn add_shared_shape(&mut self, shape: Arc<dyn Hittable>) {
+        let id = self.next_id();
+        self.shapes.push(Arc::new(IdentifiedObject::new(shape, id)));
+    }
+
+    /// Adds an emissive shape and registers it for light sampling. The shape
+    /// must implement `pdf_value` and `random_direction`.
+    pub fn add_light<S: 'static + Hittable>(&mut self, shape: S) {
+        self.add_shared_light(Arc::new(shape));
+    }
+
+    pub fn add_shared_light(&mut self, shape: Arc<dyn Hittable>) {
+        let shape: Arc<dyn Hittable> = Arc::new(IdentifiedObject::new(shape, self.next_id()));
+        self.shapes.push(shape.clone());
+        self.lights.push(shape);
+    }
+
+    pub fn add_punctual_light<L: 'static + PunctualLight>(&mut self, light: L) {
+        self.punctual_lights.push(Arc::new(light));
+    }
+
+    pub fn add_shared_punctual_light(&mut self, light: Arc<dyn PunctualLight>) {
+        self.punctual_lights.push(light);
+    }
+
+    /// Numbers the materials from one in the order the shapes using them
+    /// were added, so the ids depend only on the scene and not on when the
+    /// materials were created.
+    pub fn material_ids(&self) -> MaterialIds {
+        let mut ids = HashMap::new();
+        self.visit_materials(&mut |material| {
+            let next = ids.len() as u32 + 1;
+            ids.entry(material_key(material)).or_insert(next);
+        });
+        MaterialIds{ ids }
     }
 
     pub fn clear(&mut self) {
         self.shapes.clear();
+        self.lights.clear();
+        self.punctual_lights.clear();
     }
 }
 
 impl Hittable for Scene {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let mut closest_so_far = t_max;
 
         let mut scene_result = Option::None;
         
         for shape in self.shapes.iter() {
-            let result = shape.intersect(ray, t_min, closest_so_far);
+            let result = shape.intersect(ray, t_min, closest_so_far, sampler);
             if let Option::Some(hit_record) = result {
                 closest_so_far = hit_record.t;
                 scene_result = Option::Some(hit_record);
             }
         }
 
-        return scene_result;
+        scene_result
     }
 
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
@@ -64,9 +137,12 @@ impl Hittable for Scene {
             }
         }
 
-        return Some(result);
+        Some(result)
     }
-}
 
-unsafe impl Send for Scene {}
-unsafe impl Sync for Scene {}
\ No newline at end of file
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        for shape in self.shapes.iter() {
+            shape.visit_materials(visit);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,1230 @@
+//! Loader for declarative TOML scene descriptions.
+//!
+//! A scene file has a `[camera]`, optional image, environment and sky
+//! settings, named textures and materials, a list of shapes and a list of
+//! lights. Shapes reference materials by name or give an inline table, and
+//! textures and materials may be built from each other. The format is
+//! described in `scenes/README.md`.
+
+extern crate glam;
+
+use glam::*;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::flat_bvh::FlatBVH;
+use crate::camera::{Camera, CameraSettings};
+use crate::materials::*;
+use crate::mesh::Mesh;
+use crate::scene::Scene;
+use crate::shapes::*;
+use crate::texture::*;
+use crate::rng::{Pcg32, SCENE_STREAM};
+use crate::environment::{EnvironmentSettings, SkyModel, SkySettings};
+use crate::lights::{DirectionalLight, LightProfile, PointLight, PunctualLight, SpotLight};
+use crate::ies::IesProfile;
+use crate::spectrum::{Dispersion, LAMBDA_MIN, SODIUM_D_LINE};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct SceneError {
+    pub path: PathBuf,
+    /// 1-based line and column of the offending entry, when known.
+    pub location: Option<(usize, usize)>,
+    pub message: String
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some((line, column)) => write!(f, "{}:{}:{}: {}", self.path.display(), line, column, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message)
+        }
+    }
+}
+
+/// Image settings a scene file may suggest; command line options win.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ImageSettings {
+    pub width: Option<u32>,
+    pub aspect_ratio: Option<f32>,
+    pub samples_per_pixel: Option<u32>,
+    pub max_depth: Option<i32>,
+    /// Run the denoiser on the finished image and the previews
+    pub denoise: Option<bool>,
+    /// Trace wavelengths instead of RGB, for dispersion
+    pub spectral: Option<bool>
+}
+
+pub struct LoadedScene {
+    pub world: Scene,
+    pub camera: CameraSettings,
+    pub background: Vec3A,
+    /// Replaces the background when present
+    pub environment: Option<EnvironmentSettings>,
+    /// Replaces the background when present, never set together with `environment`
+    pub sky: Option<SkySettings>,
+    pub image: ImageSettings
+}
+
+impl LoadedScene {
+    /// Builds the camera using the aspect ratio from the file, or 1.0.
+    pub fn build_camera(&self) -> Camera {
+        self.camera.build(self.image.aspect_ratio.unwrap_or(1.0))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default)]
+    background: [f32; 3],
+    camera: Spanned<CameraDesc>,
+    #[serde(default)]
+    image: ImageSettings,
+    environment: Option<Spanned<EnvironmentDesc>>,
+    sky: Option<Spanned<SkyDesc>>,
+    #[serde(default)]
+    textures: BTreeMap<String, Spanned<TextureDesc>>,
+    #[serde(default)]
+    materials: BTreeMap<String, Spanned<MaterialDesc>>,
+    #[serde(default)]
+    shapes: Vec<Spanned<ShapeDesc>>,
+    #[serde(default)]
+    lights: Vec<Spanned<LightDesc>>
+}
+
+fn default_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_focus_distance() -> f32 {
+    10.0
+}
+
+fn default_time() -> [f32; 2] {
+    [0.0, 1.0]
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_color() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_base_color() -> TextureRef {
+    TextureRef::Color([0.8, 0.8, 0.8])
+}
+
+fn default_ior() -> f32 {
+    1.5
+}
+
+fn default_down() -> [f32; 3] {
+    [0.0, -1.0, 0.0]
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    /// Equirectangular image, relative to the scene file
+    map: String,
+    /// Degrees around +Y
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "default_intensity")]
+    intensity: f32
+}
+
+/// Angles in degrees, unset entries keep the `SkySettings::new` defaults.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkyDesc {
+    model: String,
+    sun_elevation: Option<f32>,
+    sun_azimuth: Option<f32>,
+    turbidity: Option<f32>,
+    sun_radius: Option<f32>,
+    intensity: Option<f32>,
+    bottom: Option<[f32; 3]>,
+    top: Option<[f32; 3]>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    origin: [f32; 3],
+    target: [f32; 3],
+    #[serde(default = "default_up")]
+    up: [f32; 3],
+    /// Vertical field of view in degrees
+    fov: f32,
+    #[serde(default)]
+    aperture: f32,
+    #[serde(default = "default_focus_distance")]
+    focus_distance: f32,
+    #[serde(default = "default_time")]
+    shutter: [f32; 2]
+}
+
+/// Either an inline colour or the name of an entry in `[textures]`.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum TextureRef {
+    Color([f32; 3]),
+    Named(String)
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDesc {
+    Solid { color: [f32; 3] },
+    Checkered { odd: TextureRef, even: TextureRef },
+    Noise { frequency: f32 },
+    Image { path: String },
+    /// `channel` is "r", "g" or "b"
+    Channel { texture: TextureRef, channel: String }
+}
+
+/// Either the name of an entry in `[materials]` or an inline material table.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MaterialRef {
+    Named(String),
+    Inline(MaterialDesc)
+}
+
+/// Either a number in [0, 1] or the name of an entry in `[textures]`, whose
+/// first channel is used.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum ScalarRef {
+    Value(f32),
+    Named(String)
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDesc {
+    Lambertian { albedo: TextureRef },
+    Metal { albedo: [f32; 3], #[serde(default)] fuzz: f32 },
+    /// A GGX metal, either a named `preset` or a complex IOR `eta` and `k`
+    Conductor {
+        preset: Option<String>,
+        eta: Option<[f32; 3]>,
+        k: Option<[f32; 3]>,
+        #[serde(default)]
+        roughness: f32,
+        #[serde(default)]
+        anisotropy: f32
+    },
+    /// Clear glass. `cauchy` ([a, b]) or `sellmeier` coefficients make the
+    /// index depend on the wavelength in spectral mode, `ior` then defaults
+    /// to their index at the sodium D line
+    Dielectric {
+        ior: Option<f32>,
+        cauchy: Option<[f32; 2]>,
+        sellmeier: Option<SellmeierDesc>
+    },
+    /// Frosted and coloured glass; the colour is either an `absorption`
+    /// coefficient per unit distance or the `color` left after `color_distance`
+    Glass {
+        ior: f32,
+        #[serde(default)]
+        roughness: f32,
+        absorption: Option<[f32; 3]>,
+        color: Option<[f32; 3]>,
+        #[serde(default = "default_intensity")]
+        color_distance: f32
+    },
+    /// See `PrincipledMat`; parameters left out keep its defaults
+    Principled {
+        #[serde(default = "default_base_color")]
+        base_color: TextureRef,
+        metallic: Option<ScalarRef>,
+        roughness: Option<ScalarRef>,
+        specular: Option<ScalarRef>,
+        sheen: Option<ScalarRef>,
+        sheen_tint: Option<ScalarRef>,
+        clearcoat: Option<ScalarRef>,
+        clearcoat_roughness: Option<ScalarRef>,
+        transmission: Option<ScalarRef>,
+        #[serde(default = "default_ior")]
+        ior: f32
+    },
+    /// `first` where `mask` is 0, `second` where it is 1, see `MixMaterial`
+    Mix {
+        first: std::boxed::Box<MaterialRef>,
+        second: std::boxed::Box<MaterialRef>,
+        mask: ScalarRef
+    },
+    /// A clear coat over `base`, see `CoatedMat`. `color` is what the coat
+    /// leaves of the light seen straight on.
+    Coated {
+        base: std::boxed::Box<MaterialRef>,
+        #[serde(default = "default_ior")]
+        ior: f32,
+        #[serde(default)]
+        roughness: f32,
+        color: Option<[f32; 3]>
+    },
+    /// `base` with its normal from the tangent space normal map in the
+    /// texture `map`, see `PerturbedMat`
+    NormalMap {
+        base: std::boxed::Box<MaterialRef>,
+        map: String,
+        #[serde(default = "default_intensity")]
+        strength: f32
+    },
+    /// `base` with bumps `scale` times the texture `height` high
+    BumpMap {
+        base: std::boxed::Box<MaterialRef>,
+        height: String,
+        #[serde(default = "default_intensity")]
+        scale: f32
+    },
+    DiffuseLight { emit: TextureRef },
+    Isotropic { albedo: TextureRef }
+}
+
+/// Sellmeier coefficients with the wavelengths in micrometres.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SellmeierDesc {
+    b: [f32; 3],
+    c: [f32; 3]
+}
+
+/// Light intensity is `color` times `intensity`, angles are in degrees.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum LightDesc {
+    Point {
+        position: [f32; 3],
+        #[serde(default = "default_color")]
+        color: [f32; 3],
+        intensity: f32,
+        /// IES file, relative to the scene file
+        profile: Option<String>,
+        /// Direction the profile's vertical angles are measured from
+        #[serde(default = "default_down")]
+        axis: [f32; 3]
+    },
+    Spot {
+        position: [f32; 3],
+        target: [f32; 3],
+        #[serde(default = "default_color")]
+        color: [f32; 3],
+        intensity: f32,
+        outer_angle: f32,
+        /// Defaults to `outer_angle`, a hard edge
+        inner_angle: Option<f32>,
+        profile: Option<String>
+    },
+    /// `intensity` is the irradiance on a surface facing the light
+    Directional {
+        direction: [f32; 3],
+        #[serde(default = "default_color")]
+        color: [f32; 3],
+        intensity: f32
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TransformOp {
+    Translate([f32; 3]),
+    RotateX(f32),
+    RotateY(f32),
+    RotateZ(f32),
+    Scale([f32; 3])
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ShapeDesc {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+        material: Option<MaterialRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    MovingSphere {
+        center_start: [f32; 3],
+        center_end: [f32; 3],
+        radius: f32,
+        #[serde(default = "default_time")]
+        time: [f32; 2],
+        material: Option<MaterialRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    XyRect {
+        min: [f32; 2],
+        max: [f32; 2],
+        offset: f32,
+        material: Option<MaterialRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    XzRect {
+        min: [f32; 2],
+        max: [f32; 2],
+        offset: f32,
+        material: Option<MaterialRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    YzRect {
+        min: [f32; 2],
+        max: [f32; 2],
+        offset: f32,
+        material: Option<MaterialRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    Box {
+        size: Option<[f32; 3]>,
+        min: Option<[f32; 3]>,
+        max: Option<[f32; 3]>,
+        material: Option<MaterialRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    ConstantMedium {
+        boundary: std::boxed::Box<ShapeDesc>,
+        density: f32,
+        material: Option<MaterialRef>,
+        albedo: Option<TextureRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    /// A Wavefront OBJ file; `material` overrides the MTL materials
+    Mesh {
+        path: String,
+        material: Option<MaterialRef>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    },
+    /// A list of shapes gathered into their own BVH
+    Group {
+        children: Vec<ShapeDesc>,
+        #[serde(default)]
+        transform: Vec<TransformOp>
+    }
+}
+
+impl TextureDesc {
+    /// Names of the other textures this one is made of.
+    fn references(&self) -> Vec<&str> {
+        let names = match self {
+            TextureDesc::Checkered { odd, even } => vec![odd, even],
+            TextureDesc::Channel { texture, .. } => vec![texture],
+            _ => Vec::new()
+        };
+        names.into_iter().filter_map(|reference| match reference {
+            TextureRef::Named(name) => Some(name.as_str()),
+            TextureRef::Color(_) => None
+        }).collect()
+    }
+}
+
+impl MaterialRef {
+    /// Names of the materials this one is or is made of, through inline
+    /// materials too.
+    fn references(&self) -> Vec<&str> {
+        match self {
+            MaterialRef::Named(name) => vec![name.as_str()],
+            MaterialRef::Inline(desc) => desc.references()
+        }
+    }
+}
+
+impl MaterialDesc {
+    /// Names of the other materials this one is made of.
+    fn references(&self) -> Vec<&str> {
+        match self {
+            MaterialDesc::Mix { first, second, .. } => [first.references(), second.references()].concat(),
+            MaterialDesc::Coated { base, .. } |
+            MaterialDesc::NormalMap { base, .. } |
+            MaterialDesc::BumpMap { base, .. } => base.references(),
+            _ => Vec::new()
+        }
+    }
+}
+
+/// The entries of `descs` ordered so every one comes after those it
+/// references, or the names along a cycle of references. Unknown names are
+/// left for the build to report.
+fn dependency_order<'a, D>(descs: &'a BTreeMap<String, Spanned<D>>, references: fn(&'a D) -> Vec<&'a str>)
+    -> Result<Vec<&'a String>, Vec<&'a String>> {
+    fn visit<'a, D>(name: &'a String, descs: &'a BTreeMap<String, Spanned<D>>, references: fn(&'a D) -> Vec<&'a str>,
+        done: &mut HashMap<&'a String, bool>, path: &mut Vec<&'a String>, order: &mut Vec<&'a String>) -> Result<(), Vec<&'a String>> {
+        match done.get(name) {
+            Some(true) => return Ok(()),
+            Some(false) => {
+                // Still on the path, so it references itself
+                let start = path.iter().position(|entry| *entry == name).unwrap();
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return Err(cycle);
+            },
+            None => {}
+        }
+
+        done.insert(name, false);
+        path.push(name);
+        for reference in references(descs[name].get_ref()) {
+            if let Some((key, _)) = descs.get_key_value(reference) {
+                visit(key, descs, references, done, path, order)?;
+            }
+        }
+        path.pop();
+        done.insert(name, true);
+        order.push(name);
+        Ok(())
+    }
+
+    let mut done = HashMap::new();
+    let mut order = Vec::new();
+    for name in descs.keys() {
+        visit(name, descs, references, &mut done, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+fn cycle_error(builder: &Builder, kind: &str, cycle: &[&String]) -> SceneError {
+    let names = cycle.iter().map(|name| format!("'{}'", name)).collect::<Vec<String>>().join(" -> ");
+    builder.error(format!("{} reference each other in a cycle: {}", kind, names))
+}
+
+impl ShapeDesc {
+    fn transform(&self) -> &[TransformOp] {
+        match self {
+            ShapeDesc::Sphere { transform, .. } |
+            ShapeDesc::MovingSphere { transform, .. } |
+            ShapeDesc::XyRect { transform, .. } |
+            ShapeDesc::XzRect { transform, .. } |
+            ShapeDesc::YzRect { transform, .. } |
+            ShapeDesc::Box { transform, .. } |
+            ShapeDesc::ConstantMedium { transform, .. } |
+            ShapeDesc::Mesh { transform, .. } |
+            ShapeDesc::Group { transform, .. } => transform
+        }
+    }
+}
+
+/// A shape and, if it can be sampled as a light, its material.
+type SampledShape = (Arc<dyn Hittable>, Option<Arc<dyn Material>>);
+
+fn to_vec3(v: [f32; 3]) -> Vec3A {
+    Vec3A::new(v[0], v[1], v[2])
+}
+
+fn to_vec2(v: [f32; 2]) -> Vec2 {
+    Vec2::new(v[0], v[1])
+}
+
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+/// Turns descriptions into scene objects, remembering where each one came
+/// from so errors point at the right entry.
+struct Builder<'a> {
+    path: &'a Path,
+    source: &'a str,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    span: Range<usize>,
+    rng: Pcg32
+}
+
+impl<'a> Builder<'a> {
+    fn error<S: Into<String>>(&self, message: S) -> SceneError {
+        SceneError {
+            path: self.path.to_path_buf(),
+            location: Some(line_column(self.source, self.span.start)),
+            message: message.into()
+        }
+    }
+
+    /// File paths in a scene are relative to the scene file.
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        self.path.parent().unwrap_or_else(|| Path::new("")).join(path)
+    }
+
+    fn texture(&self, reference: &TextureRef) -> Result<Arc<dyn Texture>, SceneError> {
+        match reference {
+            TextureRef::Color(color) => Ok(Arc::new(SolidColor{ color: to_vec3(*color) })),
+            TextureRef::Named(name) => self.textures.get(name).cloned()
+                .ok_or_else(|| self.error(format!("unknown texture '{}'", name)))
+        }
+    }
+
+    fn build_texture(&mut self, desc: &TextureDesc) -> Result<Arc<dyn Texture>, SceneError> {
+        Ok(match desc {
+            TextureDesc::Solid { color } => Arc::new(SolidColor{ color: to_vec3(*color) }),
+            TextureDesc::Checkered { odd, even } => Arc::new(CheckeredTexture::from_shared_texture(self.texture(odd)?, self.texture(even)?)),
+            TextureDesc::Noise { frequency } => Arc::new(NoiseTexture::new(*frequency, &mut self.rng)),
+            TextureDesc::Image { path } => {
+                let full_path = self.resolve_path(path);
+                let texture = ImageTexture::load(&full_path)
+                    .map_err(|e| self.error(format!("could not load image '{}': {}", full_path.display(), e)))?;
+                Arc::new(texture)
+            },
+            TextureDesc::Channel { texture, channel } => {
+                let channel = match channel.as_str() {
+                    "r" => 0,
+                    "g" => 1,
+                    "b" => 2,
+                    _ => return Err(self.error(format!("unknown channel '{}', expected r, g or b", channel)))
+                };
+                Arc::new(ChannelTexture { texture: self.texture(texture)?, channel })
+            }
+        })
+    }
+
+    /// A scalar as a grey texture; the default when there is none.
+    fn scalar_texture(&self, reference: &Option<ScalarRef>, name: &str, default: f32) -> Result<Arc<dyn Texture>, SceneError> {
+        match reference {
+            None => Ok(Arc::new(SolidColor{ color: Vec3A::splat(default) })),
+            Some(ScalarRef::Value(value)) => {
+                if !(0.0..=1.0).contains(value) {
+                    return Err(self.error(format!("{} must be between 0 and 1", name)));
+                }
+                Ok(Arc::new(SolidColor{ color: Vec3A::splat(*value) }))
+            },
+            Some(ScalarRef::Named(texture)) => self.texture(&TextureRef::Named(texture.clone()))
+        }
+    }
+
+    fn build_material(&self, desc: &MaterialDesc) -> Result<Arc<dyn Material>, SceneError> {
+        Ok(match desc {
+            MaterialDesc::Lambertian { albedo } => Arc::new(LambertianMat::from_shared_texture(self.texture(albedo)?)),
+            MaterialDesc::Metal { albedo, fuzz } => {
+                if *fuzz < 0.0 {
+                    return Err(self.error("metal fuzz must not be negative"));
+                }
+                Arc::new(MetalMat::new(to_vec3(*albedo), *fuzz))
+            },
+            MaterialDesc::Conductor { preset, eta, k, roughness, anisotropy } => {
+                if !(0.0..=1.0).contains(roughness) || !(0.0..=1.0).contains(anisotropy) {
+                    return Err(self.error("conductor roughness and anisotropy must be between 0 and 1"));
+                }
+                let (eta, k) = match (preset, eta, k) {
+                    (Some(name), None, None) => ConductorPreset::from_name(name).ok_or_else(|| self.error(format!(
+                        "unknown conductor preset '{}', expected one of: {}", name,
+                        ConductorPreset::NAMES.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", "))))?.ior(),
+                    (None, Some(eta), Some(k)) => (to_vec3(*eta), to_vec3(*k)),
+                    _ => return Err(self.error("conductor needs either a preset or both eta and k"))
+                };
+                if eta.min_element() <= 0.0 || k.min_element() < 0.0 {
+                    return Err(self.error("conductor eta must be positive and k must not be negative"));
+                }
+                Arc::new(ConductorMat::new_anisotropic(eta, k, *roughness, *anisotropy))
+            },
+            MaterialDesc::Dielectric { ior, cauchy, sellmeier } => {
+                let dispersion = match (cauchy, sellmeier) {
+                    (Some([a, b]), None) => Some(Dispersion::Cauchy { a: *a, b: *b }),
+                    (None, Some(sellmeier)) => Some(Dispersion::Sellmeier { b: sellmeier.b, c: sellmeier.c }),
+                    (None, None) => None,
+                    (Some(_), Some(_)) => return Err(self.error("dielectric takes either cauchy or sellmeier coefficients, not both"))
+                };
+                let ior = match (ior, &dispersion) {
+                    (Some(ior), _) => *ior,
+                    (None, Some(dispersion)) => dispersion.ior(SODIUM_D_LINE),
+                    (None, None) => return Err(self.error("dielectric needs an ior or dispersion coefficients"))
+                };
+                if ior <= 0.0 {
+                    return Err(self.error("dielectric ior must be positive"));
+                }
+                if let Some(dispersion) = &dispersion {
+                    // Coefficients near a resonance give nonsense somewhere in the range
+                    let physical = (0..=47).map(|i| LAMBDA_MIN + i as f32 * 10.0).all(|lambda| {
+                        let n = dispersion.ior(lambda);
+                        n.is_finite() && n >= 1.0
+                    });
+                    if !physical {
+                        return Err(self.error("dielectric dispersion must give an ior of at least 1 from 360 to 830 nm"));
+                    }
+                }
+                match dispersion {
+                    Some(dispersion) => Arc::new(DielectricMat::new(ior).with_dispersion(dispersion)),
+                    None => Arc::new(DielectricMat::new(ior))
+                }
+            },
+            MaterialDesc::Glass { ior, roughness, absorption, color, color_distance } => {
+                if *ior <= 0.0 {
+                    return Err(self.error("glass ior must be positive"));
+                }
+                if !(0.0..=1.0).contains(roughness) {
+                    return Err(self.error("glass roughness must be between 0 and 1"));
+                }
+                let absorption = match (absorption, color) {
+                    (Some(absorption), None) => to_vec3(*absorption),
+                    (None, Some(color)) => {
+                        if *color_distance <= 0.0 {
+                            return Err(self.error("glass color_distance must be positive"));
+                        }
+                        GlassMat::absorption_for(to_vec3(*color), *color_distance)
+                    },
+                    (None, None) => Vec3A::ZERO,
+                    (Some(_), Some(_)) => return Err(self.error("glass takes either absorption or color, not both"))
+                };
+                if absorption.min_element() < 0.0 {
+                    return Err(self.error("glass absorption must not be negative"));
+                }
+                Arc::new(GlassMat::new(*ior, *roughness, absorption))
+            },
+            MaterialDesc::DiffuseLight { emit } => Arc::new(DiffuseLight::from_shared_texture(self.texture(emit)?)),
+            MaterialDesc::Principled { base_color, metallic, roughness, specular, sheen, sheen_tint, clearcoat, clearcoat_roughness, transmission, ior } => {
+                if *ior <= 0.0 {
+                    return Err(self.error("principled ior must be positive"));
+                }
+                Arc::new(PrincipledMat::from_shared_texture(self.texture(base_color)?)
+                    .with_metallic(self.scalar_texture(metallic, "metallic", 0.0)?)
+                    .with_roughness(self.scalar_texture(roughness, "roughness", 0.5)?)
+                    .with_specular(self.scalar_texture(specular, "specular", 0.5)?)
+                    .with_sheen(self.scalar_texture(sheen, "sheen", 0.0)?, self.scalar_texture(sheen_tint, "sheen_tint", 0.5)?)
+                    .with_clearcoat(self.scalar_texture(clearcoat, "clearcoat", 0.0)?, self.scalar_texture(clearcoat_roughness, "clearcoat_roughness", 0.1)?)
+                    .with_transmission(self.scalar_texture(transmission, "transmission", 0.0)?, *ior))
+            },
+            MaterialDesc::Mix { first, second, mask } => {
+                let mask = self.scalar_texture(&Some(mask.clone()), "mix mask", 0.0)?;
+                Arc::new(MixMaterial::new(self.material_ref(first)?, self.material_ref(second)?, mask))
+            },
+            MaterialDesc::Coated { base, ior, roughness, color } => {
+                if *ior <= 0.0 {
+                    return Err(self.error("coated ior must be positive"));
+                }
+                if !(0.0..=1.0).contains(roughness) {
+                    return Err(self.error("coated roughness must be between 0 and 1"));
+                }
+                let base = self.material_ref(base)?;
+                Arc::new(CoatedMat::new(base, *ior, *roughness)
+                    .with_color(color.map(to_vec3).unwrap_or(Vec3A::ONE)))
+            },
+            MaterialDesc::NormalMap { base, map, strength } => {
+                if *strength < 0.0 {
+                    return Err(self.error("normal_map strength must not be negative"));
+                }
+                let map = self.texture(&TextureRef::Named(map.clone()))?;
+                Arc::new(PerturbedMat::from_normal_map(self.material_ref(base)?, map, *strength))
+            },
+            MaterialDesc::BumpMap { base, height, scale } => {
+                let height = self.texture(&TextureRef::Named(height.clone()))?;
+                Arc::new(PerturbedMat::from_bump_map(self.material_ref(base)?, height, *scale))
+            },
+            MaterialDesc::Isotropic { albedo } => Arc::new(IsotropicMat::from_shared_texture(self.texture(albedo)?))
+        })
+    }
+
+    fn material_ref(&self, reference: &MaterialRef) -> Result<Arc<dyn Material>, SceneError> {
+        match reference {
+            MaterialRef::Named(name) => self.materials.get(name).cloned()
+                .ok_or_else(|| self.error(format!("unknown material '{}'", name))),
+            MaterialRef::Inline(desc) => self.build_material(desc)
+        }
+    }
+
+    fn material(&self, reference: &Option<MaterialRef>) -> Result<Arc<dyn Material>, SceneError> {
+        match reference {
+            Some(reference) => self.material_ref(reference),
+            None => Err(self.error("shape has no material"))
+        }
+    }
+
+    /// Medium boundaries are never shaded, so their material is optional.
+    fn boundary_material(&self, reference: &Option<MaterialRef>) -> Result<Arc<dyn Material>, SceneError> {
+        match reference {
+            Some(_) => self.material(reference),
+            None => Ok(Arc::new(NoMaterial{}))
+        }
+    }
+
+    fn profile(&self, path: &str) -> Result<Arc<IesProfile>, SceneError> {
+        let full_path = self.resolve_path(path);
+        let profile = IesProfile::load(&full_path)
+            .map_err(|e| self.error(format!("could not load IES profile '{}': {}", full_path.display(), e)))?;
+        Ok(Arc::new(profile))
+    }
+
+    fn build_light(&self, desc: &LightDesc) -> Result<Arc<dyn PunctualLight>, SceneError> {
+        let (color, intensity) = match desc {
+            LightDesc::Point { color, intensity, .. } |
+            LightDesc::Spot { color, intensity, .. } |
+            LightDesc::Directional { color, intensity, .. } => (to_vec3(*color), *intensity)
+        };
+        if !(intensity >= 0.0 && intensity.is_finite()) || color.min_element() < 0.0 {
+            return Err(self.error("light intensity and color must not be negative"));
+        }
+
+        Ok(match desc {
+            LightDesc::Point { position, profile, axis, .. } => {
+                let mut light = PointLight::new(to_vec3(*position), color * intensity);
+                if let Some(path) = profile {
+                    if to_vec3(*axis).length_squared() == 0.0 {
+                        return Err(self.error("point light axis must not be zero"));
+                    }
+                    light = light.with_profile(LightProfile::new(self.profile(path)?, to_vec3(*axis)));
+                }
+                Arc::new(light)
+            },
+            LightDesc::Spot { position, target, outer_angle, inner_angle, profile, .. } => {
+                let direction = to_vec3(*target) - to_vec3(*position);
+                if direction.length_squared() == 0.0 {
+                    return Err(self.error("spot light target must differ from its position"));
+                }
+                let inner_angle = inner_angle.unwrap_or(*outer_angle);
+                if !(*outer_angle > 0.0 && *outer_angle <= 180.0 && (0.0..=*outer_angle).contains(&inner_angle)) {
+                    return Err(self.error("spot light angles must satisfy 0 <= inner_angle <= outer_angle <= 180"));
+                }
+                let mut light = SpotLight::new(to_vec3(*position), direction, color * intensity,
+                    inner_angle.to_radians(), outer_angle.to_radians());
+                if let Some(path) = profile {
+                    light = light.with_profile(self.profile(path)?);
+                }
+                Arc::new(light)
+            },
+            LightDesc::Directional { direction, .. } => {
+                if to_vec3(*direction).length_squared() == 0.0 {
+                    return Err(self.error("directional light direction must not be zero"));
+                }
+                Arc::new(DirectionalLight::new(to_vec3(*direction), color * intensity))
+            }
+        })
+    }
+
+    fn build_transform(&self, ops: &[TransformOp]) -> Result<Mat4, SceneError> {
+        let mut transform = Mat4::IDENTITY;
+        for op in ops {
+            let op_matrix = match *op {
+                TransformOp::Translate(t) => Mat4::from_translation(Vec3::new(t[0], t[1], t[2])),
+                TransformOp::RotateX(deg) => Mat4::from_rotation_x(deg.to_radians()),
+                TransformOp::RotateY(deg) => Mat4::from_rotation_y(deg.to_radians()),
+                TransformOp::RotateZ(deg) => Mat4::from_rotation_z(deg.to_radians()),
+                TransformOp::Scale(s) => {
+                    if s.iter().any(|v| v.abs() < f32::EPSILON) {
+                        return Err(self.error("transform scale must not be zero"));
+                    }
+                    Mat4::from_scale(Vec3::new(s[0], s[1], s[2]))
+                }
+            };
+            transform = op_matrix * transform;
+        }
+        Ok(transform)
+    }
+
+    fn build_shape(&self, desc: &ShapeDesc, in_boundary: bool) -> Result<Arc<dyn Hittable>, SceneError> {
+        Ok(self.build_sampled_shape(desc, in_boundary)?.0)
+    }
+
+    /// `build_shape`, also returning the material of spheres and rects
+    /// without a transform, the shapes that can be sampled as lights.
+    fn build_sampled_shape(&self, desc: &ShapeDesc, in_boundary: bool) -> Result<SampledShape, SceneError> {
+        let material = |name: &Option<MaterialRef>| {
+            if in_boundary {
+                self.boundary_material(name)
+            } else {
+                self.material(name)
+            }
+        };
+
+        let mut sampled_material = None;
+        let shape: Arc<dyn Hittable> = match desc {
+            ShapeDesc::Sphere { center, radius, material: name, .. } => {
+                if *radius <= 0.0 {
+                    return Err(self.error("sphere radius must be positive"));
+                }
+                let material = material(name)?;
+                sampled_material = Some(material.clone());
+                Arc::new(Sphere::new_with_material(to_vec3(*center), *radius, material))
+            },
+            ShapeDesc::MovingSphere { center_start, center_end, radius, time, material: name, .. } => {
+                if *radius <= 0.0 {
+                    return Err(self.error("sphere radius must be positive"));
+                }
+                if time[1] <= time[0] {
+                    return Err(self.error("moving sphere time must be an increasing [start, end] pair"));
+                }
+                Arc::new(MovingSphere::new_with_material(to_vec3(*center_start), to_vec3(*center_end), *radius, time[0], time[1], material(name)?))
+            },
+            ShapeDesc::XyRect { min, max, offset, material: name, .. } => {
+                self.check_rect(min, max)?;
+                let material = material(name)?;
+                sampled_material = Some(material.clone());
+                Arc::new(XYRect::new_with_material(to_vec2(*min), to_vec2(*max), *offset, material))
+            },
+            ShapeDesc::XzRect { min, max, offset, material: name, .. } => {
+                self.check_rect(min, max)?;
+                let material = material(name)?;
+                sampled_material = Some(material.clone());
+                Arc::new(XZRect::new_with_material(to_vec2(*min), to_vec2(*max), *offset, material))
+            },
+            ShapeDesc::YzRect { min, max, offset, material: name, .. } => {
+                self.check_rect(min, max)?;
+                let material = material(name)?;
+                sampled_material = Some(material.clone());
+                Arc::new(YZRect::new_with_material(to_vec2(*min), to_vec2(*max), *offset, material))
+            },
+            ShapeDesc::Box { size, min, max, material: name, .. } => {
+                let (min, max) = match (size, min, max) {
+                    (Some(size), None, None) => (Vec3A::ZERO, to_vec3(*size)),
+                    (None, Some(min), Some(max)) => (to_vec3(*min), to_vec3(*max)),
+                    _ => return Err(self.error("box needs either 'size' or both 'min' and 'max'"))
+                };
+                if min.cmpge(max).any() {
+                    return Err(self.error("box min must be smaller than max on every axis"));
+                }
+                Arc::new(Box2::full_box(min, max, material(name)?))
+            },
+            ShapeDesc::ConstantMedium { boundary, density, material: name, albedo, .. } => {
+                if *density <= 0.0 {
+                    return Err(self.error("medium density must be positive"));
+                }
+                let boundary = self.build_shape(boundary, true)?;
+                match (name, albedo) {
+                    (Some(_), None) => Arc::new(ConstantMedium::new_with_material(boundary, *density, self.material(name)?)),
+                    (None, Some(albedo)) => Arc::new(ConstantMedium::new(boundary, *density, self.texture(albedo)?)),
+                    _ => return Err(self.error("medium needs either a 'material' or an 'albedo'"))
+                }
+            },
+            ShapeDesc::Mesh { path, material: name, .. } => {
+                let full_path = self.resolve_path(path);
+                let override_material = match (name, in_boundary) {
+                    (None, true) => Some(self.boundary_material(name)?),
+                    (None, false) => None,
+                    (Some(_), _) => Some(self.material(name)?)
+                };
+                let mesh = Mesh::load_obj(&full_path, override_material)
+                    .map_err(|e| self.error(format!("could not load mesh '{}': {}", full_path.display(), e)))?;
+                Arc::new(mesh)
+            },
+            ShapeDesc::Group { children, .. } => {
+                if children.is_empty() {
+                    return Err(self.error("group has no children"));
+                }
+                let children = children.iter()
+                    .map(|child| self.build_shape(child, in_boundary))
+                    .collect::<Result<Vec<Arc<dyn Hittable>>, SceneError>>()?;
+                Arc::new(FlatBVH::from_vector(children, 0.0, 1.0))
+            }
+        };
+
+        let transform = desc.transform();
+        if transform.is_empty() {
+            return Ok((shape, sampled_material));
+        }
+        Ok((Arc::new(TransformedObject::new(shape, self.build_transform(transform)?)), None))
+    }
+
+    fn check_rect(&self, min: &[f32; 2], max: &[f32; 2]) -> Result<(), SceneError> {
+        if min[0] >= max[0] || min[1] >= max[1] {
+            return Err(self.error("rect min must be smaller than max on both axes"));
+        }
+        Ok(())
+    }
+}
+
+/// Parses `source` as a scene description. `path` is used for error messages
+/// and to resolve relative image paths.
+/// `seed` drives everything random in the scene, such as noise textures.
+pub fn parse_scene(path: &Path, source: &str, seed: u64) -> Result<LoadedScene, SceneError> {
+    let file: SceneFile = toml::from_str(source).map_err(|e| SceneError {
+        path: path.to_path_buf(),
+        location: e.span().map(|span| line_column(source, span.start)),
+        message: e.message().to_string()
+    })?;
+
+    let mut builder = Builder {
+        path,
+        source,
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        span: file.camera.span(),
+        rng: Pcg32::new(seed, SCENE_STREAM)
+    };
+
+    let camera_desc = file.camera.get_ref();
+    if !(camera_desc.fov > 0.0 && camera_desc.fov < 180.0) {
+        return Err(builder.error("camera fov must be between 0 and 180 degrees"));
+    }
+    if camera_desc.aperture < 0.0 || camera_desc.focus_distance <= 0.0 {
+        return Err(builder.error("camera aperture must not be negative and focus_distance must be positive"));
+    }
+    if camera_desc.shutter[1] < camera_desc.shutter[0] {
+        return Err(builder.error("camera shutter must be an increasing [open, close] pair"));
+    }
+    let camera = CameraSettings {
+        origin: to_vec3(camera_desc.origin),
+        target: to_vec3(camera_desc.target),
+        up: to_vec3(camera_desc.up),
+        vfov: camera_desc.fov.to_radians(),
+        aperture: camera_desc.aperture,
+        focus_distance: camera_desc.focus_distance,
+        time_start: camera_desc.shutter[0],
+        time_end: camera_desc.shutter[1]
+    };
+
+    let environment = match &file.environment {
+        Some(desc) => {
+            builder.span = desc.span();
+            let desc = desc.get_ref();
+            if !(desc.intensity >= 0.0 && desc.intensity.is_finite()) {
+                return Err(builder.error("environment intensity must not be negative"));
+            }
+            Some(EnvironmentSettings {
+                path: builder.resolve_path(&desc.map),
+                rotation: desc.rotation.to_radians(),
+                intensity: desc.intensity
+            })
+        },
+        None => None
+    };
+
+    let sky = match &file.sky {
+        Some(desc) => {
+            builder.span = desc.span();
+            if file.environment.is_some() {
+                return Err(builder.error("a scene can't have both a sky and an environment map"));
+            }
+            let desc = desc.get_ref();
+            let model = SkyModel::from_name(&desc.model).ok_or_else(|| builder.error(format!(
+                "unknown sky model '{}', expected one of: {}", desc.model,
+                SkyModel::NAMES.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", "))))?;
+            let mut sky = SkySettings::new(model);
+            sky.sun_elevation = desc.sun_elevation.map(f32::to_radians).unwrap_or(sky.sun_elevation);
+            sky.sun_azimuth = desc.sun_azimuth.map(f32::to_radians).unwrap_or(sky.sun_azimuth);
+            sky.turbidity = desc.turbidity.unwrap_or(sky.turbidity);
+            sky.sun_radius = desc.sun_radius.map(f32::to_radians).unwrap_or(sky.sun_radius);
+            sky.intensity = desc.intensity.unwrap_or(sky.intensity);
+            sky.bottom = desc.bottom.map(to_vec3).unwrap_or(sky.bottom);
+            sky.top = desc.top.map(to_vec3).unwrap_or(sky.top);
+            if let Err(message) = sky.validate() {
+                return Err(builder.error(message));
+            }
+            Some(sky)
+        },
+        None => None
+    };
+
+    // Textures and materials can be made of others, build those first
+    let texture_order = dependency_order(&file.textures, TextureDesc::references)
+        .map_err(|cycle| {
+            builder.span = file.textures[cycle[0]].span();
+            cycle_error(&builder, "textures", &cycle)
+        })?;
+    for name in texture_order {
+        let desc = &file.textures[name];
+        builder.span = desc.span();
+        let texture = builder.build_texture(desc.get_ref())?;
+        builder.textures.insert(name.clone(), texture);
+    }
+
+    let material_order = dependency_order(&file.materials, MaterialDesc::references)
+        .map_err(|cycle| {
+            builder.span = file.materials[cycle[0]].span();
+            cycle_error(&builder, "materials", &cycle)
+        })?;
+    for name in material_order {
+        let desc = &file.materials[name];
+        builder.span = desc.span();
+        let material = builder.build_material(desc.get_ref())?;
+        builder.materials.insert(name.clone(), material);
+    }
+
+    let mut world = Scene::new();
+    for desc in file.shapes.iter() {
+        builder.span = desc.span();
+        let (shape, sampled_material) = builder.build_sampled_shape(desc.get_ref(), false)?;
+        match sampled_material {
+            Some(material) if material.is_emissive() => world.add_shared_light(shape),
+            _ => world.add_shared_shape(shape)
+        }
+    }
+
+    for desc in file.lights.iter() {
+        builder.span = desc.span();
+        let light = builder.build_light(desc.get_ref())?;
+        world.add_shared_punctual_light(light);
+    }
+
+    if world.shapes.is_empty() {
+        return Err(SceneError {
+            path: path.to_path_buf(),
+            location: None,
+            message: String::from("scene has no shapes")
+        });
+    }
+
+    Ok(LoadedScene {
+        world,
+        camera,
+        background: to_vec3(file.background),
+        environment,
+        sky,
+        image: file.image
+    })
+}
+
+pub fn load_scene<P: AsRef<Path>>(path: P, seed: u64) -> Result<LoadedScene, SceneError> {
+    let path = path.as_ref();
+    let source = std::fs::read_to_string(path).map_err(|e| SceneError {
+        path: path.to_path_buf(),
+        location: None,
+        message: e.to_string()
+    })?;
+
+    parse_scene(path, &source, seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "[camera]\norigin = [0.0, 0.0, 5.0]\ntarget = [0.0, 0.0, 0.0]\nfov = 40.0\n";
+
+    fn parse(source: &str) -> Result<LoadedScene, SceneError> {
+        parse_scene(Path::new("test.toml"), &format!("{}{}", CAMERA, source), 0)
+    }
+
+    fn parse_error(source: &str) -> SceneError {
+        match parse(source) {
+            Ok(_) => panic!("scene should not load"),
+            Err(error) => error
+        }
+    }
+
+    #[test]
+    fn texture_cycles_are_named() {
+        let error = parse_error(r#"
+[textures.a]
+type = "checkered"
+odd = "b"
+even = [1.0, 1.0, 1.0]
+
+[textures.b]
+type = "channel"
+texture = "a"
+channel = "r"
+"#);
+        assert_eq!(error.message, "textures reference each other in a cycle: 'a' -> 'b' -> 'a'");
+        assert_eq!(error.location, Some((6, 1)));
+    }
+
+    #[test]
+    fn material_cycles_through_inline_materials_are_named() {
+        let error = parse_error(r#"
+[materials.plain]
+type = "lambertian"
+albedo = [0.5, 0.5, 0.5]
+
+[materials.paint]
+type = "coated"
+base = { type = "mix", first = "plain", second = "blend", mask = 0.5 }
+
+[materials.blend]
+type = "mix"
+first = "plain"
+second = "paint"
+mask = 0.5
+"#);
+        assert_eq!(error.message, "materials reference each other in a cycle: 'blend' -> 'paint' -> 'blend'");
+    }
+
+    #[test]
+    fn references_resolve_out_of_order() {
+        let scene = parse(r#"
+[materials.a_blend]
+type = "mix"
+first = "z_white"
+second = { type = "coated", base = "z_white" }
+mask = "z_mask"
+
+[materials.z_white]
+type = "lambertian"
+albedo = "z_checker"
+
+[textures.a_dark]
+type = "solid"
+color = [0.1, 0.1, 0.1]
+
+[textures.z_checker]
+type = "checkered"
+odd = "a_dark"
+even = "z_light"
+
+[textures.z_light]
+type = "solid"
+color = [0.9, 0.9, 0.9]
+
+[textures.z_mask]
+type = "channel"
+texture = "z_checker"
+channel = "r"
+
+[[shapes]]
+type = "sphere"
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+material = "a_blend"
+"#);
+        assert!(scene.is_ok());
+    }
+
+    #[test]
+    fn material_ids_follow_the_shapes() {
+        let source = r#"
+[materials.white]
+type = "lambertian"
+albedo = [0.73, 0.73, 0.73]
+
+[[shapes]]
+type = "sphere"
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+material = { type = "metal", albedo = [0.8, 0.8, 0.8], fuzz = 0.1 }
+
+[[shapes]]
+type = "sphere"
+center = [2.0, 0.0, 0.0]
+radius = 1.0
+material = "white"
+
+[[shapes]]
+type = "sphere"
+center = [4.0, 0.0, 0.0]
+radius = 1.0
+material = "white"
+"#;
+        let shape_ids = || {
+            let world = parse(source).ok().unwrap().world;
+            let ids = world.material_ids();
+            world.shapes.iter().map(|shape| {
+                let mut shape_ids = Vec::new();
+                shape.visit_materials(&mut |material| shape_ids.push(ids.get(material)));
+                shape_ids
+            }).collect::<Vec<_>>()
+        };
+
+        let first = shape_ids();
+        assert_eq!(first, vec![vec![1], vec![2], vec![2]]);
+
+        // Creating other materials in between doesn't move the ids
+        let _unrelated = LambertianMat::from_color(Vec3A::ONE);
+        assert_eq!(shape_ids(), first);
+    }
+}
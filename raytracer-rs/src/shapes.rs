@@ -7,13 +7,70 @@ use crate::hit_record::HitRecord;
 use crate::materials::*;//Material;
 use crate::aabb::AABB;
 use crate::scene::Scene;
+use crate::texture::Texture;
+use crate::vec3_helpers;
+use crate::sampler::Sampler;
 
 use std::sync::Arc;
-use rand::prelude::*;
 
-pub trait Hittable {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+pub trait Hittable: Send + Sync {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord>;
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB>;
+
+    /// Solid angle density with which `random_direction` picks `direction`
+    /// from `origin`, using `sampler` for whatever intersecting the shape
+    /// needs. Shapes that can't be sampled as lights return zero.
+    fn pdf_value(&self, _origin: Vec3A, _direction: Vec3A, _sampler: &mut dyn Sampler) -> f32 {
+        0.0
+    }
+
+    /// A direction from `origin` towards a random point on the shape, picked
+    /// by the uniform sample `u`.
+    fn random_direction(&self, _origin: Vec3A, _u: Vec2) -> Vec3A {
+        Vec3A::Y
+    }
+
+    /// Calls `visit` with every material hits on the shape can carry.
+    fn visit_materials(&self, _visit: &mut dyn FnMut(&Arc<dyn Material>)) {}
+}
+
+impl<T: Hittable + ?Sized> Hittable for Arc<T> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        (**self).intersect(ray, t_min, t_max, sampler)
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        (**self).bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        (**self).pdf_value(origin, direction, sampler)
+    }
+
+    fn random_direction(&self, origin: Vec3A, u: Vec2) -> Vec3A {
+        (**self).random_direction(origin, u)
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        (**self).visit_materials(visit)
+    }
+}
+
+/// Converts the area density of sampling a point on a flat shape to a solid
+/// angle density as seen along `direction`.
+fn area_pdf_to_solid_angle(shape: &dyn Hittable, origin: Vec3A, direction: Vec3A, area: f32, sampler: &mut dyn Sampler) -> f32 {
+    let ray = Ray{ origin, direction, time: 0.0 };
+    match shape.intersect(&ray, 0.001, f32::INFINITY, sampler) {
+        Some(record) => {
+            let distance_squared = record.t * record.t * direction.length_squared();
+            let cosine = f32::abs(direction.dot(record.normal)) / direction.length();
+            if cosine <= 0.0 {
+                return 0.0;
+            }
+            distance_squared / (cosine * area)
+        },
+        None => 0.0
+    }
 }
 
 fn get_sphere_uv(point: Vec3A) -> (f32, f32) {
@@ -23,10 +80,26 @@ fn get_sphere_uv(point: Vec3A) -> (f32, f32) {
     (phi / (2.0 * std::f32::consts::PI), theta / std::f32::consts::PI)
 }
 
+/// ∂p/∂u and ∂p/∂v of the `get_sphere_uv` mapping for a unit `normal`.
+fn get_sphere_tangents(normal: Vec3A, radius: f32) -> (Vec3A, Vec3A) {
+    let sin_theta = f32::sqrt(normal.x * normal.x + normal.z * normal.z);
+    if sin_theta < 1e-6 {
+        // Both derivatives vanish at the poles, any frame will do
+        return vec3_helpers::orthonormal_basis(normal);
+    }
+    let pi = std::f32::consts::PI;
+    let tangent = 2.0 * pi * radius * Vec3A::new(normal.z, 0.0, -normal.x);
+    let bitangent = pi * radius * Vec3A::new(-normal.x * normal.y / sin_theta, sin_theta, -normal.y * normal.z / sin_theta);
+    (tangent, bitangent)
+}
+
 pub struct TransformedObject<T: Hittable> {
     object: T,
     transform: Mat4,
     inv_transform: Mat4,
+    /// Inverse transpose of `transform`, which keeps normals perpendicular
+    /// to the surface under non-uniform scaling
+    normal_transform: Mat4,
     aabb: AABB
 }
 
@@ -34,9 +107,10 @@ impl<T: Hittable> TransformedObject<T> {
     pub fn new(object: T, transform: Mat4) -> Self {
         Self{
             aabb: Self::generate_aabb(&object, transform),
-            object: object,
-            transform: transform,
-            inv_transform: transform.inverse()
+            object,
+            transform,
+            inv_transform: transform.inverse(),
+            normal_transform: transform.inverse().transpose()
         }
     }
 
@@ -62,37 +136,129 @@ impl<T: Hittable> TransformedObject<T> {
 }
 
 impl<T: Hittable> Hittable for TransformedObject<T> {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let local_ray = Ray{ 
             origin: self.inv_transform.transform_point3a(ray.origin), 
             direction: self.inv_transform.transform_vector3a(ray.direction),
             time: ray.time
         };
 
-        let result = self.object.intersect(&local_ray, t_min, t_max);
+        let result = self.object.intersect(&local_ray, t_min, t_max, sampler);
 
         if let Some(record) = result {
-            let normal = self.transform.transform_vector3a(record.normal).normalize();
+            // The local normal faces the ray, turn it outward again so the
+            // facing can be decided against the world ray
+            let local_outward = if record.front_face { record.geometric_normal } else { -record.geometric_normal };
+            let normal = self.normal_transform.transform_vector3a(local_outward).normalize();
+            let shading_normal = self.normal_transform.transform_vector3a(record.normal).normalize();
             let point = self.transform.transform_point3a(record.point);
             let mut record = HitRecord{
-                point: point, //ray.at(record.t),
+                point, //ray.at(record.t),
                 t: record.t,
-                normal: normal,
+                normal,
+                geometric_normal: normal,
+                tangent: self.transform.transform_vector3a(record.tangent),
+                bitangent: self.transform.transform_vector3a(record.bitangent),
                 material: record.material,
                 tex_coords: record.tex_coords,
-                front_face: record.front_face
+                front_face: record.front_face,
+                object_id: record.object_id
             };
 
             record.set_face_normal(ray, &normal);
+            record.normal = if shading_normal.dot(record.geometric_normal) < 0.0 { -shading_normal } else { shading_normal };
 
             return Some(record); 
         }
 
-        return None;
+        None
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
-        return Some(self.aabb);
+        Some(self.aabb)
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        self.object.visit_materials(visit)
+    }
+}
+
+/// Tags every hit on `object` with `id`, replacing whatever id the parts of
+/// the object set.
+pub struct IdentifiedObject<T: Hittable> {
+    object: T,
+    id: u32
+}
+
+impl<T: Hittable> IdentifiedObject<T> {
+    pub fn new(object: T, id: u32) -> Self {
+        IdentifiedObject {
+            object,
+            id
+        }
+    }
+}
+
+impl<T: Hittable> Hittable for IdentifiedObject<T> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        let mut record = self.object.intersect(ray, t_min, t_max, sampler)?;
+        record.object_id = self.id;
+        Some(record)
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.object.bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        self.object.pdf_value(origin, direction, sampler)
+    }
+
+    fn random_direction(&self, origin: Vec3A, u: Vec2) -> Vec3A {
+        self.object.random_direction(origin, u)
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        self.object.visit_materials(visit)
+    }
+}
+
+/// Turns the outward side of `object` inwards, for shapes whose fixed normal
+/// points into the solid they bound.
+pub struct FlipFace<T: Hittable> {
+    object: T
+}
+
+impl<T: Hittable> FlipFace<T> {
+    pub fn new(object: T) -> Self {
+        FlipFace {
+            object
+        }
+    }
+}
+
+impl<T: Hittable> Hittable for FlipFace<T> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        // The normal already faces the ray, only the side changes
+        let mut record = self.object.intersect(ray, t_min, t_max, sampler)?;
+        record.front_face = !record.front_face;
+        Some(record)
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.object.bounding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        self.object.pdf_value(origin, direction, sampler)
+    }
+
+    fn random_direction(&self, origin: Vec3A, u: Vec2) -> Vec3A {
+        self.object.random_direction(origin, u)
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        self.object.visit_materials(visit)
     }
 }
 
@@ -106,15 +272,23 @@ pub struct Sphere {
 impl Sphere {
     pub fn new<T: 'static + Material>(center: Vec3A, radius: f32, material: T) -> Sphere {
         Sphere {
-            center: center,
-            radius: radius,
+            center,
+            radius,
             material: Arc::new(material)
         }
     }
+
+    pub fn new_with_material(center: Vec3A, radius: f32, material: Arc::<dyn Material>) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material
+        }
+    }
 }
 
 impl Hittable for Sphere {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let oc = ray.origin - self.center;
         let a = ray.direction.length_squared();
         let half_b = oc.dot(ray.direction);
@@ -141,26 +315,66 @@ impl Hittable for Sphere {
         let point = ray.at(root);
         let outward_normal = (point - self.center) / self.radius;
 
+        let (tangent, bitangent) = get_sphere_tangents(outward_normal, self.radius);
         let mut record = HitRecord{
             t: root,
-            point: point,
+            point,
             normal: outward_normal,
+            geometric_normal: outward_normal,
+            tangent,
+            bitangent,
             material: self.material.clone(),
             tex_coords: get_sphere_uv(outward_normal),
-            front_face: true
+            front_face: true,
+            object_id: 0
         };
 
-        record.set_face_normal(&ray, &outward_normal);
+        record.set_face_normal(ray, &outward_normal);
 
-        return Option::Some(record);
+        Option::Some(record)
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
         let offset = Vec3A::new(self.radius, self.radius, self.radius);
-        return Option::Some(AABB {
+        Option::Some(AABB {
             min: self.center - offset,
             max: self.center + offset
-        });
+        })
+    }
+
+    // Samples the cone of directions the sphere covers, or every direction
+    // when the origin is inside it
+    fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        let ray = Ray{ origin, direction, time: 0.0 };
+        if self.intersect(&ray, 0.001, f32::INFINITY, sampler).is_none() {
+            return 0.0;
+        }
+
+        let distance_squared = (self.center - origin).length_squared();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return 1.0 / (4.0 * std::f32::consts::PI);
+        }
+
+        let cos_theta_max = f32::sqrt(1.0 - radius_squared / distance_squared);
+        let solid_angle = 2.0 * std::f32::consts::PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+    }
+
+    fn random_direction(&self, origin: Vec3A, u: Vec2) -> Vec3A {
+        let to_center = self.center - origin;
+        let distance_squared = to_center.length_squared();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return vec3_helpers::sample_sphere(u);
+        }
+
+        let cos_theta_max = f32::sqrt(1.0 - radius_squared / distance_squared);
+        vec3_helpers::sample_cone(to_center / distance_squared.sqrt(), cos_theta_max, u)
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
     }
 }
 
@@ -177,22 +391,33 @@ pub struct MovingSphere {
 impl MovingSphere {
     pub fn new<T: 'static + Material>(center_start: Vec3A, center_end: Vec3A, radius: f32, time_start: f32, time_end: f32, material: T) -> MovingSphere {
         MovingSphere {
-            center_start: center_start,
-            center_end: center_end,
-            time_start: time_start,
-            time_end: time_end,
-            radius: radius,
+            center_start,
+            center_end,
+            time_start,
+            time_end,
+            radius,
             material: Arc::new(material)
         }
     }
 
+    pub fn new_with_material(center_start: Vec3A, center_end: Vec3A, radius: f32, time_start: f32, time_end: f32, material: Arc::<dyn Material>) -> MovingSphere {
+        MovingSphere {
+            center_start,
+            center_end,
+            time_start,
+            time_end,
+            radius,
+            material
+        }
+    }
+
     pub fn center(&self, time: f32) -> Vec3A {
-        return self.center_start + ((time - self.time_start) / (self.time_end - self.time_start) * (self.center_end - self.center_start));
+        self.center_start + ((time - self.time_start) / (self.time_end - self.time_start) * (self.center_end - self.center_start))
     }
 }
 
 impl Hittable for MovingSphere {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let oc = ray.origin - self.center(ray.time);
         let a = ray.direction.length_squared();
         let half_b = oc.dot(ray.direction);
@@ -219,18 +444,23 @@ impl Hittable for MovingSphere {
         let point = ray.at(root);
         let outward_normal = (point - self.center(ray.time)) / self.radius;
 
+        let (tangent, bitangent) = get_sphere_tangents(outward_normal, self.radius);
         let mut record = HitRecord{
             t: root,
             point: ray.at(root),
             normal: outward_normal,
+            geometric_normal: outward_normal,
+            tangent,
+            bitangent,
             material: self.material.clone(),
             tex_coords: get_sphere_uv(outward_normal),
-            front_face: true
+            front_face: true,
+            object_id: 0
         };
 
-        record.set_face_normal(&ray, &outward_normal);
+        record.set_face_normal(ray, &outward_normal);
 
-        return Option::Some(record);
+        Option::Some(record)
     }
 
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
@@ -245,7 +475,11 @@ impl Hittable for MovingSphere {
             max: self.center(t1) + offset
         };
 
-        return Some(AABB::surrounding_box(&start, &end))
+        Some(AABB::surrounding_box(&start, &end))
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
     }
 }
 
@@ -259,25 +493,25 @@ pub struct XYRect {
 impl XYRect {
     pub fn new<T: 'static + Material>(min: Vec2, max: Vec2, offset: f32, material: T) -> Self {
         XYRect {
-            min: min,
-            max: max,
-            offset: offset,
+            min,
+            max,
+            offset,
             material: Arc::new(material)
         }
     }
 
     pub fn new_with_material(min: Vec2, max: Vec2, offset: f32, material: Arc::<dyn Material>) -> Self {
         Self {
-            min: min,
-            max: max,
-            offset: offset,
+            min,
+            max,
+            offset,
             material: material.clone()
         }
     }
 }
 
 impl Hittable for XYRect {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let t = (self.offset - ray.origin.z) / ray.direction.z;
 
         if t < t_min || t > t_max {
@@ -292,17 +526,21 @@ impl Hittable for XYRect {
         }
 
         let mut record = HitRecord {
-            t: t,
+            t,
             point: ray.at(t),
             tex_coords: ((x - self.min.x) / (self.max.x - self.min.x), (y - self.min.y) / (self.max.y - self.min.y)),
             normal: Vec3A::Z,
+            geometric_normal: Vec3A::Z,
+            tangent: Vec3A::X * (self.max.x - self.min.x),
+            bitangent: Vec3A::Y * (self.max.y - self.min.y),
             material: self.material.clone(),
-            front_face: true
+            front_face: true,
+            object_id: 0
         };
 
         record.set_face_normal(ray, &Vec3A::Z);
 
-        return Some(record);
+        Some(record)
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
@@ -311,6 +549,21 @@ impl Hittable for XYRect {
             max: Vec3A::new(self.max.x, self.max.y, self.offset + 0.0001),
         })
     }
+
+    fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        area_pdf_to_solid_angle(self, origin, direction, area, sampler)
+    }
+
+    fn random_direction(&self, origin: Vec3A, u: Vec2) -> Vec3A {
+        let a = self.min.x + u.x * (self.max.x - self.min.x);
+        let b = self.min.y + u.y * (self.max.y - self.min.y);
+        Vec3A::new(a, b, self.offset) - origin
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
+    }
 }
 
 pub struct XZRect {
@@ -323,25 +576,25 @@ pub struct XZRect {
 impl XZRect {
     pub fn new<T: 'static + Material>(min: Vec2, max: Vec2, offset: f32, material: T) -> Self {
         XZRect {
-            min: min,
-            max: max,
-            offset: offset,
+            min,
+            max,
+            offset,
             material: Arc::new(material)
         }
     }
 
     pub fn new_with_material(min: Vec2, max: Vec2, offset: f32, material: Arc::<dyn Material>) -> Self {
         Self {
-            min: min,
-            max: max,
-            offset: offset,
+            min,
+            max,
+            offset,
             material: material.clone()
         }
     }
 }
 
 impl Hittable for XZRect {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let t = (self.offset - ray.origin.y) / ray.direction.y;
 
         if t < t_min || t > t_max {
@@ -356,17 +609,21 @@ impl Hittable for XZRect {
         }
 
         let mut record = HitRecord {
-            t: t,
+            t,
             point: ray.at(t),
             tex_coords: ((x - self.min.x) / (self.max.x - self.min.x), (z - self.min.y) / (self.max.y - self.min.y)),
             normal: Vec3A::Y,
+            geometric_normal: Vec3A::Y,
+            tangent: Vec3A::X * (self.max.x - self.min.x),
+            bitangent: Vec3A::Z * (self.max.y - self.min.y),
             material: self.material.clone(),
-            front_face: true
+            front_face: true,
+            object_id: 0
         };
 
         record.set_face_normal(ray, &Vec3A::Y);
 
-        return Some(record);
+        Some(record)
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
@@ -375,6 +632,21 @@ impl Hittable for XZRect {
             max: Vec3A::new(self.max.x, self.offset + 0.0001, self.max.y),
         })
     }
+
+    fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        area_pdf_to_solid_angle(self, origin, direction, area, sampler)
+    }
+
+    fn random_direction(&self, origin: Vec3A, u: Vec2) -> Vec3A {
+        let a = self.min.x + u.x * (self.max.x - self.min.x);
+        let b = self.min.y + u.y * (self.max.y - self.min.y);
+        Vec3A::new(a, self.offset, b) - origin
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
+    }
 }
 
 pub struct YZRect {
@@ -387,25 +659,25 @@ pub struct YZRect {
 impl YZRect {
     pub fn new<T: 'static + Material>(min: Vec2, max: Vec2, offset: f32, material: T) -> Self {
         YZRect {
-            min: min,
-            max: max,
-            offset: offset,
+            min,
+            max,
+            offset,
             material: Arc::new(material)
         }
     }
 
     pub fn new_with_material(min: Vec2, max: Vec2, offset: f32, material: Arc::<dyn Material>) -> Self {
         Self {
-            min: min,
-            max: max,
-            offset: offset,
+            min,
+            max,
+            offset,
             material: material.clone()
         }
     }
 }
 
 impl Hittable for YZRect {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let t = (self.offset - ray.origin.x) / ray.direction.x;
 
         if t < t_min || t > t_max {
@@ -420,17 +692,21 @@ impl Hittable for YZRect {
         }
 
         let mut record = HitRecord {
-            t: t,
+            t,
             point: ray.at(t),
             tex_coords: ((x - self.min.x) / (self.max.x - self.min.x), (y - self.min.y) / (self.max.y - self.min.y)),
             normal: Vec3A::X,
+            geometric_normal: Vec3A::X,
+            tangent: Vec3A::Y * (self.max.x - self.min.x),
+            bitangent: Vec3A::Z * (self.max.y - self.min.y),
             material: self.material.clone(),
-            front_face: true
+            front_face: true,
+            object_id: 0
         };
 
         record.set_face_normal(ray, &Vec3A::X);
 
-        return Some(record);
+        Some(record)
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
@@ -439,6 +715,21 @@ impl Hittable for YZRect {
             max: Vec3A::new(self.offset + 0.0001, self.max.x, self.max.y),
         })
     }
+
+    fn pdf_value(&self, origin: Vec3A, direction: Vec3A, sampler: &mut dyn Sampler) -> f32 {
+        let area = (self.max.x - self.min.x) * (self.max.y - self.min.y);
+        area_pdf_to_solid_angle(self, origin, direction, area, sampler)
+    }
+
+    fn random_direction(&self, origin: Vec3A, u: Vec2) -> Vec3A {
+        let a = self.min.x + u.x * (self.max.x - self.min.x);
+        let b = self.min.y + u.y * (self.max.y - self.min.y);
+        Vec3A::new(self.offset, a, b) - origin
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
+    }
 }
 
 pub struct Box2 {
@@ -456,27 +747,29 @@ impl Box2 {
     pub fn full_box(min: Vec3A, max: Vec3A, color: Arc<dyn Material>) -> Self{
         let mut sides = Scene::new();
 
-        sides.add_shape(XYRect::new_with_material(Vec2::new(min.x, min.y), Vec2::new(max.x, max.y), min.z, color.clone()));
+        // The rects face +X, +Y and +Z, so the sides at the minimum are
+        // flipped to face out of the box like the others
+        sides.add_shape(FlipFace::new(XYRect::new_with_material(Vec2::new(min.x, min.y), Vec2::new(max.x, max.y), min.z, color.clone())));
         sides.add_shape(XYRect::new_with_material(Vec2::new(min.x, min.y), Vec2::new(max.x, max.y), max.z, color.clone()));
 
-        sides.add_shape(XZRect::new_with_material(Vec2::new(min.x, min.z), Vec2::new(max.x, max.z), min.y, color.clone()));
+        sides.add_shape(FlipFace::new(XZRect::new_with_material(Vec2::new(min.x, min.z), Vec2::new(max.x, max.z), min.y, color.clone())));
         sides.add_shape(XZRect::new_with_material(Vec2::new(min.x, min.z), Vec2::new(max.x, max.z), max.y, color.clone()));
 
-        sides.add_shape(YZRect::new_with_material(Vec2::new(min.y, min.z), Vec2::new(max.y, max.z), min.x, color.clone()));
+        sides.add_shape(FlipFace::new(YZRect::new_with_material(Vec2::new(min.y, min.z), Vec2::new(max.y, max.z), min.x, color.clone())));
         sides.add_shape(YZRect::new_with_material(Vec2::new(min.y, min.z), Vec2::new(max.y, max.z), max.x, color.clone()));
 
         Self {
-            min: min,
-            max: max,
-            sides: sides,
+            min,
+            max,
+            sides,
             material: color.clone()
         }
     }
 }
 
 impl Hittable for Box2 {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        self.sides.intersect(ray, t_min, t_max)
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        self.sides.intersect(ray, t_min, t_max, sampler)
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
@@ -485,6 +778,10 @@ impl Hittable for Box2 {
             max: self.max
         })
     }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
+    }
 }
 
 pub struct Box {
@@ -504,8 +801,8 @@ impl Box {
 
     pub fn full_box(min: Vec3A, max: Vec3A, color: Arc<dyn Material>) -> Self{
         Box{
-            min: min,
-            max: max,
+            min,
+            max,
             material: color.clone()
         }
     }
@@ -513,28 +810,28 @@ impl Box {
 
 fn axis_min(first: (f32, usize), second: (f32, usize)) -> (f32, usize) {
     if first.0 < second.0 {
-        return first;
+        first
     } else {
-        return second;
+        second
     }
 }
 
 fn axis_max(first: (f32, usize), second: (f32, usize)) -> (f32, usize) {
     if first.0 > second.0 {
-        return first;
+        first
     } else {
-        return second;
+        second
     }
 }
 
 impl Hittable for Box {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn intersect(&self, ray: &Ray, _t_min: f32, t_max: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let recip = ray.direction.recip();
         let min = (self.min - ray.origin) * recip;
         let max = (self.max - ray.origin) * recip;
 
-        let (mut t_min0, mut min_axis) = axis_max(axis_max(axis_min((min.x, 0), (max.x, 1)), axis_min((min.y, 2), (max.y, 3))), axis_min((min.z, 4), (max.z, 5)));
-        let (t_max0, max_axis)         = axis_min(axis_min(axis_max((min.x, 0), (max.x, 1)), axis_max((min.y, 2), (max.y, 3))), axis_max((min.z, 4), (max.z, 5)));
+        let (t_min0, min_axis) = axis_max(axis_max(axis_min((min.x, 0), (max.x, 1)), axis_min((min.y, 2), (max.y, 3))), axis_min((min.z, 4), (max.z, 5)));
+        let (t_max0, _max_axis)        = axis_min(axis_min(axis_max((min.x, 0), (max.x, 1)), axis_max((min.y, 2), (max.y, 3))), axis_max((min.z, 4), (max.z, 5)));
 
         if t_max0 <= 0.0 || t_min0 > t_max0 || t_min0 > t_max {
             return None;
@@ -552,27 +849,17 @@ impl Hittable for Box {
             3 =>  Vec3A::Y,
             4 => -Vec3A::Z,
             5 =>  Vec3A::Z,
-            _ =>  {
-                println!("WTF");
-                Vec3A::ZERO
-            }
+            _ => unreachable!()
         };
 
-        // let color = match min_axis {
-        //     0 => Vec3A::X + Vec3A::Y,
-        //     1 =>  Vec3A::X,
-        //     2 => Vec3A::Y + Vec3A::Z,
-        //     3 =>  Vec3A::Y,
-        //     4 =>  Vec3A::Z + Vec3A::X,
-        //     5 =>  Vec3A::Z,
-        //     _ =>  {
-        //         println!("WTF");
-        //         Vec3A::ZERO
-        //     }
-        // };
-
         let point = ray.at(t_min0);
         let delta = point / self.max;
+        // The faces are mapped from the box's corner at the origin
+        let (tangent, bitangent) = match min_axis {
+            0 | 1 => (Vec3A::Y * self.max.y, Vec3A::Z * self.max.z),
+            2 | 3 => (Vec3A::X * self.max.x, Vec3A::Z * self.max.z),
+            _ => (Vec3A::X * self.max.x, Vec3A::Y * self.max.y)
+        };
         let tex_coords = match min_axis {
             0 => { // X_MIN
                 (delta.y, delta.z)
@@ -592,25 +879,26 @@ impl Hittable for Box {
             5 => { // Z_MAX
                 (delta.x, delta.y)
             },
-            _ =>  {
-                println!("WTF");
-                (0.0, 0.0)
-            }
+            _ => unreachable!()
         };
 
         let mut record = HitRecord{
             t: t_min0,
-            point: point,
-            tex_coords: tex_coords, // TODO: Impl tex coords
-            normal: normal,
+            point,
+            tex_coords, // TODO: Impl tex coords
+            normal,
+            geometric_normal: normal,
+            tangent,
+            bitangent,
             material: self.material.clone(),
             //material: Arc::new(LambertianMat::from_texture(SolidColor{ color: color })),
-            front_face: true
+            front_face: true,
+            object_id: 0
         };
 
         record.set_face_normal(ray, &normal);
 
-        return Some(record);
+        Some(record)
     }
 
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
@@ -619,6 +907,137 @@ impl Hittable for Box {
             max: self.max
         })
     }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
+    }
+}
+
+/// A single triangle with optional per-vertex shading normals and texture
+/// coordinates. Without normals the face normal is used, without texture
+/// coordinates the barycentric coordinates are.
+#[derive(Clone)]
+pub struct Triangle {
+    pub vertices: [Vec3A; 3],
+    pub normals: Option<[Vec3A; 3]>,
+    pub tex_coords: Option<[(f32, f32); 3]>,
+    pub material: Arc::<dyn Material>
+}
+
+impl Triangle {
+    pub fn new<T: 'static + Material>(p0: Vec3A, p1: Vec3A, p2: Vec3A, material: T) -> Self {
+        Triangle::new_with_material([p0, p1, p2], None, None, Arc::new(material))
+    }
+
+    pub fn new_with_material(vertices: [Vec3A; 3], normals: Option<[Vec3A; 3]>, tex_coords: Option<[(f32, f32); 3]>, material: Arc::<dyn Material>) -> Self {
+        Triangle {
+            vertices,
+            normals,
+            tex_coords,
+            material
+        }
+    }
+}
+
+impl Hittable for Triangle {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        // Möller–Trumbore
+        let [p0, p1, p2] = self.vertices;
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+
+        let pvec = ray.direction.cross(edge2);
+        let det = edge1.dot(pvec);
+
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        let tvec = ray.origin - p0;
+        let u = tvec.dot(pvec) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let qvec = tvec.cross(edge1);
+        let v = ray.direction.dot(qvec) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = edge2.dot(qvec) * inv_det;
+        if t < t_min || t > t_max {
+            return None;
+        }
+
+        let w = 1.0 - u - v;
+        let geometric_normal = edge1.cross(edge2).normalize();
+
+        let tex_coords = match self.tex_coords {
+            Some([uv0, uv1, uv2]) => (
+                w * uv0.0 + u * uv1.0 + v * uv2.0,
+                w * uv0.1 + u * uv1.1 + v * uv2.1
+            ),
+            None => (u, v)
+        };
+
+        // Solve edge = ∂p/∂u du + ∂p/∂v dv for both edges, the barycentric
+        // coordinates stand in for missing or degenerate texture coordinates
+        let (tangent, bitangent) = match self.tex_coords {
+            Some([uv0, uv1, uv2]) => {
+                let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+                let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+                let determinant = du1 * dv2 - dv1 * du2;
+                if determinant.abs() < 1e-12 {
+                    (edge1, edge2)
+                } else {
+                    ((dv2 * edge1 - dv1 * edge2) / determinant, (du1 * edge2 - du2 * edge1) / determinant)
+                }
+            },
+            None => (edge1, edge2)
+        };
+
+        let mut record = HitRecord {
+            t,
+            point: ray.at(t),
+            tex_coords,
+            normal: geometric_normal,
+            geometric_normal,
+            tangent,
+            bitangent,
+            material: self.material.clone(),
+            front_face: true,
+            object_id: 0
+        };
+
+        record.set_face_normal(ray, &geometric_normal);
+
+        if let Some([n0, n1, n2]) = self.normals {
+            // Facing is decided by the geometry; the shading normal is only
+            // flipped onto the same side as the geometric one.
+            let shading_normal = (w * n0 + u * n1 + v * n2).normalize();
+            if shading_normal.is_finite() {
+                record.normal = if shading_normal.dot(record.normal) < 0.0 { -shading_normal } else { shading_normal };
+            }
+        }
+
+        Some(record)
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        let [p0, p1, p2] = self.vertices;
+        // Pad so axis aligned triangles don't produce flat boxes
+        let padding = Vec3A::new(0.0001, 0.0001, 0.0001);
+        Some(AABB {
+            min: p0.min(p1.min(p2)) - padding,
+            max: p0.max(p1.max(p2)) + padding
+        })
+    }
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
+    }
 }
 
 pub struct ConstantMedium<T: Hittable> {
@@ -628,17 +1047,25 @@ pub struct ConstantMedium<T: Hittable> {
 }
 
 impl<T: Hittable> ConstantMedium<T> {
-    // pub fn new(boundary: T, density: f32, texture: Arc<dyn Texture>) -> Self{
-    //     Self{
-    //         boundary: boundary,
-    //         negative_density: -1.0 / density,
-    //         material: Arc::new(IsotropicMat::new(texture.clone()))
-    //     }
-    // }
+    pub fn new(boundary: T, density: f32, texture: Arc<dyn Texture>) -> Self{
+        Self{
+            boundary,
+            negative_density: -1.0 / density as f64,
+            material: Arc::new(IsotropicMat::from_shared_texture(texture))
+        }
+    }
+
+    pub fn new_with_material(boundary: T, density: f32, material: Arc<dyn Material>) -> Self{
+        Self{
+            boundary,
+            negative_density: -1.0 / density as f64,
+            material
+        }
+    }
 
     pub fn from_color(boundary: T, density: f32, color: Vec3A) -> Self{
         Self{
-            boundary: boundary,
+            boundary,
             negative_density: -1.0 / density as f64,
             material: Arc::new(IsotropicMat::from_color(color))
         }
@@ -646,17 +1073,9 @@ impl<T: Hittable> ConstantMedium<T> {
 }
 
 impl<T: Hittable> Hittable for ConstantMedium<T> {
-    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if let Some(mut record) = self.boundary.intersect(ray, -f32::INFINITY, f32::INFINITY) {
-            if let Some(mut record2) = self.boundary.intersect(ray, record.t + 0.0001, f32::INFINITY) {
-                let mut rng = rand::thread_rng();
-                let enable_debug = false;
-                let debugging = enable_debug && rng.gen::<f32>() < 0.00001;
-
-                if debugging {
-                    println!("t_min = {}, t_max = {}", record.t, record2.t);
-                }
-
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        if let Some(mut record) = self.boundary.intersect(ray, -f32::INFINITY, f32::INFINITY, sampler) {
+            if let Some(mut record2) = self.boundary.intersect(ray, record.t + 0.0001, f32::INFINITY, sampler) {
                 if record.t < t_min {
                     record.t = t_min;
                 }
@@ -673,8 +1092,8 @@ impl<T: Hittable> Hittable for ConstantMedium<T> {
                 }
 
                 let length = ray.direction.length() as f64;
-                let distance_inside = (record2.t - record.t) as f64 / length;
-                let hit_distance = self.negative_density * f64::ln(rng.gen_range(0.0..1.0));
+                let distance_inside = (record2.t - record.t) as f64 * length;
+                let hit_distance = self.negative_density * f64::ln(1.0 - sampler.get_1d() as f64);
 
                 if hit_distance > distance_inside {
                     return None;
@@ -683,23 +1102,48 @@ impl<T: Hittable> Hittable for ConstantMedium<T> {
                 let final_t = record.t + (hit_distance / length) as f32;
                 let final_point = ray.at(final_t);
 
-                if debugging {
-                    println!("distance = {},\nt = {}, \np: {:?}", hit_distance, final_t, final_point);
-                }
-
                 return Some(HitRecord{
                     t: final_t,
                     point: final_point,
                     normal: Vec3A::X,
+                    geometric_normal: Vec3A::X,
+                    tangent: Vec3A::Y,
+                    bitangent: Vec3A::Z,
                     front_face: true,
                     material: self.material.clone(),
-                    tex_coords: (0.0, 0.0)
+                    tex_coords: (0.0, 0.0),
+                    object_id: 0
                 });
             }
         }
-        return None;
+        None
     }
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
         self.boundary.bounding_box(_t0, _t1)
     }
-}
\ No newline at end of file
+
+    fn visit_materials(&self, visit: &mut dyn FnMut(&Arc<dyn Material>)) {
+        visit(&self.material)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn transformed_normals_follow_non_uniform_scale() {
+        // The ellipsoid x²/4 + y² + z² = 1, whose normal is along (x/4, y, z)
+        let sphere = Sphere::new(Vec3A::ZERO, 1.0, LambertianMat::from_color(Vec3A::ONE));
+        let ellipsoid = TransformedObject::new(sphere, Mat4::from_scale(Vec3::new(2.0, 1.0, 1.0)));
+        let ray = Ray{ origin: Vec3A::new(1.0, 5.0, 0.0), direction: -Vec3A::Y, time: 0.0 };
+
+        let record = ellipsoid.intersect(&ray, 0.001, f32::INFINITY, &mut IndependentSampler::new(0)).unwrap();
+        let expected = Vec3A::new(0.25, f32::sqrt(0.75), 0.0).normalize();
+        assert!((record.point - Vec3A::new(1.0, f32::sqrt(0.75), 0.0)).length() < 1e-4);
+        assert!((record.normal - expected).length() < 1e-4, "{:?}", record.normal);
+        assert!((record.geometric_normal - expected).length() < 1e-4);
+        assert!(record.front_face);
+        assert!(record.tangent.dot(record.normal).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,241 @@
+//! Spectral rendering: sampled wavelengths, the CIE colour matching
+//! functions, RGB to spectrum uplifting and dispersive indices of refraction.
+//!
+//! In spectral mode every camera sample follows three wavelengths at once,
+//! one per component of the `Vec3A`s the integrator passes around. The RGB
+//! values of textures, emitters and lights are uplifted to spectra at those
+//! wavelengths as the path meets them, and the radiance at the end is
+//! projected back to RGB through the colour matching functions.
+
+extern crate glam;
+
+use glam::*;
+
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+/// Wavelength of the sodium D line in nanometres, where glass catalogues
+/// quote the index of refraction.
+pub const SODIUM_D_LINE: f32 = 587.6;
+
+/// Integral of the colour matching function ȳ over the wavelengths, so an
+/// equal energy spectrum of one has a luminance of one.
+const CIE_Y_INTEGRAL: f32 = 106.922;
+
+/// White of the sRGB space (D65) in XYZ, an equal energy spectrum maps to it.
+const D65_WHITE: Vec3A = const_vec3a!([0.95047, 1.0, 1.08883]);
+
+/// Converts CIE XYZ to linear sRGB (D65).
+pub fn xyz_to_rgb(xyz: Vec3A) -> Vec3A {
+    Vec3A::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z
+    )
+}
+
+/// A Gaussian with different widths on either side of its peak.
+fn piecewise_gaussian(x: f32, mean: f32, sigma_below: f32, sigma_above: f32) -> f32 {
+    let sigma = if x < mean { sigma_below } else { sigma_above };
+    let t = (x - mean) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+/// The CIE 1931 colour matching functions x̄, ȳ and z̄ at `lambda`
+/// nanometres, using the multi-lobe fit of Wyman, Sloan and Shirley 2013.
+pub fn cie_xyz(lambda: f32) -> Vec3A {
+    let x = 1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5)
+        + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0)
+        + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8);
+    Vec3A::new(x, y, z)
+}
+
+/// The spectra Smits 1999 builds RGB colours from, ten bins from 380 to
+/// 720 nm.
+const SMITS_BIN_START: f32 = 380.0;
+const SMITS_BIN_WIDTH: f32 = 34.0;
+const SMITS_WHITE: [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+/// The value at `lambda` of a smooth spectrum whose colour is close to
+/// `rgb`. Reflectances in [0, 1] stay in [0, 1], white is flat.
+pub fn uplift(rgb: Vec3A, lambda: f32) -> f32 {
+    let rgb = rgb.max(Vec3A::ZERO);
+    let bin = (((lambda - SMITS_BIN_START) / SMITS_BIN_WIDTH) as i32).clamp(0, 9) as usize;
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+
+    // The smallest component is white, the middle one a secondary colour
+    // and the rest a primary
+    if r <= g && r <= b {
+        let rest = if g <= b {
+            (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        };
+        r * SMITS_WHITE[bin] + rest
+    } else if g <= r && g <= b {
+        let rest = if r <= b {
+            (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        };
+        g * SMITS_WHITE[bin] + rest
+    } else {
+        let rest = if r <= g {
+            (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        };
+        b * SMITS_WHITE[bin] + rest
+    }
+}
+
+/// Picks wavelengths where the eye is sensitive (Radziszewski et al. 2009).
+fn sample_visible_wavelength(u: f32) -> f32 {
+    538.0 - 138.888_89 * f32::atanh(0.856_910_6 - 1.827_502 * u)
+}
+
+fn visible_wavelength_pdf(lambda: f32) -> f32 {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    let c = f32::cosh(0.0072 * (lambda - 538.0));
+    0.003_939_804 / (c * c)
+}
+
+/// The wavelengths one camera sample carries, in nanometres, and their
+/// densities. The first one is the hero wavelength, the only one that
+/// survives a dispersive surface.
+#[derive(Copy, Clone, Debug)]
+pub struct SampledWavelengths {
+    pub lambda: Vec3A,
+    pub pdf: Vec3A,
+    /// Whether the path has gone through a dispersive surface
+    pub secondary_terminated: bool
+}
+
+impl SampledWavelengths {
+    /// Three wavelengths evenly spread over the visible range by `u`.
+    pub fn sample_visible(u: f32) -> Self {
+        let mut lambda = [0.0; 3];
+        let mut pdf = [0.0; 3];
+        for i in 0..3 {
+            let ui = (u + i as f32 / 3.0).fract();
+            lambda[i] = sample_visible_wavelength(ui).clamp(LAMBDA_MIN, LAMBDA_MAX);
+            pdf[i] = visible_wavelength_pdf(lambda[i]);
+        }
+        SampledWavelengths {
+            lambda: Vec3A::from(lambda),
+            pdf: Vec3A::from(pdf),
+            secondary_terminated: false
+        }
+    }
+
+    pub fn hero(&self) -> f32 {
+        self.lambda.x
+    }
+
+    /// `rgb` as a spectrum, sampled at the wavelengths.
+    pub fn uplift(&self, rgb: Vec3A) -> Vec3A {
+        Vec3A::new(uplift(rgb, self.lambda.x), uplift(rgb, self.lambda.y), uplift(rgb, self.lambda.z))
+    }
+
+    /// Drops the other wavelengths once a dispersive surface has sent them
+    /// elsewhere, the hero wavelength stands in for all three from then on.
+    /// Returns what the radiance along the path is scaled by.
+    pub fn terminate_secondary(&mut self) -> Vec3A {
+        if self.secondary_terminated {
+            return Vec3A::ONE;
+        }
+        self.secondary_terminated = true;
+        Vec3A::new(3.0, 0.0, 0.0)
+    }
+
+    /// The linear sRGB colour of `radiance` sampled at the wavelengths.
+    pub fn to_rgb(self, radiance: Vec3A) -> Vec3A {
+        let mut xyz = Vec3A::ZERO;
+        for i in 0..3 {
+            if self.pdf[i] > 0.0 {
+                xyz += cie_xyz(self.lambda[i]) * radiance[i] / self.pdf[i];
+            }
+        }
+        xyz_to_rgb(xyz / (3.0 * CIE_Y_INTEGRAL) * D65_WHITE)
+    }
+}
+
+/// How the index of refraction of a dielectric changes with the wavelength.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dispersion {
+    /// n = a + b / λ², λ in micrometres
+    Cauchy { a: f32, b: f32 },
+    /// n² = 1 + Σ bᵢ λ² / (λ² - cᵢ), λ in micrometres and cᵢ in µm²
+    Sellmeier { b: [f32; 3], c: [f32; 3] }
+}
+
+impl Dispersion {
+    /// The index of refraction at `lambda` nanometres.
+    pub fn ior(&self, lambda: f32) -> f32 {
+        let micrometres = lambda / 1000.0;
+        let squared = micrometres * micrometres;
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / squared,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f32 = (0..3).map(|i| b[i] * squared / (squared - c[i])).sum();
+                f32::sqrt(f32::max(1.0 + sum, 1e-4))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RGB colour of the spectrum `radiance` gives at each wavelength,
+    /// averaged over camera samples evenly spread over [0, 1).
+    fn average_rgb(radiance: impl Fn(&SampledWavelengths) -> Vec3A) -> Vec3A {
+        let count = 4096;
+        let mut sum = Vec3A::ZERO;
+        for i in 0..count {
+            let wavelengths = SampledWavelengths::sample_visible((i as f32 + 0.5) / count as f32);
+            sum += wavelengths.to_rgb(radiance(&wavelengths));
+        }
+        sum / count as f32
+    }
+
+    /// Within `tolerance` of the largest component of `expected`.
+    fn assert_close(rgb: Vec3A, expected: Vec3A, tolerance: f32) {
+        let error = (rgb - expected).abs().max_element() / expected.max_element();
+        assert!(error <= tolerance, "{:?} instead of {:?}", rgb, expected);
+    }
+
+    #[test]
+    fn equal_energy_is_white() {
+        assert_close(average_rgb(|_| Vec3A::ONE), Vec3A::ONE, 0.01);
+    }
+
+    #[test]
+    fn uplifted_colours_round_trip() {
+        // Smits' spectra only approximate the saturated colours
+        let colours = [
+            (Vec3A::ONE, 0.01),
+            (Vec3A::splat(0.18), 0.01),
+            (Vec3A::new(0.8, 0.3, 0.1), 0.1),
+            (Vec3A::X, 0.15),
+            (Vec3A::Y, 0.15),
+            (Vec3A::Z, 0.15)
+        ];
+        for (rgb, tolerance) in colours.iter() {
+            assert_close(average_rgb(|wavelengths| wavelengths.uplift(*rgb)), *rgb, *tolerance);
+        }
+    }
+}
@@ -4,10 +4,11 @@ extern crate image;
 use glam::*;
 
 use crate::perlin::Perlin;
+use crate::rng::Pcg32;
 
 use std::sync::Arc;
 
-pub trait Texture {
+pub trait Texture: Send + Sync {
     fn value(&self, coords: (f32, f32), point: Vec3A) -> Vec3A;
 }
 
@@ -17,7 +18,7 @@ pub struct SolidColor {
 
 impl Texture for SolidColor {
     fn value(&self, _coords: (f32, f32), _point: Vec3A) -> Vec3A {
-        return self.color;
+        self.color
     }
 }
 
@@ -54,22 +55,35 @@ impl Texture for CheckeredTexture {
         let sines = f32::sin(10.0 * point.x) * f32::sin(10.0 * point.y) * f32::sin(10.0 * point.z);
 
         if sines < 0.0 {
-            return self.odd.value(coords, point);
+            self.odd.value(coords, point)
         } else {
-            return self.even.value(coords, point);
+            self.even.value(coords, point)
         }
     }
 }
 
+/// One channel of another texture as a grey texture, for images that pack
+/// several parameters (glTF keeps roughness in green and metallic in blue).
+pub struct ChannelTexture {
+    pub texture: Arc<dyn Texture>,
+    pub channel: usize
+}
+
+impl Texture for ChannelTexture {
+    fn value(&self, coords: (f32, f32), point: Vec3A) -> Vec3A {
+        Vec3A::splat(self.texture.value(coords, point)[self.channel])
+    }
+}
+
 pub struct NoiseTexture {
     noise: Perlin,
     frequency: f32
 }
 
 impl NoiseTexture {
-    pub fn new(freq: f32) -> Self {
+    pub fn new(freq: f32, rng: &mut Pcg32) -> Self {
         Self {
-            noise: Perlin::new(),
+            noise: Perlin::new(rng),
             frequency: freq
         }
     }
@@ -77,7 +91,7 @@ impl NoiseTexture {
 
 impl Texture for NoiseTexture {
     fn value(&self, _coords: (f32, f32), point: Vec3A) -> Vec3A {
-        return Vec3A::new(0.5, 0.5, 0.5) * (1.0 + f32::sin(self.frequency * point.z + 10.0 * self.noise.turb(point, 7)));
+        Vec3A::new(0.5, 0.5, 0.5) * (1.0 + f32::sin(self.frequency * point.z + 10.0 * self.noise.turb(point, 7)))
     }
 }
 
@@ -87,9 +101,13 @@ pub struct ImageTexture {
 
 impl ImageTexture {
     pub fn new(path: String) -> Self {
-        ImageTexture {
-            image: image::open(path).unwrap().into_rgb8()
-        }
+        ImageTexture::load(path).unwrap()
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> image::ImageResult<Self> {
+        Ok(ImageTexture {
+            image: image::open(path)?.into_rgb8()
+        })
     }
 }
 
@@ -0,0 +1,105 @@
+extern crate glam;
+extern crate image;
+
+use glam::*;
+use image::{ImageBuffer, Rgb, RgbImage};
+
+use crate::framebuffer::Framebuffer;
+
+/// Curve used to bring linear radiance into the [0, 1] range.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToneMapOperator {
+    /// Cuts everything above 1
+    Clamp,
+    /// L / (1 + L) on the luminance, keeping the hue
+    Reinhard,
+    /// Filmic curve fitted to the ACES reference transform
+    Aces
+}
+
+impl ToneMapOperator {
+    pub const NAMES: [(&'static str, ToneMapOperator); 3] = [
+        ("clamp", ToneMapOperator::Clamp),
+        ("reinhard", ToneMapOperator::Reinhard),
+        ("aces", ToneMapOperator::Aces),
+    ];
+
+    pub fn from_name(name: &str) -> Option<ToneMapOperator> {
+        ToneMapOperator::NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, o)| *o)
+    }
+
+    pub fn apply(&self, color: Vec3A) -> Vec3A {
+        match self {
+            ToneMapOperator::Clamp => color.max(Vec3A::ZERO).min(Vec3A::ONE),
+            ToneMapOperator::Reinhard => {
+                let l = luminance(color);
+                if l <= 0.0 {
+                    return Vec3A::ZERO;
+                }
+                (color * (1.0 / (1.0 + l))).max(Vec3A::ZERO).min(Vec3A::ONE)
+            },
+            ToneMapOperator::Aces => {
+                // Krzysztof Narkowicz, "ACES Filmic Tone Mapping Curve"
+                let x = color.max(Vec3A::ZERO);
+                let a = x * (2.51 * x + Vec3A::splat(0.03));
+                let b = x * (2.43 * x + Vec3A::splat(0.59)) + Vec3A::splat(0.14);
+                (a / b).max(Vec3A::ZERO).min(Vec3A::ONE)
+            }
+        }
+    }
+}
+
+pub fn luminance(color: Vec3A) -> f32 {
+    color.dot(Vec3A::new(0.2126, 0.7152, 0.0722))
+}
+
+/// The sRGB transfer function for a linear value in [0, 1].
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Turns linear radiance into display values: exposure, then the operator,
+/// then the sRGB transfer curve.
+#[derive(Copy, Clone, Debug)]
+pub struct ToneMapSettings {
+    /// In stops, each one doubles the brightness
+    pub exposure: f32,
+    pub operator: ToneMapOperator,
+    /// Without it the values are written linearly
+    pub srgb: bool
+}
+
+impl Default for ToneMapSettings {
+    fn default() -> Self {
+        ToneMapSettings {
+            exposure: 0.0,
+            operator: ToneMapOperator::Clamp,
+            srgb: true
+        }
+    }
+}
+
+impl ToneMapSettings {
+    pub fn apply(&self, color: Vec3A) -> Vec3A {
+        let mapped = self.operator.apply(color * self.exposure.exp2());
+        if self.srgb {
+            return Vec3A::new(linear_to_srgb(mapped.x), linear_to_srgb(mapped.y), linear_to_srgb(mapped.z));
+        }
+        mapped
+    }
+
+    pub fn map_to_rgb8(&self, buffer: &Framebuffer) -> RgbImage {
+        ImageBuffer::from_fn(buffer.width, buffer.height, |x, y| {
+            let color = self.apply(buffer.get(x, y));
+            Rgb([float_to_u8_color(color.x), float_to_u8_color(color.y), float_to_u8_color(color.z)])
+        })
+    }
+}
+
+fn float_to_u8_color(f: f32) -> u8 {
+    (256.0 * f32::clamp(f, 0.0, 0.999)) as u8
+}
@@ -5,16 +5,14 @@ use rand::prelude::*;
 use glam::*;
 
 pub fn is_near_zero(v: Vec3A) -> bool {
-    return v.x.abs() < f32::EPSILON && v.y.abs() < f32::EPSILON && v.z.abs() < f32::EPSILON; 
+    v.x.abs() < f32::EPSILON && v.y.abs() < f32::EPSILON && v.z.abs() < f32::EPSILON
 }
 
-pub fn random() -> Vec3A {
-    let mut rng = rand::thread_rng();
+pub fn random<R: Rng + ?Sized>(rng: &mut R) -> Vec3A {
     Vec3A::new(rng.gen(), rng.gen(), rng.gen())
 }
 
-pub fn random_range(min: f32, max: f32) -> Vec3A {
-    let mut rng = rand::thread_rng();
+pub fn random_range<R: Rng + ?Sized>(min: f32, max: f32, rng: &mut R) -> Vec3A {
     glam::Vec3A::new(
         rng.gen_range(min..max),
         rng.gen_range(min..max),
@@ -22,30 +20,29 @@ pub fn random_range(min: f32, max: f32) -> Vec3A {
     )
 }
 
-pub fn random_in_unit_sphere() -> Vec3A {
-    let mut p = random_range(-1.0, 1.0);
+pub fn random_in_unit_sphere<R: Rng + ?Sized>(rng: &mut R) -> Vec3A {
+    let mut p = random_range(-1.0, 1.0, rng);
     while p.length_squared() >= 1.0 {
-        p = random_range(-1.0, 1.0);
+        p = random_range(-1.0, 1.0, rng);
     }
     p
 }
 
-pub fn random_unit_vector() -> Vec3A {
-    random_in_unit_sphere().normalize()
+pub fn random_unit_vector<R: Rng + ?Sized>(rng: &mut R) -> Vec3A {
+    random_in_unit_sphere(rng).normalize()
 }
 
-pub fn random_in_hemisphere(normal: Vec3A) -> Vec3A {
-    let in_unit_sphere = random_in_unit_sphere();
+pub fn random_in_hemisphere<R: Rng + ?Sized>(normal: Vec3A, rng: &mut R) -> Vec3A {
+    let in_unit_sphere = random_in_unit_sphere(rng);
 
     if in_unit_sphere.dot(normal) > 0.0 {
-        return in_unit_sphere;
+        in_unit_sphere
     } else {
-        return -in_unit_sphere;
+        -in_unit_sphere
     }
 }
 
-pub fn random_in_unit_disk() -> Vec3A {
-    let mut rng = rand::thread_rng();
+pub fn random_in_unit_disk<R: Rng + ?Sized>(rng: &mut R) -> Vec3A {
     loop {
         let p = Vec3A::new( 
             rng.gen_range(-1.0..1.0),
@@ -53,7 +50,7 @@ pub fn random_in_unit_disk() -> Vec3A {
             0.0
         );
 
-        if p.length_squared() >= 1.0 {
+        if p.length_squared() < 1.0 {
             return p;
         }
     }
@@ -72,4 +69,68 @@ pub fn refract(uv: Vec3A, normal: Vec3A, etai_over_etat: f32) -> Vec3A {
     let r_out_parallel = -f32::sqrt(f32::abs(1.0 - r_out_perp.length_squared())) * normal;
     
     r_out_perp + r_out_parallel
-}
\ No newline at end of file
+}
+/// Two unit vectors that together with `normal` form an orthonormal basis.
+pub fn orthonormal_basis(normal: Vec3A) -> (Vec3A, Vec3A) {
+    // Duff et al., "Building an Orthonormal Basis, Revisited"
+    let sign = f32::copysign(1.0, normal.z);
+    let a = -1.0 / (sign + normal.z);
+    let b = normal.x * normal.y * a;
+    let tangent = Vec3A::new(1.0 + sign * normal.x * normal.x * a, sign * b, -sign * normal.x);
+    let bitangent = Vec3A::new(b, sign + normal.y * normal.y * a, -normal.y);
+    (tangent, bitangent)
+}
+
+/// A uniformly distributed direction inside the cone around `axis` (a unit
+/// vector) whose half-angle has cosine `cos_theta_max`.
+pub fn sample_cone(axis: Vec3A, cos_theta_max: f32, u: Vec2) -> Vec3A {
+    let cos_theta = 1.0 + u.x * (cos_theta_max - 1.0);
+    let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+    let phi = 2.0 * std::f32::consts::PI * u.y;
+
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + axis * cos_theta
+}
+
+/// A cosine weighted direction in the hemisphere around `normal` (a unit
+/// vector), with density cos(theta) / pi.
+pub fn sample_cosine_direction(normal: Vec3A, u: Vec2) -> Vec3A {
+    let r1 = u.x;
+    let r2 = u.y;
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let radius = r2.sqrt();
+
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    tangent * (radius * phi.cos()) + bitangent * (radius * phi.sin()) + normal * f32::sqrt(1.0 - r2)
+}
+
+/// A uniformly distributed point on the unit disk in the xy plane, using
+/// Shirley's concentric mapping so strata of `u` stay compact.
+pub fn sample_disk(u: Vec2) -> Vec3A {
+    let offset = 2.0 * u - Vec2::ONE;
+    if offset.x == 0.0 && offset.y == 0.0 {
+        return Vec3A::ZERO;
+    }
+
+    let quarter_pi = std::f32::consts::FRAC_PI_4;
+    let (radius, theta) = if offset.x.abs() > offset.y.abs() {
+        (offset.x, quarter_pi * (offset.y / offset.x))
+    } else {
+        (offset.y, 2.0 * quarter_pi - quarter_pi * (offset.x / offset.y))
+    };
+    Vec3A::new(radius * theta.cos(), radius * theta.sin(), 0.0)
+}
+
+/// A uniformly distributed unit vector.
+pub fn sample_sphere(u: Vec2) -> Vec3A {
+    let z = 1.0 - 2.0 * u.x;
+    let radius = f32::sqrt(f32::max(0.0, 1.0 - z * z));
+    let phi = 2.0 * std::f32::consts::PI * u.y;
+    Vec3A::new(radius * phi.cos(), radius * phi.sin(), z)
+}
+
+/// A uniformly distributed point inside the unit ball, `u` picks the
+/// direction and `r` the distance from the centre.
+pub fn sample_ball(u: Vec2, r: f32) -> Vec3A {
+    sample_sphere(u) * r.cbrt()
+}